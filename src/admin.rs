@@ -0,0 +1,896 @@
+//! The repository, user and group commands of `gitcontrol`.
+//!
+//! `gitcontrol` runs them as root for the operator; `gitcontrol-shell` runs
+//! the subset in [`SHELL_COMMANDS`] as the git user for admins named in
+//! `gitcontrol.cfg` (`ssh git@host admin repo create bob web/site`). Both go
+//! through [`run`], so a command behaves the same whichever way it is invoked.
+//! Malformed arguments are reported as [`Error::UsageInvalid`].
+//!
+//! The shell also runs [`PERMS`] for the owners of the repository (or
+//! directory) it names, so they can manage access to it without being admins.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::adminrepo::ADMIN_REPO;
+use crate::config::Config;
+use crate::diff;
+use crate::errors::Error;
+use crate::history;
+use crate::lock;
+use crate::policy::{Operation, decide, decide_create};
+use crate::repos::{self, repos_on_disk};
+use crate::types::{Group, Permission, RefPattern, Repo, Subject, User, resolve};
+use crate::userdb::read_db;
+
+/// The commands admins may run through `gitcontrol-shell`.
+pub const SHELL_COMMANDS: [&str; 4] = ["repo", "user", "group", "access"];
+
+/// `perms <dir/repo|dir/*> list|grant <who> <perm>|revoke <who>`.
+pub const PERMS: &str = "perms";
+
+pub struct Options {
+    /// Base directory holding the repositories and `gitcontrol.cfg`.
+    pub base: PathBuf,
+    /// `user:group` ownership applied to created files.
+    pub owner: String,
+    /// Whether to change ownership of created files at all.
+    pub chown: bool,
+    /// The update hook installed in created repositories.
+    pub hook: PathBuf,
+    /// Who runs the command, and its command line, for the history.
+    pub operator: String,
+    pub command: String,
+    /// Print what would change, as a diff, instead of changing anything.
+    pub dry_run: bool,
+}
+
+pub fn config_path(base: &Path) -> PathBuf {
+    base.join("gitcontrol.cfg")
+}
+
+/// Run the command `cmd` (`repo`, `user`, `group`, `access`, `perms` or
+/// `admin`) with its arguments.
+pub fn run(opts: &Options, cmd: &str, args: &[String]) -> Result<(), Error> {
+    match cmd {
+        "repo" => repo_cmd(opts, args),
+        "user" => user_cmd(opts, args),
+        "group" => group_cmd(opts, args),
+        "admin" => admin_cmd(opts, args),
+        PERMS => {
+            let (target, rest) = split_command(args, "expected <dir/repo> or <dir/*>")?;
+            perms_cmd(opts, &Repo::from_ownable(target.to_string())?, rest)
+        }
+        "access" => {
+            let (user, repo) = parse_user_repo(args)?;
+            access(opts, user, &repo)
+        }
+        other => Err(usage(format!("unknown command: {other}"))),
+    }
+}
+
+/// [`run`] for `gitcontrol-shell`, which refuses to grant on, hand out or
+/// create [`ADMIN_REPO`] (or a pattern covering it): whoever can push there
+/// can add `admin` lines, which the shell leaves to `gitcontrol admin add`.
+pub fn run_from_shell(opts: &Options, cmd: &str, args: &[String]) -> Result<(), Error> {
+    let gives_access = match (cmd, args.first().map(String::as_str)) {
+        ("repo", Some("create" | "grant" | "owner")) => true,
+        (PERMS, _) => args.get(1).is_some_and(|sub| sub == "grant"),
+        _ => false,
+    };
+    let admin_repo = Repo::from_string(ADMIN_REPO.to_string())?;
+    let names_admin_repo = args
+        .iter()
+        .any(|a| Repo::from_pattern(a.clone()).is_ok_and(|repo| repo.matches(&admin_repo)));
+    if gives_access && names_admin_repo {
+        return Err(Error::AccessDenied(
+            "to the admin repository: manage it with `gitcontrol` on the server",
+        ));
+    }
+    run(opts, cmd, args)
+}
+
+fn repo_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
+    let (sub, rest) = split_command(args, "missing repo subcommand (create|grant|revoke|list)")?;
+    match sub {
+        "create" => {
+            let (who, repo, perm) = parse_subject_repo_perm(rest)?;
+            if repo.is_pattern() {
+                return Err(Error::RepoInvalid((
+                    repo.to_string(),
+                    "cannot create a pattern",
+                )));
+            }
+            repo_create(opts, &who, &repo, perm)
+        }
+        "grant" => {
+            let (rest, target) = split_target_flags(rest)?;
+            let (who, repo, perm) = parse_subject_repo_perm(&rest)?;
+            match target {
+                Target::Ref(_) if perm == Permission::Read => {
+                    Err(usage("a ref rule cannot be read-only"))
+                }
+                Target::Ref(pattern) => grant_ref(opts, &who, &repo, pattern, perm),
+                Target::Create if perm != Permission::Write => {
+                    Err(usage("--create takes no permission"))
+                }
+                Target::Create => grant_create(opts, &who, &repo),
+                Target::Access => grant(opts, &who, &repo, perm),
+            }
+        }
+        "revoke" => {
+            let (rest, target) = split_target_flags(rest)?;
+            let (who, repo) = parse_subject_repo(&rest)?;
+            match target {
+                Target::Ref(pattern) => revoke_ref(opts, &who, &repo, &pattern),
+                Target::Create => revoke_create(opts, &who, &repo),
+                Target::Access => revoke(opts, &who, &repo),
+            }
+        }
+        "owner" => match rest {
+            [user, repo] => repo_owner(
+                opts,
+                &User::from_string(user.clone())?,
+                &Repo::from_ownable(repo.clone())?,
+            ),
+            _ => Err(usage("expected <user> <dir/repo|dir/*>")),
+        },
+        "disown" => match rest {
+            [repo] => repo_disown(opts, &Repo::from_ownable(repo.clone())?),
+            _ => Err(usage("expected <dir/repo|dir/*>")),
+        },
+        "list" => repo_list(opts),
+        other => Err(usage(format!("unknown repo subcommand: {other}"))),
+    }
+}
+
+fn user_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
+    let (sub, rest) = split_command(args, "missing user subcommand (add|remove|list|show)")?;
+    match sub {
+        "add" => user_add(opts, &parse_one_user(rest)?),
+        "remove" | "del" => user_remove(opts, &parse_one_user(rest)?),
+        "list" => user_list(opts),
+        "show" => user_show(opts, &parse_one_user(rest)?),
+        other => Err(usage(format!("unknown user subcommand: {other}"))),
+    }
+}
+
+fn group_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
+    let (sub, rest) = split_command(args, "missing group subcommand (add|remove|list|show)")?;
+    match sub {
+        "add" => {
+            let (group, users) = parse_group_users(rest)?;
+            group_add(opts, &group, &users)
+        }
+        "remove" | "del" => {
+            let (group, users) = parse_group_users(rest)?;
+            group_remove(opts, &group, &users)
+        }
+        "list" => group_list(opts),
+        "show" => match parse_group_users(rest)? {
+            (group, users) if users.is_empty() => group_show(opts, &group),
+            _ => Err(usage("expected a single <group>")),
+        },
+        other => Err(usage(format!("unknown group subcommand: {other}"))),
+    }
+}
+
+fn admin_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
+    let (sub, rest) = split_command(args, "missing admin subcommand (add|remove|list)")?;
+    match sub {
+        "add" | "remove" | "del" if rest.is_empty() => Err(usage("expected <who>...")),
+        "add" => {
+            let subjects = parse_subjects(rest)?;
+            let mut added = Vec::new();
+            edit_config(opts, |cfg| {
+                added = subjects.iter().map(|s| cfg.add_admin(s.clone())).collect();
+                Ok(())
+            })?;
+            for (who, added) in subjects.iter().zip(added) {
+                if added {
+                    println!("made {who} an admin");
+                } else {
+                    println!("{who} already was an admin");
+                }
+            }
+            Ok(())
+        }
+        "remove" | "del" => {
+            let subjects = parse_subjects(rest)?;
+            let mut removed = Vec::new();
+            edit_config(opts, |cfg| {
+                removed = subjects.iter().map(|s| cfg.remove_admin(s)).collect();
+                Ok(())
+            })?;
+            for (who, removed) in subjects.iter().zip(removed) {
+                if removed {
+                    println!("{who} is no longer an admin");
+                } else {
+                    println!("{who} was not an admin");
+                }
+            }
+            Ok(())
+        }
+        "list" if rest.is_empty() => {
+            let cfg = Config::load(&config_path(&opts.base))?;
+            for who in cfg.admins() {
+                println!("{who}");
+            }
+            Ok(())
+        }
+        "list" => Err(usage("admin list takes no arguments")),
+        other => Err(usage(format!("unknown admin subcommand: {other}"))),
+    }
+}
+
+// --- commands -------------------------------------------------------------
+
+fn repo_create(opts: &Options, who: &Subject, repo: &Repo, perm: Permission) -> Result<(), Error> {
+    let cfg = Config::load(&config_path(&opts.base))?;
+    let repo_path = repos::repo_path(&opts.base, cfg.personal_dir(), repo);
+    if repo_path.exists() {
+        return Err(Error::RepoExists(repo.to_string()));
+    }
+
+    if opts.dry_run {
+        println!("would create {}", repo_path.display());
+    } else {
+        repos::init_bare(&repo_path, &opts.hook)?;
+    }
+
+    if opts.chown && !opts.dry_run {
+        // the shell creates repositories next to it when pushed to
+        if let Some(parent) = repo_path.parent() {
+            chown(opts, false, parent)?;
+        }
+        chown(opts, true, &repo_path)?;
+    }
+
+    edit_config(opts, |cfg| {
+        cfg.set_subject_permission(who, repo.clone(), perm);
+        if let Subject::User(user) = who {
+            cfg.set_owner(repo.clone(), user);
+        }
+        Ok(())
+    })?;
+
+    println!("created {repo} and granted {who} {perm} access");
+    Ok(())
+}
+
+fn repo_owner(opts: &Options, user: &User, repo: &Repo) -> Result<(), Error> {
+    edit_config(opts, |cfg| {
+        cfg.set_owner(repo.clone(), user);
+        Ok(())
+    })?;
+    println!("{user} now owns {repo}");
+    Ok(())
+}
+
+fn repo_disown(opts: &Options, repo: &Repo) -> Result<(), Error> {
+    let mut removed = None;
+    edit_config(opts, |cfg| {
+        removed = cfg.remove_owner(repo);
+        Ok(())
+    })?;
+    match removed {
+        Some(user) => println!("{user} no longer owns {repo}"),
+        None => println!("{repo} had no owner"),
+    }
+    Ok(())
+}
+
+/// Access to a single repository or directory, as its owners manage it.
+fn perms_cmd(opts: &Options, repo: &Repo, args: &[String]) -> Result<(), Error> {
+    let (sub, rest) = split_command(args, "missing perms subcommand (list|grant|revoke)")?;
+    match (sub, rest) {
+        ("list", []) => {
+            let cfg = Config::load(&config_path(&opts.base))?;
+            let mut owned = vec![repo.directory()];
+            if !repo.is_directory() {
+                owned.push(repo.clone());
+            }
+            for r in owned {
+                if let Some(owner) = cfg.owner_of(&r) {
+                    println!("owner {owner} ({r})");
+                }
+            }
+            for (who, perm) in cfg.grants_on(repo) {
+                println!("{} {who}", perm.to_char());
+            }
+            Ok(())
+        }
+        ("grant", [who, perm]) => grant(
+            opts,
+            &Subject::from_string(who.clone())?,
+            repo,
+            Permission::from_token(perm)?,
+        ),
+        ("revoke", [who]) => revoke(opts, &Subject::from_string(who.clone())?, repo),
+        ("list" | "grant" | "revoke", _) => {
+            Err(usage("expected list, grant <who> <perm> or revoke <who>"))
+        }
+        (other, _) => Err(usage(format!("unknown perms subcommand: {other}"))),
+    }
+}
+
+fn grant(opts: &Options, who: &Subject, repo: &Repo, perm: Permission) -> Result<(), Error> {
+    edit_config(opts, |cfg| {
+        cfg.set_subject_permission(who, repo.clone(), perm);
+        Ok(())
+    })?;
+    if perm == Permission::Deny {
+        println!("denied {who} any access to {repo}");
+    } else {
+        println!("granted {who} {perm} access to {repo}");
+    }
+    Ok(())
+}
+
+fn revoke(opts: &Options, who: &Subject, repo: &Repo) -> Result<(), Error> {
+    let mut removed = false;
+    edit_config(opts, |cfg| {
+        removed = cfg.revoke_subject(who, repo);
+        Ok(())
+    })?;
+    if removed {
+        println!("revoked {who} access to {repo}");
+    } else {
+        println!("{who} had no access to {repo}");
+    }
+    Ok(())
+}
+
+fn grant_ref(
+    opts: &Options,
+    who: &Subject,
+    repo: &Repo,
+    pattern: RefPattern,
+    perm: Permission,
+) -> Result<(), Error> {
+    edit_config(opts, |cfg| {
+        cfg.set_ref_rule(who, repo.clone(), pattern.clone(), perm);
+        Ok(())
+    })?;
+    if perm == Permission::Deny {
+        println!("denied {who} any update to {pattern} in {repo}");
+    } else {
+        println!("granted {who} {perm} access to {pattern} in {repo}");
+    }
+    Ok(())
+}
+
+fn revoke_ref(
+    opts: &Options,
+    who: &Subject,
+    repo: &Repo,
+    pattern: &RefPattern,
+) -> Result<(), Error> {
+    let mut removed = false;
+    edit_config(opts, |cfg| {
+        removed = cfg.revoke_ref_rule(who, repo, pattern);
+        Ok(())
+    })?;
+    if removed {
+        println!("revoked {who} rule for {pattern} in {repo}");
+    } else {
+        println!("{who} had no rule for {pattern} in {repo}");
+    }
+    Ok(())
+}
+
+fn grant_create(opts: &Options, who: &Subject, pattern: &Repo) -> Result<(), Error> {
+    edit_config(opts, |cfg| {
+        cfg.set_create(who, pattern.clone());
+        Ok(())
+    })?;
+    println!("allowed {who} to create repositories matching {pattern}");
+    Ok(())
+}
+
+fn revoke_create(opts: &Options, who: &Subject, pattern: &Repo) -> Result<(), Error> {
+    let mut removed = false;
+    edit_config(opts, |cfg| {
+        removed = cfg.revoke_create(who, pattern);
+        Ok(())
+    })?;
+    if removed {
+        println!("revoked {who} creating repositories matching {pattern}");
+    } else {
+        println!("{who} could not create repositories matching {pattern}");
+    }
+    Ok(())
+}
+
+fn repo_list(opts: &Options) -> Result<(), Error> {
+    let cfg = Config::load(&config_path(&opts.base))?;
+    let on_disk = repos_on_disk(&opts.base, cfg.personal_dir())?;
+    for group in cfg.groups() {
+        println!("={group}");
+        if let Some(repos) = cfg.group_permissions_of(group) {
+            print_grants(repos, &on_disk);
+        }
+        print_ref_rules(&cfg, &Subject::Group(group.clone()), "  ");
+    }
+    for user in cfg.users() {
+        println!("@{user}");
+        if let Some(repos) = cfg.permissions_of(user) {
+            print_grants(repos, &on_disk);
+        }
+        print_ref_rules(&cfg, &Subject::User(user.clone()), "  ");
+    }
+    Ok(())
+}
+
+/// Print a section's ref rules, create permissions and owned repositories.
+fn print_ref_rules(cfg: &Config, subject: &Subject, indent: &str) {
+    for (repo, pattern, perm) in cfg.ref_rules_of(subject) {
+        println!("{indent}{} {repo} {pattern}", perm.to_char());
+    }
+    for pattern in cfg.creates_of(subject) {
+        println!("{indent}C {pattern}");
+    }
+    if let Subject::User(user) = subject {
+        for repo in cfg.owned_by(user) {
+            println!("{indent}owner {repo}");
+        }
+    }
+}
+
+/// Print a section's grants; a pattern is followed by the repositories on
+/// disk for which it is the deciding grant.
+fn print_grants(repos: &BTreeMap<Repo, Permission>, on_disk: &[Repo]) {
+    for (repo, perm) in repos {
+        if !repo.is_pattern() {
+            println!("  {} {repo}", perm.to_char());
+            continue;
+        }
+        let covered: Vec<String> = on_disk
+            .iter()
+            .filter(|r| {
+                resolve(repos.iter().map(|(g, p)| (g, *p)), r).is_some_and(|(g, _)| g == repo)
+            })
+            .map(|r| r.to_string())
+            .collect();
+        if covered.is_empty() {
+            println!("  {} {repo}", perm.to_char());
+        } else {
+            println!("  {} {repo} -> {}", perm.to_char(), covered.join(" "));
+        }
+    }
+}
+
+fn user_add(opts: &Options, user: &User) -> Result<(), Error> {
+    edit_config(opts, |cfg| cfg.add_user(user.clone()))?;
+    println!("added user {user}");
+    Ok(())
+}
+
+fn user_remove(opts: &Options, user: &User) -> Result<(), Error> {
+    edit_config(opts, |cfg| {
+        if cfg.remove_user(user) {
+            Ok(())
+        } else {
+            Err(Error::UserUnknown(user.as_str().to_string()))
+        }
+    })?;
+    println!("removed user {user}");
+    Ok(())
+}
+
+fn user_list(opts: &Options) -> Result<(), Error> {
+    let cfg = Config::load(&config_path(&opts.base))?;
+    for user in cfg.users() {
+        println!("{user}");
+    }
+    Ok(())
+}
+
+fn user_show(opts: &Options, user: &User) -> Result<(), Error> {
+    let cfg = Config::load(&config_path(&opts.base))?;
+    match cfg.permissions_of(user) {
+        None => Err(Error::UserUnknown(user.as_str().to_string())),
+        Some(repos) => {
+            for (repo, perm) in repos {
+                println!("{} {repo}", perm.to_char());
+            }
+            print_ref_rules(&cfg, &Subject::User(user.clone()), "");
+            for group in cfg.groups_of(user) {
+                println!("member of ={group}");
+            }
+            if cfg.is_admin(user) {
+                println!("admin");
+            }
+            Ok(())
+        }
+    }
+}
+
+fn group_add(opts: &Options, group: &Group, users: &[User]) -> Result<(), Error> {
+    let mut created = false;
+    edit_config(opts, |cfg| {
+        if !cfg.has_group(group) {
+            cfg.add_group(group.clone())?;
+            created = true;
+        }
+        for user in users {
+            cfg.add_member(group, user.clone())?;
+        }
+        Ok(())
+    })?;
+    if created {
+        println!("added group {group}");
+    }
+    for user in users {
+        println!("added {user} to {group}");
+    }
+    Ok(())
+}
+
+fn group_remove(opts: &Options, group: &Group, users: &[User]) -> Result<(), Error> {
+    let mut removed = Vec::new();
+    edit_config(opts, |cfg| {
+        if users.is_empty() {
+            if !cfg.remove_group(group) {
+                return Err(Error::GroupUnknown(group.as_str().to_string()));
+            }
+            return Ok(());
+        }
+        for user in users {
+            removed.push(cfg.remove_member(group, user)?);
+        }
+        Ok(())
+    })?;
+    if users.is_empty() {
+        println!("removed group {group}");
+    }
+    for (user, removed) in users.iter().zip(removed) {
+        if removed {
+            println!("removed {user} from {group}");
+        } else {
+            println!("{user} was not a member of {group}");
+        }
+    }
+    Ok(())
+}
+
+fn group_list(opts: &Options) -> Result<(), Error> {
+    let cfg = Config::load(&config_path(&opts.base))?;
+    for group in cfg.groups() {
+        let members: Vec<&str> = cfg
+            .members_of(group)
+            .unwrap_or_default()
+            .iter()
+            .map(|u| u.as_str())
+            .collect();
+        println!("{group}: {}", members.join(" "));
+    }
+    Ok(())
+}
+
+fn group_show(opts: &Options, group: &Group) -> Result<(), Error> {
+    let cfg = Config::load(&config_path(&opts.base))?;
+    let (Some(members), Some(repos)) = (cfg.members_of(group), cfg.group_permissions_of(group))
+    else {
+        return Err(Error::GroupUnknown(group.as_str().to_string()));
+    };
+    for member in members {
+        println!("member {member}");
+    }
+    for (repo, perm) in repos {
+        println!("{} {repo}", perm.to_char());
+    }
+    print_ref_rules(&cfg, &Subject::Group(group.clone()), "");
+    Ok(())
+}
+
+fn access(opts: &Options, user: User, repo: &Repo) -> Result<(), Error> {
+    let db = read_db(&config_path(&opts.base), user)?;
+    for op in Operation::ALL {
+        println!("{op}: {}", decide(&db, repo, op));
+    }
+    if !repos::repo_path(&opts.base, db.personal_dir.as_deref(), repo).exists() {
+        println!("create: {}", decide_create(&db, repo));
+    }
+    if let Some((grant, perm)) = db.permission(repo) {
+        println!("decided by the {} {grant} grant", perm.to_char());
+    }
+    for (pattern, perm) in db.ref_rules(repo) {
+        println!("push limited by the {} {pattern} ref rule", perm.to_char());
+    }
+    Ok(())
+}
+
+// --- helpers --------------------------------------------------------------
+
+/// Load the config, apply `f`, and write it back (and, if enabled, fix its
+/// ownership), holding its lock throughout and recording the change in the
+/// history. If `f` fails nothing is written. In a dry run, print the changes
+/// and the diff of each file instead.
+fn edit_config<F>(opts: &Options, f: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut Config) -> Result<(), Error>,
+{
+    let cfg_path = config_path(&opts.base);
+    if opts.dry_run {
+        let before = Config::load(&cfg_path)?;
+        let mut cfg = before.clone();
+        f(&mut cfg)?;
+        for change in before.diff(&cfg) {
+            println!("# {change}");
+        }
+        for (path, old, new) in cfg.edits(&cfg_path) {
+            let name = cfg.file_name(&path);
+            print!(
+                "{}",
+                diff::unified(&format!("a/{name}"), &format!("b/{name}"), &old, &new)
+            );
+        }
+        return Ok(());
+    }
+    let _lock = lock::lock(&cfg_path)?;
+    let (cfg, written) = history::recorded(&opts.base, &opts.operator, &opts.command, || {
+        let mut cfg = Config::load(&cfg_path)?;
+        f(&mut cfg)?;
+        let written = cfg.save(&cfg_path)?;
+        Ok((cfg, written))
+    })?;
+    for path in written {
+        if opts.chown {
+            chown(opts, false, &path)?;
+        }
+        println!("edited {}", cfg.file_name(&path));
+    }
+    if opts.chown {
+        chown(opts, true, &history::history_path(&opts.base))?;
+    }
+    Ok(())
+}
+
+/// Give `path` (and, if `recursive`, everything below it) to the owner in
+/// `opts`.
+pub fn chown(opts: &Options, recursive: bool, path: &Path) -> Result<(), Error> {
+    let mut args: Vec<&OsStr> = Vec::new();
+    if recursive {
+        args.push(OsStr::new("-R"));
+    }
+    args.push(OsStr::new(&opts.owner));
+    args.push(path.as_os_str());
+    run_command("chown", &args)
+}
+
+fn run_command(program: &str, args: &[&OsStr]) -> Result<(), Error> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| Error::CommandFailed(format!("{program}: {e}")))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::CommandFailed(format!(
+            "{program} exited with {status}"
+        )))
+    }
+}
+
+// --- argument parsing -----------------------------------------------------
+
+fn usage(msg: impl Into<String>) -> Error {
+    Error::UsageInvalid(msg.into())
+}
+
+/// The first argument and the rest, or a usage error saying `msg`.
+pub fn split_command<'a>(args: &'a [String], msg: &str) -> Result<(&'a str, &'a [String]), Error> {
+    match args.split_first() {
+        Some((first, rest)) => Ok((first.as_str(), rest)),
+        None => Err(usage(msg)),
+    }
+}
+
+/// The value following the flag at `args[*i]`, advancing `i` past it.
+pub fn flag_value(args: &[String], i: &mut usize, what: &str) -> Result<String, Error> {
+    *i += 1;
+    match args.get(*i) {
+        Some(v) => Ok(v.clone()),
+        None => Err(usage(format!("{} requires {what}", args[*i - 1]))),
+    }
+}
+
+/// What `repo grant` and `repo revoke` apply to.
+enum Target {
+    /// access to the repository
+    Access,
+    /// `--ref <pattern>`: a ref rule
+    Ref(RefPattern),
+    /// `--create`: creating matching repositories
+    Create,
+}
+
+/// Take `--ref <pattern>` or `--create` out of `args`, returning the other
+/// arguments.
+fn split_target_flags(args: &[String]) -> Result<(Vec<String>, Target), Error> {
+    let mut rest = Vec::new();
+    let mut target = Target::Access;
+    let mut i = 0;
+    while i < args.len() {
+        let next = match args[i].as_str() {
+            "--ref" => Target::Ref(RefPattern::from_string(flag_value(args, &mut i, "a ref")?)?),
+            "--create" => Target::Create,
+            _ => {
+                rest.push(args[i].clone());
+                i += 1;
+                continue;
+            }
+        };
+        if !matches!(target, Target::Access) {
+            return Err(usage("--ref and --create cannot be combined"));
+        }
+        target = next;
+        i += 1;
+    }
+    Ok((rest, target))
+}
+
+fn parse_subject_repo_perm(args: &[String]) -> Result<(Subject, Repo, Permission), Error> {
+    let mut positional: Vec<&str> = Vec::new();
+    let mut perm = Permission::Write;
+    for a in args {
+        match a.as_str() {
+            "-r" | "--read" => perm = Permission::Read,
+            "-w" | "--write" => perm = Permission::Write,
+            "--force" => perm = Permission::Force,
+            "--delete" => perm = Permission::Delete,
+            "--deny" => perm = Permission::Deny,
+            s if s.starts_with('-') => return Err(usage(format!("unknown flag: {s}"))),
+            s => positional.push(s),
+        }
+    }
+    if positional.len() != 2 {
+        return Err(usage("expected <who> <dir/repo>"));
+    }
+    let who = Subject::from_string(positional[0].to_string())?;
+    let repo = Repo::from_pattern(positional[1].to_string())?;
+    Ok((who, repo, perm))
+}
+
+fn parse_subject_repo(args: &[String]) -> Result<(Subject, Repo), Error> {
+    if args.len() != 2 {
+        return Err(usage("expected <who> <dir/repo>"));
+    }
+    let who = Subject::from_string(args[0].clone())?;
+    let repo = Repo::from_pattern(args[1].clone())?;
+    Ok((who, repo))
+}
+
+fn parse_subjects(args: &[String]) -> Result<Vec<Subject>, Error> {
+    args.iter()
+        .map(|a| Subject::from_string(a.clone()))
+        .collect()
+}
+
+fn parse_group_users(args: &[String]) -> Result<(Group, Vec<User>), Error> {
+    let Some((group, users)) = args.split_first() else {
+        return Err(usage("expected <group> [<user>...]"));
+    };
+    let group = Group::from_string(group.strip_prefix('=').unwrap_or(group).to_string())?;
+    let users = users
+        .iter()
+        .map(|u| User::from_string(u.clone()))
+        .collect::<Result<_, _>>()?;
+    Ok((group, users))
+}
+
+fn parse_user_repo(args: &[String]) -> Result<(User, Repo), Error> {
+    if args.len() != 2 {
+        return Err(usage("expected <user> <dir/repo>"));
+    }
+    let user = User::from_string(args[0].clone())?;
+    let repo = Repo::from_string(args[1].clone())?;
+    Ok((user, repo))
+}
+
+fn parse_one_user(args: &[String]) -> Result<User, Error> {
+    if args.len() != 1 {
+        return Err(usage("expected a single <user>"));
+    }
+    User::from_string(args[0].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn commands_edit_the_config() {
+        let base = TestDir::new("admin");
+        let opts = Options {
+            base: base.to_path_buf(),
+            owner: String::new(),
+            chown: false,
+            hook: PathBuf::from("/usr/bin/gitcontrol-hook"),
+            operator: "root".to_string(),
+            command: "gitcontrol test".to_string(),
+            dry_run: false,
+        };
+        run(&opts, "repo", &args("grant bob web/* -r")).unwrap();
+        run(&opts, "group", &args("add ops carol")).unwrap();
+        run(&opts, "admin", &args("add alice =ops")).unwrap();
+        run(&opts, "repo", &args("owner bob web/*")).unwrap();
+        run(&opts, PERMS, &args("web/site grant =ops +")).unwrap();
+        let cfg = Config::load(&config_path(&base)).unwrap();
+        assert_eq!(
+            cfg.to_string(),
+            "admin alice =ops\n\n@bob\nr web/*\nowner web/*\n\n=ops carol\n+ web/site\n"
+        );
+        run(&opts, PERMS, &args("web/site revoke =ops")).unwrap();
+        run(&opts, "repo", &args("disown web/*")).unwrap();
+        let cfg = Config::load(&config_path(&base)).unwrap();
+        assert_eq!(
+            cfg.owner_of(&Repo::from_ownable("web/*".to_string()).unwrap()),
+            None
+        );
+        assert!(
+            cfg.grants_on(&Repo::from_string("web/site".to_string()).unwrap())
+                .is_empty()
+        );
+        assert_eq!(history::entries(&base).unwrap().len(), 7);
+
+        for bad in [
+            "bogus",
+            "grant bob",
+            "grant bob web/x --create -r",
+            "revoke",
+            "owner bob",
+        ] {
+            assert!(
+                matches!(run(&opts, "repo", &args(bad)), Err(Error::UsageInvalid(_))),
+                "{bad}"
+            );
+        }
+        assert!(matches!(
+            run(&opts, "keys", &[]),
+            Err(Error::UsageInvalid(_))
+        ));
+
+        // the shell hands out nothing on the admin repository
+        for (cmd, bad) in [
+            ("repo", "grant bob gitcontrol/admin"),
+            ("repo", "grant =ops */* -r"),
+            ("repo", "grant bob gitcontrol/admin --ref refs/heads/*"),
+            ("repo", "create bob gitcontrol/admin"),
+            ("repo", "owner bob gitcontrol/*"),
+            (PERMS, "gitcontrol/admin grant bob +"),
+        ] {
+            assert!(
+                matches!(
+                    run_from_shell(&opts, cmd, &args(bad)),
+                    Err(Error::AccessDenied(_))
+                ),
+                "{bad}"
+            );
+        }
+        assert!(!base.join("gitcontrol/admin").exists());
+        assert_eq!(history::entries(&base).unwrap().len(), 7);
+        run_from_shell(&opts, "repo", &args("grant bob web/site")).unwrap();
+        assert_eq!(history::entries(&base).unwrap().len(), 8);
+
+        let before = fs::read_to_string(config_path(&base)).unwrap();
+        let dry_run = Options {
+            dry_run: true,
+            ..opts
+        };
+        run(&dry_run, "repo", &args("create bob web/new")).unwrap();
+        run(&dry_run, "user", &args("remove bob")).unwrap();
+        assert_eq!(fs::read_to_string(config_path(&base)).unwrap(), before);
+        assert!(!base.join("web/new").exists());
+        assert_eq!(history::entries(&base).unwrap().len(), 8);
+    }
+}
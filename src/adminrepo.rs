@@ -0,0 +1,413 @@
+//! The `gitcontrol/admin` repository: `gitcontrol.cfg`, `gitcontrol.d/` and
+//! the `users/` key files kept in git, gitolite-admin style, so access
+//! changes are reviewed and versioned like code.
+//!
+//! Pushing to its `master` branch changes the live configuration.
+//! `gitcontrol-hook`, as the `update` hook, [`export`]s the pushed commit and
+//! [`validate`]s it, rejecting the push if the config does not parse, a key
+//! file does not compile, or the pusher would lose write access to the admin
+//! repository. As the `post-receive` hook it then [`install`]s the files, or
+//! [`reset`]s the branch if they cannot be installed.
+//!
+//! [`import`] starts the repository off with the live configuration.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::admin::config_path;
+use crate::authkeys;
+use crate::config::{CONF_DIR, Config};
+use crate::errors::Error;
+use crate::history;
+use crate::hook::is_zero;
+use crate::lock;
+use crate::policy::{Operation, decide};
+use crate::types::{Repo, User};
+
+/// The admin repository.
+pub const ADMIN_REPO: &str = "gitcontrol/admin";
+/// The branch holding the live configuration; other branches are free for
+/// changes under review.
+pub const ADMIN_BRANCH: &str = "refs/heads/master";
+
+const CONFIG: &str = "gitcontrol.cfg";
+const USERS: &str = "users";
+
+pub fn is_admin_repo(repo: &Repo) -> bool {
+    repo.to_string() == ADMIN_REPO
+}
+
+/// Check out the tree of `commit` in the admin repository at `repo_path`
+/// into `dest`, which is created and must not exist yet.
+pub fn export(repo_path: &Path, commit: &str, dest: &Path) -> Result<(), Error> {
+    fs::create_dir(dest)?;
+    // a private index, leaving the repository's own alone
+    let index = dest.join(".index");
+    let result = git(
+        [
+            OsStr::new("--git-dir"),
+            repo_path.as_os_str(),
+            OsStr::new("--work-tree"),
+            dest.as_os_str(),
+            OsStr::new("checkout"),
+            OsStr::new(commit),
+            OsStr::new("--"),
+            OsStr::new("."),
+        ],
+        Some(&index),
+    );
+    let _ = fs::remove_file(&index);
+    result.map(|_| ())
+}
+
+/// Check the configuration exported to `stage`, returning the
+/// `authorized_keys` its key files compile to (forcing `shell`).
+///
+/// Only `gitcontrol.cfg` and what is under `gitcontrol.d/` gets installed,
+/// so the config may not include anything else.
+pub fn validate(stage: &Path, user: &User, shell: &str) -> Result<String, Error> {
+    let cfg = Config::read(&config_path(stage))?;
+    let conf_dir = fs::canonicalize(stage.join(CONF_DIR)).ok();
+    for file in &cfg.files()[1..] {
+        let inside = conf_dir
+            .as_ref()
+            .is_some_and(|dir| fs::canonicalize(file).is_ok_and(|f| f.starts_with(dir)));
+        if !inside {
+            return Err(Error::ConfigInvalid(format!(
+                "{}: included files must be in {CONF_DIR}/",
+                cfg.file_name(file)
+            )));
+        }
+    }
+    let keys = authkeys::compile(&stage.join(USERS), shell)?;
+    let admin_repo = Repo::from_string(ADMIN_REPO.to_string())?;
+    if !decide(&cfg.user_db(user), &admin_repo, Operation::Push).is_allowed() {
+        return Err(Error::ConfigInvalid(format!(
+            "{user} could no longer push to {ADMIN_REPO}"
+        )));
+    }
+    Ok(keys)
+}
+
+/// Replace the live `gitcontrol.cfg`, `gitcontrol.d/`, `users/` and
+/// `authorized_keys` under `base` with those exported to `stage` (which must
+/// be on the same file system, e.g. inside `base`) and validated into
+/// `authorized_keys`, under the locks of `gitcontrol.cfg` and
+/// `authorized_keys`. The history records `pusher` pushing `commit`.
+///
+/// The two files are replaced atomically. Each directory is moved aside and
+/// the new one moved in, directories first as the new `gitcontrol.cfg` may
+/// need them: a reader may find a directory missing for a moment, or the new
+/// `gitcontrol.d/` next to the old `gitcontrol.cfg`. If any step fails,
+/// whatever was replaced is put back.
+pub fn install(
+    stage: &Path,
+    base: &Path,
+    authorized_keys: &str,
+    pusher: &User,
+    commit: &str,
+) -> Result<(), Error> {
+    let keys_path = base.join(".ssh").join("authorized_keys");
+    let _config_lock = lock::lock(&config_path(base))?;
+    let _keys_lock = lock::lock(&keys_path)?;
+    let message = format!("push {} to {ADMIN_REPO}", &commit[..commit.len().min(12)]);
+    history::recorded(base, pusher.as_str(), &message, || {
+        let mut replaced = Vec::new();
+        let result = (|| {
+            replaced.push(replace(stage, base, CONF_DIR, true)?);
+            replaced.push(replace(stage, base, USERS, true)?);
+            replaced.push(replace(stage, base, CONFIG, false)?);
+            authkeys::write_authorized_keys(&keys_path, authorized_keys, true)
+        })();
+        match result {
+            Ok(()) => {
+                for r in replaced {
+                    r.discard();
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for r in replaced.into_iter().rev() {
+                    r.undo();
+                }
+                Err(e)
+            }
+        }
+    })
+}
+
+/// A file or directory of `base` that [`install`] replaced, and where what
+/// it replaced was kept.
+struct Replaced {
+    live: PathBuf,
+    old: Option<PathBuf>,
+    dir: bool,
+}
+
+impl Replaced {
+    /// Put back what was replaced.
+    fn undo(self) {
+        if self.dir && self.live.exists() {
+            let _ = fs::remove_dir_all(&self.live);
+        }
+        match &self.old {
+            Some(old) => {
+                let _ = fs::rename(old, &self.live);
+            }
+            None if !self.dir => {
+                let _ = fs::remove_file(&self.live);
+            }
+            None => {}
+        }
+    }
+
+    /// Drop the copy of what was replaced.
+    fn discard(self) {
+        match &self.old {
+            Some(old) if self.dir => {
+                let _ = fs::remove_dir_all(old);
+            }
+            Some(old) => {
+                let _ = fs::remove_file(old);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Replace `name` under `base` (a directory if `dir`) with the one under
+/// `stage`, keeping the live one as `.<name>.old`. A directory `stage` has
+/// none of is removed; a file must be there. On failure nothing is changed.
+fn replace(stage: &Path, base: &Path, name: &str, dir: bool) -> Result<Replaced, Error> {
+    let live = base.join(name);
+    let old = base.join(format!(".{name}.old"));
+    let new = stage.join(name);
+    if dir && old.exists() {
+        fs::remove_dir_all(&old)?;
+    } else if old.exists() {
+        fs::remove_file(&old)?;
+    }
+    let kept = match live.exists() {
+        // a file stays in place until the new one is renamed over it
+        true if dir => fs::rename(&live, &old).map(|()| Some(old)),
+        true => fs::hard_link(&live, &old).map(|()| Some(old)),
+        false => Ok(None),
+    }?;
+    let replaced = Replaced {
+        live,
+        old: kept,
+        dir,
+    };
+    if (new.exists() || !dir)
+        && let Err(e) = fs::rename(&new, &replaced.live)
+    {
+        replaced.undo();
+        return Err(e.into());
+    }
+    Ok(replaced)
+}
+
+/// Commit the live `gitcontrol.cfg`, `gitcontrol.d/` and `users/` under
+/// `base` to the [`ADMIN_BRANCH`] of the admin repository at `repo_path`,
+/// returning the commit. Git runs no hooks for this, so nothing is
+/// validated or installed.
+pub fn import(repo_path: &Path, base: &Path) -> Result<String, Error> {
+    let index = repo_path.join("gitcontrol-import.index");
+    let git_dir = repo_path.as_os_str();
+    let mut add = vec![
+        OsStr::new("--git-dir"),
+        git_dir,
+        OsStr::new("--work-tree"),
+        base.as_os_str(),
+        OsStr::new("add"),
+        OsStr::new("--"),
+        OsStr::new("gitcontrol.cfg"),
+        OsStr::new(USERS),
+    ];
+    if base.join(CONF_DIR).is_dir() {
+        add.push(OsStr::new(CONF_DIR));
+    }
+    let tree = git(add, Some(&index)).and_then(|_| {
+        git(
+            [OsStr::new("--git-dir"), git_dir, OsStr::new("write-tree")],
+            Some(&index),
+        )
+    });
+    let _ = fs::remove_file(&index);
+    let tree = tree?;
+
+    let commit = git(
+        [
+            OsStr::new("-c"),
+            OsStr::new("user.name=gitcontrol"),
+            OsStr::new("-c"),
+            OsStr::new("user.email=gitcontrol@localhost"),
+            OsStr::new("--git-dir"),
+            git_dir,
+            OsStr::new("commit-tree"),
+            OsStr::new(&tree),
+            OsStr::new("-m"),
+            OsStr::new("Import the live configuration"),
+        ],
+        None,
+    )?;
+    git(
+        [
+            OsStr::new("--git-dir"),
+            git_dir,
+            OsStr::new("update-ref"),
+            OsStr::new(ADMIN_BRANCH),
+            OsStr::new(&commit),
+        ],
+        None,
+    )?;
+    Ok(commit)
+}
+
+/// Move the [`ADMIN_BRANCH`] of the admin repository at `repo_path` back
+/// from `new` to `old` (removing it if `old` is all zeros), so it holds what
+/// is live again after `new` could not be installed.
+pub fn reset(repo_path: &Path, old: &str, new: &str) -> Result<(), Error> {
+    let mut args = vec![
+        OsStr::new("--git-dir"),
+        repo_path.as_os_str(),
+        OsStr::new("update-ref"),
+    ];
+    match is_zero(old) {
+        true => args.extend([OsStr::new("-d"), OsStr::new(ADMIN_BRANCH)]),
+        false => args.extend([OsStr::new(ADMIN_BRANCH), OsStr::new(old)]),
+    }
+    // only if nothing was pushed since
+    args.push(OsStr::new(new));
+    git(args, None).map(|_| ())
+}
+
+/// Run git, with `index` as its index file if given, returning its trimmed
+/// output.
+pub(crate) fn git<'a>(
+    args: impl IntoIterator<Item = &'a OsStr>,
+    index: Option<&Path>,
+) -> Result<String, Error> {
+    let mut cmd = Command::new("git");
+    cmd.args(args);
+    if let Some(index) = index {
+        cmd.env("GIT_INDEX_FILE", index);
+    }
+    let out = cmd
+        .output()
+        .map_err(|e| Error::CommandFailed(format!("git: {e}")))?;
+    if !out.status.success() {
+        return Err(Error::CommandFailed(format!(
+            "git exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    const ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+    fn user(s: &str) -> User {
+        User::from_string(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn import_export_validate_install() {
+        let dir = TestDir::new("adminrepo");
+        let base = dir.join("base");
+        let repo = dir.join("admin.git");
+        fs::create_dir_all(base.join(USERS)).unwrap();
+        fs::create_dir_all(base.join(CONF_DIR)).unwrap();
+        fs::write(config_path(&base), "@alice\nw gitcontrol/admin\n").unwrap();
+        fs::write(base.join("gitcontrol.d/web.cfg"), "@alice\nw web/site\n").unwrap();
+        fs::write(base.join("users/alice"), format!("{ED25519}\n")).unwrap();
+        git(
+            [
+                OsStr::new("init"),
+                OsStr::new("--bare"),
+                OsStr::new("-q"),
+                repo.as_os_str(),
+            ],
+            None,
+        )
+        .unwrap();
+
+        let commit = import(&repo, &base).unwrap();
+        let stage = base.join(".stage");
+        export(&repo, &commit, &stage).unwrap();
+        assert!(!stage.join(".index").exists());
+
+        let keys = validate(&stage, &user("alice"), "/bin/gc").unwrap();
+        assert_eq!(keys, format!("command=\"/bin/gc alice\" {ED25519}\n"));
+        assert!(validate(&stage, &user("bob"), "/bin/gc").is_err());
+        fs::write(stage.join("gitcontrol.d/web.cfg"), "include ../../x.cfg\n").unwrap();
+        fs::write(base.join("x.cfg"), "").unwrap();
+        assert!(matches!(
+            validate(&stage, &user("alice"), "/bin/gc"),
+            Err(Error::ConfigInvalid(e)) if e.contains("must be in gitcontrol.d/")
+        ));
+        fs::write(stage.join("gitcontrol.d/web.cfg"), "@alice\nw web/site\n").unwrap();
+
+        fs::write(config_path(&base), "@alice\n").unwrap();
+        install(&stage, &base, &keys, &user("alice"), &commit).unwrap();
+        assert_eq!(
+            fs::read_to_string(config_path(&base)).unwrap(),
+            "@alice\nw gitcontrol/admin\n"
+        );
+        assert!(base.join("users/alice").exists());
+        assert!(base.join("gitcontrol.d/web.cfg").exists());
+        assert!(!base.join(".users.old").exists());
+        let log = history::entries(&base).unwrap();
+        assert_eq!(
+            log[0].command,
+            format!("push {} to {ADMIN_REPO}", &commit[..12])
+        );
+        assert_eq!(log[0].operator, "alice");
+        assert_eq!(
+            fs::read_to_string(base.join(".ssh/authorized_keys")).unwrap(),
+            keys
+        );
+
+        // authorized_keys cannot be written: everything is put back
+        let stage = base.join(".stage2");
+        export(&repo, &commit, &stage).unwrap();
+        fs::write(config_path(&stage), "@alice\nw gitcontrol/admin\nr x/y\n").unwrap();
+        fs::remove_file(stage.join("users/alice")).unwrap();
+        fs::remove_dir_all(stage.join(CONF_DIR)).unwrap();
+        fs::remove_file(base.join(".ssh/authorized_keys")).unwrap();
+        fs::create_dir(base.join(".ssh/authorized_keys")).unwrap();
+        assert!(install(&stage, &base, &keys, &user("alice"), &commit).is_err());
+        assert_eq!(
+            fs::read_to_string(config_path(&base)).unwrap(),
+            "@alice\nw gitcontrol/admin\n"
+        );
+        assert!(base.join("users/alice").exists());
+        assert!(base.join("gitcontrol.d/web.cfg").exists());
+        for old in [".gitcontrol.cfg.old", ".users.old", ".gitcontrol.d.old"] {
+            assert!(!base.join(old).exists(), "{old}");
+        }
+        assert_eq!(history::entries(&base).unwrap().len(), log.len());
+
+        // the branch goes back to what is live, unless it moved on again
+        fs::write(config_path(&base), "@alice\nw gitcontrol/admin\nr x/y\n").unwrap();
+        let pushed = import(&repo, &base).unwrap();
+        assert!(reset(&repo, &commit, &commit).is_err());
+        reset(&repo, &commit, &pushed).unwrap();
+        let branch = [
+            OsStr::new("--git-dir"),
+            repo.as_os_str(),
+            OsStr::new("rev-parse"),
+            OsStr::new(ADMIN_BRANCH),
+        ];
+        assert_eq!(git(branch, None).unwrap(), commit);
+    }
+}
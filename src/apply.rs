@@ -0,0 +1,607 @@
+//! `gitcontrol apply`: bring the server to the [`State`] described in a file.
+//!
+//! [`plan`] compares the state with the live `gitcontrol.cfg`, the key files
+//! in `users/` and the repositories on disk, and [`execute`] carries the plan
+//! out. Every declared user and group exists afterwards, each group has
+//! exactly the members listed, a repository table holds every grant on its
+//! name or pattern (others are revoked) and its owner, and a user with `keys`
+//! has exactly those keys. Declared repositories missing on disk are created.
+//!
+//! With `prune`, users, groups and key files that are not declared are
+//! removed, as are grants and owners on names and patterns without a table.
+//! Repositories on disk are never deleted. Ref rules, `--create` grants and
+//! admins are left as they are, except where they belong to a pruned user
+//! or group.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::admin::{self, chown, config_path};
+use crate::authkeys;
+use crate::config::{Change, Config};
+use crate::diff;
+use crate::errors::Error;
+use crate::history;
+use crate::repos;
+use crate::state::State;
+use crate::types::{Repo, User};
+
+const USERS: &str = "users";
+
+/// What [`execute`] will do, from [`plan`].
+pub struct Plan {
+    /// The configuration to save, and how it differs from the live one.
+    pub config: Config,
+    pub changes: Vec<Change>,
+    /// Repositories to create, and where.
+    pub create: Vec<(Repo, PathBuf)>,
+    /// Key files to write: the user, the current contents and the new ones.
+    pub write_keys: Vec<(User, String, String)>,
+    pub remove_keys: Vec<User>,
+    /// `authorized_keys`, its current contents and the new ones if they
+    /// change.
+    pub authorized_keys: PathBuf,
+    pub old_authorized_keys: String,
+    pub new_authorized_keys: Option<String>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+            && self.create.is_empty()
+            && self.write_keys.is_empty()
+            && self.remove_keys.is_empty()
+            && self.new_authorized_keys.is_none()
+    }
+
+    /// The unified diff of every file the plan changes, relative to `base`.
+    pub fn diff(&self, base: &Path) -> String {
+        let mut out = String::new();
+        let mut file = |name: &str, old: &str, new: &str| {
+            out.push_str(&diff::unified(
+                &format!("a/{name}"),
+                &format!("b/{name}"),
+                old,
+                new,
+            ));
+        };
+        for (path, old, new) in self.config.edits(&config_path(base)) {
+            file(&self.config.file_name(&path), &old, &new);
+        }
+        for (user, old, new) in &self.write_keys {
+            file(&format!("{USERS}/{user}"), old, new);
+        }
+        if let Some(new) = &self.new_authorized_keys {
+            let path = &self.authorized_keys;
+            let name = path.strip_prefix(base).unwrap_or(path).display();
+            file(&name.to_string(), &self.old_authorized_keys, new);
+        }
+        out
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{change}")?;
+        }
+        for (repo, _) in &self.create {
+            writeln!(f, "create repository {repo}")?;
+        }
+        for (user, _, new) in &self.write_keys {
+            let n = keys(new).len();
+            writeln!(f, "write {USERS}/{user} ({n} key{})", plural(n))?;
+        }
+        for user in &self.remove_keys {
+            writeln!(f, "remove {USERS}/{user}")?;
+        }
+        if let Some(new) = &self.new_authorized_keys {
+            let n = new.lines().count();
+            let path = self.authorized_keys.display();
+            writeln!(f, "write {path} ({n} key{})", plural(n))?;
+        }
+        Ok(())
+    }
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+/// The keys in a key file: its lines other than blank ones and comments.
+fn keys(contents: &str) -> Vec<&str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect()
+}
+
+/// What it takes to bring `base` to `state`, with `authorized_keys` forcing
+/// `shell`. Every key is validated here, so [`execute`] only fails on I/O.
+/// Hold the locks of `gitcontrol.cfg` and `authorized_keys` from here until
+/// the plan is executed.
+pub fn plan(base: &Path, state: &State, prune: bool, shell: &str) -> Result<Plan, Error> {
+    let live = Config::load(&config_path(base))?;
+    let mut cfg = live.clone();
+
+    for user in state.users.keys() {
+        if !cfg.has_user(user) {
+            cfg.add_user(user.clone())?;
+        }
+    }
+    for (group, members) in &state.groups {
+        if !cfg.has_group(group) {
+            cfg.add_group(group.clone())?;
+        }
+        let current = cfg.members_of(group).unwrap_or_default().to_vec();
+        for user in current.iter().filter(|u| !members.contains(u)) {
+            cfg.remove_member(group, user)?;
+        }
+        for user in members {
+            cfg.add_member(group, user.clone())?;
+        }
+    }
+    if prune {
+        let users: Vec<User> = cfg.users().cloned().collect();
+        for user in users.iter().filter(|u| !state.users.contains_key(u)) {
+            cfg.remove_user(user);
+        }
+        let groups: Vec<_> = cfg.groups().cloned().collect();
+        for group in groups.iter().filter(|g| !state.groups.contains_key(g)) {
+            cfg.remove_group(group);
+        }
+    }
+
+    // the names and patterns granted or owned anywhere, to find those
+    // without a table
+    let mut granted: Vec<Repo> = cfg.grants().map(|(_, repo, _)| repo.clone()).collect();
+    for group in cfg.groups() {
+        granted.extend(
+            cfg.group_permissions_of(group)
+                .into_iter()
+                .flatten()
+                .map(|(r, _)| r.clone()),
+        );
+    }
+    for user in cfg.users() {
+        granted.extend(cfg.owned_by(user).cloned());
+    }
+    granted.sort();
+    granted.dedup();
+    for repo in &granted {
+        if state.repos.contains_key(repo) || !prune {
+            continue;
+        }
+        for (subject, _) in cfg.grants_on(repo) {
+            cfg.revoke_subject(&subject, repo);
+        }
+        cfg.remove_owner(repo);
+    }
+    for (repo, wanted) in &state.repos {
+        for (subject, _) in cfg.grants_on(repo) {
+            if !wanted.grants.contains_key(&subject) {
+                cfg.revoke_subject(&subject, repo);
+            }
+        }
+        for (subject, permission) in &wanted.grants {
+            cfg.set_subject_permission(subject, repo.clone(), *permission);
+        }
+        match &wanted.owner {
+            Some(owner) if cfg.owner_of(repo) != Some(owner) => cfg.set_owner(repo.clone(), owner),
+            Some(_) => {}
+            None => {
+                cfg.remove_owner(repo);
+            }
+        }
+    }
+
+    let mut create = Vec::new();
+    for repo in state.repos.keys().filter(|r| !r.is_pattern()) {
+        let path = repos::repo_path(base, cfg.personal_dir(), repo);
+        if !repos::is_bare_repo(&path) {
+            if path.exists() {
+                return Err(Error::RepoExists(format!(
+                    "{repo}: {} exists but is not a bare repository",
+                    path.display()
+                )));
+            }
+            create.push((repo.clone(), path));
+        }
+    }
+
+    let users_dir = base.join(USERS);
+    let mut entries = match users_dir.exists() {
+        true => authkeys::load_user_keys(&users_dir)?,
+        false => Vec::new(),
+    };
+    let mut write_keys = Vec::new();
+    let mut remove_keys = Vec::new();
+    for (user, declared) in &state.users {
+        let Some(declared) = declared else { continue };
+        let new: String = declared.iter().map(|k| format!("{}\n", k.trim())).collect();
+        match entries.iter_mut().find(|(u, _)| u == user) {
+            Some((_, old)) if keys(old) == keys(&new) => {}
+            Some((_, old)) => {
+                write_keys.push((user.clone(), old.clone(), new.clone()));
+                *old = new;
+            }
+            None => {
+                write_keys.push((user.clone(), String::new(), new.clone()));
+                entries.push((user.clone(), new));
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    if prune {
+        entries.retain(|(user, _)| match state.users.contains_key(user) {
+            true => true,
+            false => {
+                remove_keys.push(user.clone());
+                false
+            }
+        });
+    }
+
+    let authorized_keys = base.join(".ssh").join("authorized_keys");
+    let new = authkeys::compile_authorized_keys(&entries, shell)?;
+    let old = match fs::read_to_string(&authorized_keys) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        old => old?,
+    };
+    let changed = new != old || !authorized_keys.exists();
+
+    Ok(Plan {
+        changes: live.diff(&cfg),
+        config: cfg,
+        create,
+        write_keys,
+        remove_keys,
+        authorized_keys,
+        old_authorized_keys: old,
+        new_authorized_keys: changed.then_some(new),
+    })
+}
+
+/// Carry out `plan` as one change. The key files and `authorized_keys` are
+/// written to hidden temporary files first; then the repositories are
+/// created and the configuration is saved (recorded in the history as
+/// `opts.command`), and only then are the temporary files renamed into
+/// place. If any step fails, everything done so far is undone: the new
+/// repositories are removed, the configuration is restored from the history
+/// and the old key files are put back.
+pub fn execute(opts: &admin::Options, plan: &Plan) -> Result<(), Error> {
+    let mut done = Done::default();
+    match carry_out(opts, plan, &mut done) {
+        Ok(()) => {
+            for (repo, _) in &plan.create {
+                println!("created {repo}");
+            }
+            for path in &done.edited {
+                println!("edited {}", plan.config.file_name(path));
+            }
+            for (user, _, _) in &plan.write_keys {
+                println!("wrote {USERS}/{user}");
+            }
+            for user in &plan.remove_keys {
+                println!("removed {USERS}/{user}");
+            }
+            if let Some(content) = &plan.new_authorized_keys {
+                println!(
+                    "wrote {} key(s) to {}",
+                    content.lines().count(),
+                    plan.authorized_keys.display()
+                );
+            }
+            done.discard();
+            Ok(())
+        }
+        Err(e) => {
+            done.undo(opts, plan);
+            Err(e)
+        }
+    }
+}
+
+fn carry_out<'a>(opts: &admin::Options, plan: &'a Plan, done: &mut Done<'a>) -> Result<(), Error> {
+    let base = &opts.base;
+    let users_dir = base.join(USERS);
+    if !plan.write_keys.is_empty() {
+        fs::create_dir_all(&users_dir)?;
+    }
+    let mut staged = Vec::new();
+    for (user, _, new) in &plan.write_keys {
+        let path = users_dir.join(user.as_str());
+        let tmp = authkeys::write_temp(&path, new, 0o644)?;
+        done.temporary.push(tmp.clone());
+        if opts.chown {
+            chown(opts, false, &tmp)?;
+        }
+        staged.push((tmp, path));
+    }
+    if let Some(content) = &plan.new_authorized_keys {
+        let path = &plan.authorized_keys;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+            fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
+            if opts.chown {
+                chown(opts, false, parent)?;
+            }
+        }
+        let tmp = authkeys::write_temp(path, content, 0o600)?;
+        done.temporary.push(tmp.clone());
+        if opts.chown {
+            chown(opts, false, &tmp)?;
+        }
+        staged.push((tmp, path.clone()));
+    }
+
+    for (_, path) in &plan.create {
+        // the plan checked that nothing is there yet
+        done.created.push(path);
+        repos::init_bare(path, &opts.hook)?;
+        if opts.chown {
+            if let Some(parent) = path.parent() {
+                chown(opts, false, parent)?;
+            }
+            chown(opts, true, path)?;
+        }
+    }
+
+    if !plan.changes.is_empty() {
+        let cfg_path = config_path(base);
+        history::record(base, &opts.operator, history::OUTSIDE)?;
+        let before = history::entries(base)?.first().map(|e| e.id.clone());
+        done.config = Some(before);
+        done.edited = plan
+            .config
+            .edits(&cfg_path)
+            .into_iter()
+            .map(|(path, _, _)| path)
+            .collect();
+        history::recorded(base, &opts.operator, &opts.command, || {
+            plan.config.save(&cfg_path)
+        })?;
+        if opts.chown {
+            for path in &done.edited {
+                chown(opts, false, path)?;
+            }
+            chown(opts, true, &history::history_path(base))?;
+        }
+    }
+
+    for (tmp, path) in staged {
+        done.replaced.push(replace(&tmp, path)?);
+    }
+    for user in &plan.remove_keys {
+        let path = users_dir.join(user.as_str());
+        let old = authkeys::sibling(&path, "old")?;
+        fs::rename(&path, &old)?;
+        done.replaced.push(Replaced {
+            live: path,
+            old: Some(old),
+        });
+    }
+    Ok(())
+}
+
+/// What [`carry_out`] has done so far, for [`execute`] to keep or undo.
+#[derive(Default)]
+struct Done<'a> {
+    /// Temporary files written, whether or not renamed into place since.
+    temporary: Vec<PathBuf>,
+    created: Vec<&'a Path>,
+    /// Once the configuration is being saved: the history entry it was
+    /// recorded as beforehand, if any, and the files it writes.
+    config: Option<Option<String>>,
+    edited: Vec<PathBuf>,
+    replaced: Vec<Replaced>,
+}
+
+impl Done<'_> {
+    fn discard(self) {
+        for r in self.replaced {
+            r.discard();
+        }
+    }
+
+    fn undo(self, opts: &admin::Options, plan: &Plan) {
+        for r in self.replaced.into_iter().rev() {
+            r.undo();
+        }
+        for tmp in &self.temporary {
+            let _ = fs::remove_file(tmp);
+        }
+        let base = &opts.base;
+        match &self.config {
+            // record whatever was saved, so it can be rolled back from
+            Some(Some(before)) => {
+                let restored = history::record(base, &opts.operator, &opts.command)
+                    .and_then(|_| history::restore(base, before))
+                    .and_then(|_| {
+                        let message = format!("undo failed {}", opts.command);
+                        history::record(base, &opts.operator, &message)
+                    });
+                if let Err(e) = restored {
+                    eprintln!(
+                        "gitcontrol: warning: {} could not be restored: {e}",
+                        plan.config.file_name(&config_path(base))
+                    );
+                }
+            }
+            // there was no configuration before
+            Some(None) => {
+                for path in &self.edited {
+                    let _ = fs::remove_file(path);
+                }
+            }
+            None => {}
+        }
+        for path in self.created.iter().rev() {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+}
+
+/// A file [`carry_out`] replaced or removed, and where the old one was kept.
+struct Replaced {
+    live: PathBuf,
+    old: Option<PathBuf>,
+}
+
+impl Replaced {
+    /// Put back what was replaced.
+    fn undo(self) {
+        match &self.old {
+            Some(old) => {
+                let _ = fs::rename(old, &self.live);
+            }
+            None => {
+                let _ = fs::remove_file(&self.live);
+            }
+        }
+    }
+
+    /// Drop the copy of what was replaced.
+    fn discard(self) {
+        if let Some(old) = &self.old {
+            let _ = fs::remove_file(old);
+        }
+    }
+}
+
+/// Rename `tmp` over `live`, first keeping the live file as `.<name>.old`.
+/// On failure nothing is changed.
+fn replace(tmp: &Path, live: PathBuf) -> Result<Replaced, Error> {
+    let old = authkeys::sibling(&live, "old")?;
+    if old.exists() {
+        fs::remove_file(&old)?;
+    }
+    let kept = match live.exists() {
+        // the live file stays in place until the new one is renamed over it
+        true => fs::hard_link(&live, &old).map(|()| Some(old)),
+        false => Ok(None),
+    }?;
+    if let Err(e) = fs::rename(tmp, &live) {
+        if let Some(old) = &kept {
+            let _ = fs::remove_file(old);
+        }
+        return Err(e.into());
+    }
+    Ok(Replaced { live, old: kept })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn options(base: &Path) -> admin::Options {
+        admin::Options {
+            base: base.to_path_buf(),
+            owner: String::new(),
+            chown: false,
+            hook: PathBuf::from("/bin/true"),
+            operator: "root".to_string(),
+            command: "gitcontrol apply state.toml".to_string(),
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn plans_and_applies() {
+        let base = TestDir::new("apply");
+        fs::create_dir_all(base.join(USERS)).unwrap();
+        fs::write(
+            config_path(&base),
+            "# live\n@alice\nr web/site\nw docs/old\n@carol\nw web/site\n=devs carol\n",
+        )
+        .unwrap();
+        fs::write(base.join("users/carol"), "").unwrap();
+
+        let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIDx alice@laptop";
+        let state = State::parse(&format!(
+            "[users.alice]\nkeys = [\"{key}\"]\n[users.bob]\n\
+             [groups.devs]\nmembers = [\"alice\", \"bob\"]\n\
+             [repos.\"web/site\"]\nowner = \"alice\"\nwrite = [\"alice\", \"=devs\"]\n"
+        ))
+        .unwrap();
+
+        let plan = plan(&base, &state, false, "/bin/sh").unwrap();
+        let changes: Vec<String> = plan.changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            [
+                "add user bob",
+                "remove carol from devs",
+                "add alice to devs",
+                "add bob to devs",
+                "alice web/site: read -> write",
+                "carol web/site: write -> none",
+                "=devs web/site: none -> write",
+                "owner of web/site: alice",
+            ]
+        );
+        assert_eq!(plan.create.len(), 1);
+        assert_eq!(plan.write_keys.len(), 1);
+        assert!(plan.remove_keys.is_empty());
+        assert!(
+            plan.diff(&base)
+                .contains("+++ b/users/alice\n@@ -0,0 +1,1 @@\n+ssh-ed25519")
+        );
+
+        // authorized_keys cannot be replaced: everything is undone
+        let live = fs::read_to_string(config_path(&base)).unwrap();
+        fs::create_dir_all(base.join(".ssh/authorized_keys")).unwrap();
+        assert!(execute(&options(&base), &plan).is_err());
+        assert_eq!(fs::read_to_string(config_path(&base)).unwrap(), live);
+        assert!(!base.join("web/site").exists());
+        let users: Vec<_> = fs::read_dir(base.join(USERS))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(users, ["carol"]);
+        assert!(!base.join(".ssh/.authorized_keys.tmp").exists());
+        let log = history::entries(&base).unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[0].command, "undo failed gitcontrol apply state.toml");
+        fs::remove_dir(base.join(".ssh/authorized_keys")).unwrap();
+
+        execute(&options(&base), &plan).unwrap();
+        assert!(repos::is_bare_repo(&base.join("web/site")));
+        let keys = fs::read_to_string(base.join(".ssh/authorized_keys")).unwrap();
+        assert_eq!(keys, format!("command=\"/bin/sh alice\" {key}\n"));
+        assert_eq!(history::entries(&base).unwrap().len(), 4);
+
+        // applied again, nothing is left to do, unless pruning
+        assert!(
+            super::plan(&base, &state, false, "/bin/sh")
+                .unwrap()
+                .is_empty()
+        );
+        let pruned = super::plan(&base, &state, true, "/bin/sh").unwrap();
+        let changes: Vec<String> = pruned.changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            ["remove user carol", "alice docs/old: write -> none"]
+        );
+        assert_eq!(
+            pruned.remove_keys,
+            [User::from_string("carol".to_string()).unwrap()]
+        );
+        execute(&options(&base), &pruned).unwrap();
+        let users: Vec<_> = fs::read_dir(base.join(USERS))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(users, ["alice"]);
+        assert!(
+            super::plan(&base, &state, true, "/bin/sh")
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
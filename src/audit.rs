@@ -0,0 +1,542 @@
+//! Append-only access log written by the shell.
+//!
+//! Every connection attempt, allowed or denied, becomes one line in
+//! `<base>/gitcontrol.log`, with tab separated fields:
+//!
+//! ```text
+//! 2026-10-17T09:14:02Z  alice  git-receive-pack  web/site  allowed  10.0.0.7 52114  -
+//! 2026-10-17T09:15:40Z  bob    git-receive-pack  web/site  denied   10.0.0.9 40022  8
+//! ```
+//!
+//! that is: UTC timestamp, user, command, repository (`-` when there is
+//! none), decision, the client address and port from `SSH_CONNECTION`, and
+//! the shell's exit code on denial (`-` otherwise).
+//!
+//! Each entry is written with a single `write` on a file opened in append
+//! mode while holding an exclusive lock, so concurrent shells never
+//! interleave their lines.
+//!
+//! Reading goes through [`log_files`], which also picks up rotated logs
+//! (`gitcontrol.log.1`, `gitcontrol.log-20261017`, `gitcontrol.log.2.gz`...),
+//! so queries cover the whole trail regardless of how it was rotated.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::errors::Error;
+
+/// Name of the log file, next to `gitcontrol.cfg` in the base directory.
+pub const LOG_FILE: &str = "gitcontrol.log";
+
+/// Placeholder written for an absent field.
+const NONE: &str = "-";
+
+pub fn log_path(base: &Path) -> PathBuf {
+    base.join(LOG_FILE)
+}
+
+/// Seconds since the unix epoch, displayed as an ISO 8601 UTC timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Timestamp(secs)
+    }
+
+    /// Parse a UTC date (`2026-10-17`) or date and time
+    /// (`2026-10-17T09:14:02Z`, `2026-10-17 09:14`), as used in the log and
+    /// on the command line.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::TimestampInvalid(s.to_string());
+        let (date, time) = match s.split_once(['T', ' ']) {
+            Some((d, t)) => (d, Some(t.strip_suffix('Z').unwrap_or(t))),
+            None => (s, None),
+        };
+
+        let date: Vec<&str> = date.split('-').collect();
+        let [y, m, d] = date.as_slice() else {
+            return Err(invalid());
+        };
+        let y: i64 = number(y, 4).ok_or_else(invalid)?;
+        let m: u32 = number(m, 2).ok_or_else(invalid)?;
+        let d: u32 = number(d, 2).ok_or_else(invalid)?;
+        if !(1..=12).contains(&m) || d < 1 || d > days_in_month(y, m) || y < 1970 {
+            return Err(invalid());
+        }
+
+        let secs = match time {
+            None => 0,
+            Some(t) => {
+                let hms: Vec<u64> = t
+                    .split(':')
+                    .map(|p| number(p, 2).ok_or_else(invalid))
+                    .collect::<Result<_, _>>()?;
+                let (h, mi, se) = match hms.as_slice() {
+                    [h, mi] => (*h, *mi, 0),
+                    [h, mi, se] => (*h, *mi, *se),
+                    _ => return Err(invalid()),
+                };
+                if h > 23 || mi > 59 || se > 59 {
+                    return Err(invalid());
+                }
+                h * 3600 + mi * 60 + se
+            }
+        };
+        Ok(Timestamp(days_from_civil(y, m, d) as u64 * 86400 + secs))
+    }
+}
+
+/// Parse a run of exactly `digits` ASCII digits.
+fn number<T: std::str::FromStr>(s: &str, digits: usize) -> Option<T> {
+    if s.len() != digits || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn days_in_month(y: i64, m: u32) -> u32 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Convert a civil date into days since 1970-01-01.
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = i64::from(if m > 2 { m - 3 } else { m + 9 });
+    let doy = (153 * mp + 2) / 5 + i64::from(d) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days = (self.0 / 86400) as i64;
+        let secs = self.0 % 86400;
+        let (y, m, d) = civil_from_days(days);
+        write!(
+            f,
+            "{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}Z",
+            secs / 3600,
+            (secs / 60) % 60,
+            secs % 60
+        )
+    }
+}
+
+/// Convert days since 1970-01-01 into a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+/// One access attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub timestamp: Timestamp,
+    pub user: String,
+    pub command: String,
+    pub repo: Option<String>,
+    pub allowed: bool,
+    /// client address and port, from `SSH_CONNECTION`
+    pub source: Option<String>,
+    /// the shell's exit code when the attempt was denied
+    pub code: Option<i32>,
+}
+
+impl Entry {
+    /// The log line for this entry, including the trailing newline.
+    pub fn to_line(&self) -> String {
+        let repo = self.repo.as_deref().unwrap_or(NONE);
+        let source = self.source.as_deref().unwrap_or(NONE);
+        let code = self.code.map(|c| c.to_string());
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.timestamp,
+            field(&self.user),
+            field(&self.command),
+            field(repo),
+            if self.allowed { "allowed" } else { "denied" },
+            field(source),
+            code.as_deref().unwrap_or(NONE),
+        )
+    }
+
+    /// Parse a line written by [`Entry::to_line`].
+    pub fn parse(line: &str) -> Result<Entry, Error> {
+        let invalid = |why: &str| Error::LogInvalid(format!("{why}: {line:?}"));
+        let fields: Vec<&str> = line.trim_end_matches('\n').split('\t').collect();
+        let [timestamp, user, command, repo, decision, source, code] = fields.as_slice() else {
+            return Err(invalid("expected 7 fields"));
+        };
+        let optional = |s: &str| (s != NONE).then(|| s.to_string());
+        Ok(Entry {
+            timestamp: Timestamp::parse(timestamp)?,
+            user: user.to_string(),
+            command: command.to_string(),
+            repo: optional(repo),
+            allowed: match *decision {
+                "allowed" => true,
+                "denied" => false,
+                _ => return Err(invalid("unknown decision")),
+            },
+            source: optional(source),
+            code: match *code {
+                NONE => None,
+                c => Some(c.parse().map_err(|_| invalid("invalid code"))?),
+            },
+        })
+    }
+
+    /// The entry as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let optional = |v: Option<&str>| v.map(json_string).unwrap_or_else(|| "null".to_string());
+        format!(
+            "{{\"timestamp\":{},\"user\":{},\"command\":{},\"repo\":{},\"decision\":{},\"source\":{},\"code\":{}}}",
+            json_string(&self.timestamp.to_string()),
+            json_string(&self.user),
+            json_string(&self.command),
+            optional(self.repo.as_deref()),
+            json_string(if self.allowed { "allowed" } else { "denied" }),
+            optional(self.source.as_deref()),
+            self.code
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| "null".to_string()),
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Criteria selecting log entries; `None` fields match anything.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    pub user: Option<String>,
+    pub repo: Option<String>,
+    pub command: Option<String>,
+    pub allowed: Option<bool>,
+    /// inclusive lower bound
+    pub since: Option<Timestamp>,
+    /// exclusive upper bound
+    pub until: Option<Timestamp>,
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.user.as_ref().is_none_or(|u| *u == entry.user)
+            && self
+                .repo
+                .as_ref()
+                .is_none_or(|r| entry.repo.as_ref() == Some(r))
+            && self.command.as_ref().is_none_or(|c| *c == entry.command)
+            && self.allowed.is_none_or(|a| a == entry.allowed)
+            && self.since.is_none_or(|t| entry.timestamp >= t)
+            && self.until.is_none_or(|t| entry.timestamp < t)
+    }
+}
+
+/// Make a client supplied value safe to put in a field: no tabs or newlines
+/// (which would forge fields or entries), never empty, bounded length.
+fn field(s: &str) -> String {
+    const MAX: usize = 128;
+    if s.is_empty() {
+        return NONE.to_string();
+    }
+    s.chars()
+        .take(MAX)
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect()
+}
+
+/// The client address and port from an `SSH_CONNECTION` value
+/// (`client_ip client_port server_ip server_port`).
+pub fn source_of_ssh_connection(s: &str) -> Option<String> {
+    let mut fields = s.split_whitespace();
+    let ip = fields.next()?;
+    match fields.next() {
+        Some(port) => Some(format!("{ip} {port}")),
+        None => Some(ip.to_string()),
+    }
+}
+
+/// Append `entry` to the log at `path`, creating it if needed.
+pub fn append(path: &Path, entry: &Entry) -> Result<(), Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.lock()?;
+    file.write_all(entry.to_line().as_bytes())?;
+    Ok(())
+}
+
+/// The current log and every rotated copy of it in `base`, oldest first.
+pub fn log_files(base: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(base)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if is_log_file(name) && entry.file_type()?.is_file() {
+            files.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// `gitcontrol.log`, or a copy of it rotated by logrotate, numbered
+/// (`gitcontrol.log.<N>`) or with `dateext` (`gitcontrol.log-<YYYYMMDD>`),
+/// either one possibly compressed (`.gz`).
+fn is_log_file(name: &str) -> bool {
+    let Some(rest) = name.strip_prefix(LOG_FILE) else {
+        return false;
+    };
+    if rest.is_empty() {
+        return true;
+    }
+    let rest = rest.strip_suffix(".gz").unwrap_or(rest);
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match (rest.strip_prefix('.'), rest.strip_prefix('-')) {
+        (Some(n), _) => digits(n),
+        (_, Some(date)) => date.len() == 8 && digits(date),
+        _ => false,
+    }
+}
+
+/// Read a log file, decompressing it first if it was rotated with `compress`.
+pub fn read_log_file(path: &Path) -> Result<String, Error> {
+    if path.extension().is_some_and(|e| e == "gz") {
+        let out = Command::new("gzip")
+            .arg("-dc")
+            .arg(path)
+            .output()
+            .map_err(|e| Error::CommandFailed(format!("gzip: {e}")))?;
+        if !out.status.success() {
+            return Err(Error::CommandFailed(format!(
+                "gzip -dc {} exited with {}",
+                path.display(),
+                out.status
+            )));
+        }
+        String::from_utf8(out.stdout)
+            .map_err(|_| Error::LogInvalid(format!("{} is not UTF-8", path.display())))
+    } else {
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn entry() -> Entry {
+        Entry {
+            timestamp: Timestamp(1_700_000_000),
+            user: "alice".to_string(),
+            command: "git-receive-pack".to_string(),
+            repo: Some("web/site".to_string()),
+            allowed: false,
+            source: Some("10.0.0.9 40022".to_string()),
+            code: Some(8),
+        }
+    }
+
+    #[test]
+    fn formats_timestamps_in_utc() {
+        assert_eq!(Timestamp(0).to_string(), "1970-01-01T00:00:00Z");
+        assert_eq!(Timestamp(951_782_400).to_string(), "2000-02-29T00:00:00Z");
+        assert_eq!(Timestamp(1_700_000_000).to_string(), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn formats_one_line_per_entry() {
+        assert_eq!(
+            entry().to_line(),
+            "2023-11-14T22:13:20Z\talice\tgit-receive-pack\tweb/site\tdenied\t10.0.0.9 40022\t8\n"
+        );
+
+        let mut allowed = entry();
+        allowed.allowed = true;
+        allowed.repo = None;
+        allowed.code = None;
+        allowed.source = None;
+        assert!(allowed.to_line().ends_with("\t-\tallowed\t-\t-\n"));
+    }
+
+    #[test]
+    fn client_values_cannot_forge_fields_or_lines() {
+        let mut e = entry();
+        e.command = "evil\tallowed\ncommand".to_string();
+        let line = e.to_line();
+        assert_eq!(line.matches('\t').count(), 6);
+        assert_eq!(line.matches('\n').count(), 1);
+    }
+
+    #[test]
+    fn source_is_client_address_and_port() {
+        assert_eq!(
+            source_of_ssh_connection("10.0.0.9 40022 10.0.0.1 22").as_deref(),
+            Some("10.0.0.9 40022")
+        );
+        assert_eq!(source_of_ssh_connection(""), None);
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        for t in [0, 951_782_400, 1_700_000_000, 1_792_220_042] {
+            let ts = Timestamp(t);
+            assert_eq!(Timestamp::parse(&ts.to_string()).unwrap(), ts);
+        }
+        assert_eq!(
+            Timestamp::parse("2023-11-14").unwrap(),
+            Timestamp(1_699_920_000)
+        );
+        assert_eq!(
+            Timestamp::parse("2023-11-14 22:13").unwrap(),
+            Timestamp(1_699_999_980)
+        );
+        for bad in [
+            "",
+            "2023-11",
+            "2023-13-01",
+            "2023-02-29",
+            "2023-11-14T25:00",
+            "yesterday",
+        ] {
+            assert!(Timestamp::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        let e = entry();
+        assert_eq!(Entry::parse(&e.to_line()).unwrap(), e);
+
+        let mut allowed = entry();
+        allowed.allowed = true;
+        allowed.repo = None;
+        allowed.code = None;
+        assert_eq!(Entry::parse(&allowed.to_line()).unwrap(), allowed);
+
+        assert!(Entry::parse("2023-11-14T22:13:20Z\talice").is_err());
+    }
+
+    #[test]
+    fn json_escapes_and_uses_null() {
+        let mut e = entry();
+        e.user = "a\"b".to_string();
+        e.source = None;
+        assert_eq!(
+            e.to_json(),
+            "{\"timestamp\":\"2023-11-14T22:13:20Z\",\"user\":\"a\\\"b\",\"command\":\"git-receive-pack\",\"repo\":\"web/site\",\"decision\":\"denied\",\"source\":null,\"code\":8}"
+        );
+    }
+
+    #[test]
+    fn filter_matches_each_criterion() {
+        let e = entry();
+        assert!(Filter::default().matches(&e));
+
+        let by_user = |u: &str| Filter {
+            user: Some(u.to_string()),
+            ..Filter::default()
+        };
+        assert!(by_user("alice").matches(&e));
+        assert!(!by_user("bob").matches(&e));
+
+        let by_repo = Filter {
+            repo: Some("web/other".to_string()),
+            ..Filter::default()
+        };
+        assert!(!by_repo.matches(&e));
+
+        let allowed_only = Filter {
+            allowed: Some(true),
+            ..Filter::default()
+        };
+        assert!(!allowed_only.matches(&e));
+
+        let window = |since: u64, until: u64| Filter {
+            since: Some(Timestamp(since)),
+            until: Some(Timestamp(until)),
+            ..Filter::default()
+        };
+        assert!(window(1_700_000_000, 1_700_000_001).matches(&e));
+        assert!(!window(1_600_000_000, 1_700_000_000).matches(&e));
+    }
+
+    #[test]
+    fn appends_lines_and_finds_rotated_logs() {
+        let dir = TestDir::new("audit");
+        let path = log_path(&dir);
+        append(&path, &entry()).unwrap();
+        append(&path, &entry()).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+
+        for name in [
+            "gitcontrol.log.1",
+            "gitcontrol.log.2.gz",
+            "gitcontrol.log-20261016",
+            "gitcontrol.log-20261015.gz",
+            "gitcontrol.log-old",
+            "gitcontrol.log.lock",
+            "gitcontrol.log.swp",
+            "gitcontrol.log~",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let mut names: Vec<String> = log_files(&dir)
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "gitcontrol.log",
+                "gitcontrol.log-20261015.gz",
+                "gitcontrol.log-20261016",
+                "gitcontrol.log.1",
+                "gitcontrol.log.2.gz",
+            ]
+        );
+    }
+}
@@ -21,9 +21,16 @@
 //! key text are placed on the same line as the forced command: a stray
 //! newline or quote would otherwise let an entry escape its `command="..."`
 //! restriction.
+//!
+//! Users manage their own key file over SSH (`ssh git@host keys add`), through
+//! [`add_key`], [`remove_key`] and [`save_user_keys`]. Keys are named by their
+//! `SHA256:` [`fingerprint`], as `ssh-keygen -l` prints it.
 
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
 use crate::errors::Error;
 use crate::types::User;
@@ -123,6 +130,204 @@ pub fn compile(users_dir: &Path, shell: &str) -> Result<String, Error> {
     compile_authorized_keys(&entries, shell)
 }
 
+/// The keys in one user's key file, as `(fingerprint, line)` pairs.
+pub fn keys_in(contents: &str) -> Result<Vec<(String, &str)>, Error> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| Ok((fingerprint(l)?, l)))
+        .collect()
+}
+
+/// Check every line of one user's key file, returning how many keys it holds
+/// and a `(line number, message)` pair for each invalid one.
+pub fn check_keys(contents: &str) -> (usize, Vec<(usize, String)>) {
+    let mut keys = 0;
+    let mut invalid = Vec::new();
+    for (i, raw) in contents.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match validate_public_key(line) {
+            Ok(()) => keys += 1,
+            Err(msg) => invalid.push((i + 1, msg)),
+        }
+    }
+    (keys, invalid)
+}
+
+/// Add `key` to the keys of `user` in `entries` (as read by
+/// [`load_user_keys`]), returning its fingerprint. A key some user already
+/// has is refused: sshd would log in as whoever's line comes first. Lines of
+/// the key files that are not keys cannot be that key, and are skipped.
+pub fn add_key(entries: &mut Vec<(User, String)>, user: &User, key: &str) -> Result<String, Error> {
+    let key = key.trim();
+    let fp = fingerprint(key)?;
+    let registered = entries.iter().flat_map(|(_, c)| c.lines()).any(|line| {
+        let line = line.trim();
+        validate_public_key(line).is_ok() && fingerprint(line).is_ok_and(|f| f == fp)
+    });
+    if registered {
+        return Err(Error::KeyExists(fp));
+    }
+    let idx = match entries.binary_search_by(|(u, _)| u.cmp(user)) {
+        Ok(idx) => idx,
+        Err(idx) => {
+            entries.insert(idx, (user.clone(), String::new()));
+            idx
+        }
+    };
+    let contents = &mut entries[idx].1;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(key);
+    contents.push('\n');
+    Ok(fp)
+}
+
+/// Remove the key with `fingerprint` from the keys of `user` in `entries`.
+/// Their last key, and the key they are `connected` with (see
+/// [`connected_key`]), are not removed: either would lock them out. Without
+/// knowing the `connected` key nothing is removed, as it could be that one.
+pub fn remove_key(
+    entries: &mut [(User, String)],
+    user: &User,
+    fingerprint: &str,
+    connected: Option<&str>,
+) -> Result<(), Error> {
+    let unknown = || Error::KeyUnknown(fingerprint.to_string());
+    let contents = entries
+        .iter_mut()
+        .find(|(u, _)| u == user)
+        .map(|(_, c)| c)
+        .ok_or_else(unknown)?;
+    let keys = keys_in(contents)?;
+    if !keys.iter().any(|(f, _)| f == fingerprint) {
+        return Err(unknown());
+    }
+    if keys.len() == 1 {
+        return Err(Error::AccessDenied("cannot remove your last key"));
+    }
+    match connected {
+        None => {
+            return Err(Error::AccessDenied(
+                "cannot tell which key you are connected with (sshd needs `ExposeAuthInfo yes`)",
+            ));
+        }
+        Some(connected) if connected == fingerprint => {
+            return Err(Error::AccessDenied(
+                "cannot remove the key you are connected with",
+            ));
+        }
+        Some(_) => {}
+    }
+    *contents = contents
+        .lines()
+        .filter(|l| self::fingerprint(l.trim()).ok().as_deref() != Some(fingerprint))
+        .map(|l| format!("{l}\n"))
+        .collect();
+    Ok(())
+}
+
+/// The fingerprint of the key a user logged in with, from the file sshd
+/// names in `SSH_USER_AUTH` when `ExposeAuthInfo` is on: the key of its last
+/// `publickey` line.
+pub fn connected_key(auth_info: &str) -> Option<String> {
+    auth_info
+        .lines()
+        .rev()
+        .find_map(|l| l.strip_prefix("publickey "))
+        .and_then(|key| fingerprint(key).ok())
+}
+
+/// Write the key file of `user` in `users_dir` from `entries`, and
+/// regenerate `authorized_keys` from all of them, each replaced atomically.
+/// Every entry is compiled first: an invalid key anywhere leaves both files
+/// as they were.
+pub fn save_user_keys(
+    users_dir: &Path,
+    entries: &[(User, String)],
+    user: &User,
+    authorized_keys: &Path,
+    shell: &str,
+) -> Result<(), Error> {
+    let content = compile_authorized_keys(entries, shell)?;
+    let keys = entries
+        .iter()
+        .find(|(u, _)| u == user)
+        .map(|(_, c)| c.as_str())
+        .unwrap_or_default();
+    write_atomically(&users_dir.join(user.as_str()), keys, 0o644)?;
+    write_authorized_keys(authorized_keys, &content, true)
+}
+
+/// Write `content` to an authorized_keys file atomically with the modes sshd
+/// expects: 0600 on the file and, with `private_dir`, 0700 on the enclosing
+/// directory (`.ssh`), which is created if missing.
+pub fn write_authorized_keys(path: &Path, content: &str, private_dir: bool) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+        if private_dir {
+            fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
+        }
+    }
+    write_atomically(path, content, 0o600)
+}
+
+/// Write to a hidden sibling file (skipped by [`load_user_keys`]), then
+/// rename it over `path`.
+pub(crate) fn write_atomically(path: &Path, content: &str, mode: u32) -> Result<(), Error> {
+    let tmp = write_temp(path, content, mode)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Write `content` to `.<name>.tmp` next to `path`, returning it.
+pub(crate) fn write_temp(path: &Path, content: &str, mode: u32) -> Result<PathBuf, Error> {
+    let tmp = sibling(path, "tmp")?;
+    fs::write(&tmp, content)?;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+    Ok(tmp)
+}
+
+/// `.<name>.<suffix>` next to `path`.
+pub(crate) fn sibling(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::ConfigInvalid(format!("{} has no file name", path.display())))?;
+    let mut sibling = std::ffi::OsString::from(".");
+    sibling.push(name);
+    sibling.push(".");
+    sibling.push(suffix);
+    Ok(path.with_file_name(sibling))
+}
+
+/// The `SHA256:...` fingerprint of a public key line, from `ssh-keygen -l`,
+/// which also refuses key data that does not decode to a key of its type.
+pub fn fingerprint(line: &str) -> Result<String, Error> {
+    validate_public_key(line).map_err(Error::KeyInvalid)?;
+    let mut child = Command::new("ssh-keygen")
+        .args(["-l", "-E", "sha256", "-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| Error::CommandFailed(format!("ssh-keygen: {e}")))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(format!("{line}\n").as_bytes())?;
+    }
+    let out = child.wait_with_output()?;
+    // <bits> SHA256:<hash> <comment> (<type>)
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    match stdout.split_whitespace().nth(1) {
+        Some(fp) if out.status.success() && fp.starts_with("SHA256:") => Ok(fp.to_string()),
+        _ => Err(Error::KeyInvalid("not a valid public key".to_string())),
+    }
+}
+
 /// Validate that `line` is a plausible single SSH public key: a recognised
 /// key type, a base64 blob, and nothing that could break out of the line.
 fn validate_public_key(line: &str) -> Result<(), String> {
@@ -155,10 +360,14 @@ fn is_base64(b: u8) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testdir::TestDir;
 
     const ED25519: &str =
         "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
-    const RSA: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+    const RSA: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQDcouPuT6XBPYnrjkabnvbgbNy0NXURRE1DSASXI8WfEdE2n6cmsbW2qLNJ3Az49J7foDOGqsN4DkNghswyGPek0+CyQUgfvEqFwWr1wkH+hNTY08xN2vlaXuvXvlyY3tuPrdXZI3vESdWOmk4xa0S4nxEXfKTB3Za0yZrTMk5ptw==";
+    const RSA2: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQDGSmPdDvDeIt494BQ8T5xOG+c2wrrU106Uofq3ogvZ1wSC/O69JZRVN2aEMGY028JBK/QPkjz8kwQZC3z2KmK/ELkdCMh247eSQCZ/QJ5AAXzWASW3ODXJDMZs1XYZO2tWvFCExoYT14fs04dxrpz5/mmlcN2inWQDmfhRpuLwWQ==";
+    /// Well-formed, but not a key `ssh-keygen` can read.
+    const UNREADABLE: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAAAAA";
 
     fn user(s: &str) -> User {
         User::from_string(s.to_string()).unwrap()
@@ -234,8 +443,7 @@ mod tests {
 
     #[test]
     fn load_user_keys_reads_sorts_and_skips_hidden() {
-        let dir =
-            std::env::temp_dir().join(format!("gitcontrol-authkeys-test-{}", std::process::id()));
+        let dir = TestDir::new("authkeys");
         let users = dir.join("users");
         fs::create_dir_all(&users).unwrap();
         fs::write(users.join("bob"), format!("{ED25519}\n")).unwrap();
@@ -245,8 +453,82 @@ mod tests {
         let entries = load_user_keys(&users).unwrap();
         let names: Vec<&str> = entries.iter().map(|(u, _)| u.as_str()).collect();
         assert_eq!(names, vec!["alice", "bob"]); // sorted, .gitkeep skipped
+    }
+
+    #[test]
+    fn fingerprints_match_ssh_keygen() {
+        assert_eq!(
+            fingerprint(ED25519).unwrap(),
+            "SHA256:kmYcvdi2GkPeWxB6XLjrZB8JHsy2Hm8luHMFp9GMvqk"
+        );
+        assert_eq!(
+            fingerprint("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIDbG8Ehr2IBj1P4B4/w9DiLw49jc78IqzJ7i6WTdEmtn me@host").unwrap(),
+            "SHA256:DLKDKPWTLm77peltAAQGoeGHcw72eeZ0S3+o2vt7xQQ"
+        );
+        assert_eq!(
+            fingerprint(RSA).unwrap(),
+            "SHA256:59NWXYzwToCuORFdrDuIUuG1jBKAirV21ll3Eo9Kl/c"
+        );
+        assert!(fingerprint("ssh-ed25519 not_base64!!").is_err());
+        // base64, but not an RSA key
+        assert!(matches!(fingerprint(UNREADABLE), Err(Error::KeyInvalid(_))));
+    }
+
+    #[test]
+    fn add_and_remove_keys() {
+        let fp_ed = fingerprint(ED25519).unwrap();
+        let fp_rsa = fingerprint(RSA).unwrap();
+        let mut entries = vec![(user("bob"), format!("# laptop\n{ED25519} bob@laptop\n"))];
+
+        assert!(matches!(
+            add_key(&mut entries, &user("alice"), ED25519),
+            Err(Error::KeyExists(_))
+        ));
+        assert_eq!(add_key(&mut entries, &user("alice"), RSA).unwrap(), fp_rsa);
+        assert_eq!(entries[0].0, user("alice"));
+
+        // the last key stays
+        assert!(remove_key(&mut entries, &user("alice"), &fp_rsa, None).is_err());
+        assert!(remove_key(&mut entries, &user("alice"), &fp_ed, None).is_err());
+
+        add_key(&mut entries, &user("bob"), RSA2).unwrap();
+        let fp_new = fingerprint(RSA2).unwrap();
+        assert!(remove_key(&mut entries, &user("bob"), &fp_new, Some(&fp_new)).is_err());
+        // nor when the connected key is not known
+        assert!(matches!(
+            remove_key(&mut entries, &user("bob"), &fp_ed, None),
+            Err(Error::AccessDenied(_))
+        ));
+        remove_key(&mut entries, &user("bob"), &fp_ed, Some(&fp_new)).unwrap();
+        assert_eq!(entries[1].1, format!("# laptop\n{RSA2}\n"));
+    }
+
+    #[test]
+    fn connected_key_is_the_last_publickey() {
+        let info = format!("publickey {RSA}\npublickey {ED25519}\n");
+        assert_eq!(connected_key(&info), Some(fingerprint(ED25519).unwrap()));
+        assert_eq!(connected_key("password\n"), None);
+    }
+
+    #[test]
+    fn save_user_keys_writes_both_files() {
+        let dir = TestDir::new("savekeys");
+        let users = dir.join("users");
+        fs::create_dir_all(&users).unwrap();
+        let auth = dir.join(".ssh/authorized_keys");
+        // a line of bob's that ssh-keygen cannot read is no reason to refuse
+        let mut entries = vec![(user("bob"), format!("{RSA}\n{UNREADABLE}\n"))];
+        add_key(&mut entries, &user("alice"), ED25519).unwrap();
 
-        fs::remove_dir_all(&dir).unwrap();
+        save_user_keys(&users, &entries, &user("alice"), &auth, DEFAULT_SHELL).unwrap();
+        assert_eq!(
+            fs::read_to_string(users.join("alice")).unwrap(),
+            format!("{ED25519}\n")
+        );
+        assert!(!users.join("bob").exists());
+        assert_eq!(fs::read_to_string(&auth).unwrap().lines().count(), 3);
+        let mode = fs::metadata(&auth).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
     }
 
     #[test]
@@ -0,0 +1,149 @@
+//! `gitcontrol-hook` — the `update` hook enforcing gitcontrol ref rules and
+//! who may rewind or delete refs, and the `post-receive` hook installing the
+//! configuration pushed to the admin repository.
+//!
+//! As `update`, git runs it once per ref being pushed, as `update <ref> <old>
+//! <new>`, in the repository being pushed to. The pushing user and the
+//! repository come from the environment set by `gitcontrol-shell`; a push
+//! that did not come through the shell (e.g. a local admin) is not
+//! restricted. Exiting non-zero rejects the update of that ref.
+//!
+//! As `post-receive` it reads the updated refs from stdin and, for the
+//! `master` branch of `gitcontrol/admin`, installs what was pushed. If that
+//! fails, the branch is reset to what it was, which is still live.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use gitcontrol_shell::adminrepo::{self, ADMIN_BRANCH, ADMIN_REPO};
+use gitcontrol_shell::authkeys;
+use gitcontrol_shell::config::Config;
+use gitcontrol_shell::errors::Error;
+use gitcontrol_shell::hook::{ENV_BASE, ENV_REPO, ENV_USER, classify, is_zero};
+use gitcontrol_shell::policy::decide_ref;
+use gitcontrol_shell::types::{Repo, User};
+
+fn main() {
+    let mut args = env::args();
+    let name = args.next().unwrap_or_default();
+    if Path::new(&name).file_name() == Some("post-receive".as_ref()) {
+        if let Err(e) = post_receive() {
+            eprintln!("gitcontrol-hook: {e}");
+            exit(1);
+        }
+        return;
+    }
+
+    let args: Vec<String> = args.collect();
+    let [refname, old, new] = args.as_slice() else {
+        eprintln!("gitcontrol-hook: usage: update <ref> <old> <new>");
+        exit(2);
+    };
+    if let Err(e) = check(refname, old, new) {
+        eprintln!("gitcontrol-hook: {refname}: {e}");
+        exit(1);
+    }
+}
+
+/// The pushing user, the repository and the base directory, or `None` for a
+/// push that did not come through the shell.
+fn pusher() -> Result<Option<(User, Repo, PathBuf)>, Error> {
+    let Ok(user) = env::var(ENV_USER) else {
+        return Ok(None);
+    };
+    let user = User::from_string(user)?;
+    let repo = Repo::from_string(env::var(ENV_REPO)?)?;
+    let base = PathBuf::from(env::var(ENV_BASE)?);
+    Ok(Some((user, repo, base)))
+}
+
+fn check(refname: &str, old: &str, new: &str) -> Result<(), Error> {
+    let Some((user, repo, base)) = pusher()? else {
+        return Ok(());
+    };
+
+    let cfg = Config::load(&base.join("gitcontrol.cfg"))?;
+    let update = classify(old, new)?;
+    decide_ref(&cfg.user_db(&user), &repo, refname, update).into_result()?;
+
+    if adminrepo::is_admin_repo(&repo) && refname == ADMIN_BRANCH {
+        if is_zero(new) {
+            return Err(Error::AccessDenied("the admin branch cannot be deleted"));
+        }
+        staged(&base, new, |stage| {
+            adminrepo::validate(stage, &user, &shell_path()).map(|_| ())
+        })?;
+    }
+    Ok(())
+}
+
+fn post_receive() -> Result<(), Error> {
+    let Some((user, repo, base)) = pusher()? else {
+        return Ok(());
+    };
+    if !adminrepo::is_admin_repo(&repo) {
+        return Ok(());
+    }
+    let mut pushed = None;
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if let [old, new, refname] = line.split(' ').collect::<Vec<_>>()[..]
+            && refname == ADMIN_BRANCH
+        {
+            pushed = Some((old.to_string(), new.to_string()));
+        }
+    }
+    let Some((old, new)) = pushed else {
+        return Ok(());
+    };
+    let installed = staged(&base, &new, |stage| {
+        let keys = adminrepo::validate(stage, &user, &shell_path())?;
+        adminrepo::install(stage, &base, &keys, &user, &new)
+    });
+    if let Err(e) = installed {
+        // the live configuration is as it was; the branch must say so too
+        eprintln!(
+            "gitcontrol-hook: {ADMIN_REPO} {} was not installed: {e}",
+            short(&new)
+        );
+        adminrepo::reset(Path::new("."), &old, &new)?;
+        eprintln!(
+            "gitcontrol-hook: {ADMIN_BRANCH} reset to {}, the live configuration",
+            short(&old)
+        );
+        exit(1);
+    }
+    eprintln!("gitcontrol-hook: installed {ADMIN_REPO} {}", short(&new));
+    Ok(())
+}
+
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(12)]
+}
+
+/// Export `commit` to a staging directory inside `base` and run `f` on it,
+/// removing the directory afterwards.
+fn staged<F>(base: &Path, commit: &str, f: F) -> Result<(), Error>
+where
+    F: FnOnce(&Path) -> Result<(), Error>,
+{
+    let stage = base.join(format!(".gitcontrol-admin.{}", std::process::id()));
+    // git runs hooks in the repository
+    let result = adminrepo::export(Path::new("."), commit, &stage).and_then(|()| f(&stage));
+    let _ = fs::remove_dir_all(&stage);
+    result
+}
+
+/// `gitcontrol-shell` installed alongside this hook, or the default one, to
+/// force on every key.
+fn shell_path() -> String {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("gitcontrol-shell")))
+        .filter(|shell| shell.is_file())
+        .and_then(|shell| shell.to_str().map(str::to_string))
+        .unwrap_or_else(|| authkeys::DEFAULT_SHELL.to_string())
+}
@@ -4,18 +4,31 @@
 //! repository under the base directory, grants the requested user access to it
 //! in `gitcontrol.cfg`, and sets ownership of the created files to the owner
 //! user/group (`git:git` by default) so the `gitcontrol-shell` can serve them.
+//! It also installs `gitcontrol-hook` as the repository's `update` hook, which
+//! enforces ref rules and the force-push and delete permissions on push.
 
 use std::env;
-use std::ffi::OsStr;
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::{Command, exit};
 
+use gitcontrol_shell::admin::{self, chown, config_path, flag_value};
+use gitcontrol_shell::adminrepo::{self, ADMIN_REPO};
+use gitcontrol_shell::apply;
+use gitcontrol_shell::audit;
 use gitcontrol_shell::authkeys;
+use gitcontrol_shell::check;
 use gitcontrol_shell::config::Config;
+use gitcontrol_shell::diff;
 use gitcontrol_shell::errors::Error;
-use gitcontrol_shell::types::{Permission, Repo, User};
+use gitcontrol_shell::history;
+use gitcontrol_shell::hook;
+use gitcontrol_shell::lock;
+use gitcontrol_shell::policy::Operation;
+use gitcontrol_shell::repos::{self, repos_on_disk};
+use gitcontrol_shell::state::State;
+use gitcontrol_shell::types::{Repo, User};
 
 const USAGE: &str = "\
 gitcontrol - administer the gitcontrol user database and repositories
@@ -30,24 +43,118 @@ Options:
                           GITCONTROL_HOME environment variable.
     --owner <user:group>  ownership applied to created files (default: git:git)
     --no-chown            do not change ownership of created files
+    --dry-run             change nothing: print the changes a command would
+                          make to the config, and the diff of each file
+    --hook <path>         hook installed in created repositories
+                          (default: /usr/bin/gitcontrol-hook)
     -h, --help            show this help
 
 Commands:
-    repo create <user> <dir/repo> [-r|-w]   create a bare repo and grant access
-    repo grant  <user> <dir/repo> [-r|-w]   grant/update access (no repo created)
-    repo revoke <user> <dir/repo>           remove access to a repo
+    repo create <who> <dir/repo> [<perm>]   create a bare repo and grant access
+    repo grant  <who> <dir/repo> [<perm>|--deny]
+                                            grant/update access (no repo created)
+    repo grant  <who> <dir/repo> --ref <refs/...> [<perm>|--deny]
+                                            allow/deny pushing to matching refs
+    repo grant  <who> <dir/*> --create      allow creating matching repos by
+                                            pushing to them
+    repo revoke <who> <dir/repo> [--ref <refs/...>|--create]
+                                            remove access, a ref rule, or a
+                                            create permission
+    repo owner  <user> <dir/repo|dir/*>     make a user the owner of a repo or
+                                            of a whole directory
+    repo disown <dir/repo|dir/*>            remove the owner
     repo list                               list every grant, grouped by user
+                                            and group, with the repositories
+                                            each pattern grant covers
+    install-hooks                           (re)install the update and
+                                            post-receive hooks in every
+                                            repository
+    init-admin-repo <who>                   create gitcontrol/admin, holding
+                                            the live configuration, and grant
+                                            <who> access to it
 
     user add    <user>                      add an empty user entry
     user remove <user>                      remove a user and all their grants
     user list                               list users
     user show   <user>                      show a single user's grants
 
+    group add    <group> [<user>...]        create a group and/or add members
+    group remove <group> [<user>...]        remove members, or the whole group
+                                            (and its grants) if none are given
+    group list                              list groups and their members
+    group show   <group>                    show a group's members and grants
+
+    access <user> <dir/repo>                show which operations (fetch, push,
+                                            archive) a user may perform
+
+    perms <dir/repo|dir/*> list|grant <who> <perm>|revoke <who>
+                                            list or change the grants on one
+                                            repo or directory, as its owners
+                                            do over SSH
+
+    admin add    <who>...                   allow users/groups to run the repo,
+                                            user, group and access commands
+                                            over SSH
+    admin remove <who>...                   take that right away again
+    admin list                              list admins
+
+    apply <state.toml> [--prune] [--shell <path>]
+                                            bring users, keys, groups, repos
+                                            and grants to the state described
+                                            in a file (see below)
+
+    check                                   check the config, the key files in
+                                            users/ and the repositories on
+                                            disk (and their hooks) against
+                                            each other, exiting non-zero on
+                                            any problem
+
+    history [<id>]                          list the recorded changes to the
+                                            config, or show one of them
+    rollback <id>                           restore the config as it was after
+                                            change <id>
+
+    log [--user <user>] [--repo <dir/repo>] [--command <cmd>]
+        [--allowed|--denied] [--since <time>] [--until <time>] [--json]
+                                            query the shell's access log
+
     authorized-keys [--stdout] [--output <path>] [--shell <path>]
                                             compile <base>/users/* into an
                                             authorized_keys file
 
-Permission flags default to write (-w); pass -r for read-only.
+<who> is a user name, or a group name prefixed with `=` (e.g. `=devs`).
+<perm> defaults to write (-w): pushing new commits, but no rewriting history.
+Pass -r for read-only, --force to also allow force-pushes rewinding refs, or
+--delete to also allow deleting refs. --deny denies all access whatever else
+is granted (e.g. `repo grant =devs 'secret/*' --deny`). `grant` and `revoke`
+also take patterns such as `web/*`, `*/docs` or `*/*`, covering every matching
+repository; the most specific grant matching a repository wins.
+
+Once any ref rule applies to a repository, pushing to it is limited to the
+refs matching a rule (the most specific ref pattern wins; a pattern may end in
+`*`), and the rule's permission decides what may be done to them: `--ref
+refs/heads/main` allows fast-forwards, --force and --delete also allow
+rewinding and deleting, and --deny forbids any update. A ref rule is enough to
+push even with only read access to the repository. Ref rules are enforced
+by `gitcontrol-hook`, installed as the update hook by `repo create` and
+`install-hooks`.
+
+Once `init-admin-repo` has run, pushing to the master branch of
+gitcontrol/admin replaces gitcontrol.cfg, gitcontrol.d/, users/ and
+authorized_keys with what was pushed. The push is rejected if the config does
+not parse, a key file is invalid, or the pusher would lose write access to
+gitcontrol/admin. Changes made with this tool afterwards are overwritten by
+the next push.
+
+With --create, pushing to a missing repository matching the pattern (e.g.
+`repo grant =devs 'sandbox/*' --create`) creates it, and records the pusher as
+its owner, with full access to it. `repo create` records the user it creates
+the repository for as its owner in the same way. Owners can run `perms` on
+what they own through the shell (`ssh git@host perms web/site grant bob r`).
+
+Admins run the commands through the shell as the git user, e.g. `ssh
+git@host admin repo create bob web/site`; the shell does not chown what they
+create.
 
 The `authorized-keys` command reads one file per user from <base>/users/ (the
 file name is the user name, each file holds that user's public keys, one per
@@ -55,12 +162,37 @@ line) and writes an authorized_keys forcing `command=\"<shell> <user>\"` on
 every key. It defaults to writing <base>/.ssh/authorized_keys (mode 0600, .ssh
 mode 0700); use --stdout to print instead, --output to choose another file, and
 --shell to override the forced shell path (default /usr/bin/gitcontrol-shell).
+
+Every change to gitcontrol.cfg, its included files and gitcontrol.d/ is
+committed to <base>/.gitcontrol-history.git, with who made it (the SSH user,
+or SUDO_USER or USER) and the command line. Changes made by hand are recorded
+before the next one. `rollback` records the current state first, so it can be
+rolled back in turn; it refuses to restore a config that does not parse.
+
+`apply` reads a TOML file with [users.<user>] tables (optional `keys`, a list
+of public keys), [groups.<group>] tables (`members`) and [repos.\"<dir/repo>\"]
+tables (`owner`, and lists of who gets each of `deny`, `read`, `write`,
+`force` and `delete`). It prints the plan, then creates missing repositories,
+sets grants, owners and members, writes key files and regenerates
+authorized_keys. Each repos table lists every grant on that name or pattern.
+--prune also removes undeclared users, groups, key files, and grants on names
+without a table; repositories on disk are never deleted.
+
+The `log` command reads <base>/gitcontrol.log and its rotated copies (including
+gzip-compressed ones), oldest first. --command takes a git command name or an
+operation (fetch, push, archive). Times are UTC, as YYYY-MM-DD or
+YYYY-MM-DDTHH:MM[:SS]; --since is inclusive and --until exclusive. --json
+prints one JSON object per line.
 ";
 
 struct Options {
     base: Option<PathBuf>,
     owner: String,
     chown: bool,
+    hook: PathBuf,
+    /// The command line, for the history.
+    command: String,
+    dry_run: bool,
 }
 
 fn main() {
@@ -70,6 +202,9 @@ fn main() {
         base: None,
         owner: "git:git".to_string(),
         chown: true,
+        hook: PathBuf::from(hook::DEFAULT_HOOK),
+        command: format!("gitcontrol {}", args.join(" ")),
+        dry_run: false,
     };
 
     // Global options must precede the command.
@@ -91,6 +226,14 @@ fn main() {
                 opts.owner = v.clone();
             }
             "--no-chown" => opts.chown = false,
+            "--dry-run" => opts.dry_run = true,
+            "--hook" => {
+                i += 1;
+                let v = args
+                    .get(i)
+                    .unwrap_or_else(|| usage_exit("--hook requires a path"));
+                opts.hook = PathBuf::from(v);
+            }
             "-h" | "--help" | "help" => {
                 print!("{USAGE}");
                 return;
@@ -100,169 +243,259 @@ fn main() {
         i += 1;
     }
 
-    if let Err(e) = dispatch(&opts, &args[i..]) {
-        eprintln!("gitcontrol: {e}");
-        exit(1);
+    match dispatch(&opts, &args[i..]) {
+        Ok(()) if opts.dry_run => eprintln!("gitcontrol: dry run, nothing was changed"),
+        Ok(()) => {}
+        Err(Error::UsageInvalid(msg)) => usage_exit(&msg),
+        Err(e) => {
+            eprintln!("gitcontrol: {e}");
+            exit(1);
+        }
     }
 }
 
 fn dispatch(opts: &Options, args: &[String]) -> Result<(), Error> {
-    let (cmd, rest) = split_or_usage(args, "missing command (try `gitcontrol --help`)");
+    let (cmd, rest) = admin::split_command(args, "missing command (try `gitcontrol --help`)")?;
     match cmd {
-        "repo" => repo_cmd(opts, rest),
-        "user" => user_cmd(opts, rest),
         "authorized-keys" | "keys" => keys_cmd(opts, rest),
-        other => usage_exit(&format!("unknown command: {other}")),
-    }
-}
-
-fn repo_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
-    let (sub, rest) = split_or_usage(args, "missing repo subcommand (create|grant|revoke|list)");
-    match sub {
-        "create" => {
-            let (user, repo, perm) = parse_user_repo_perm(rest)?;
-            repo_create(opts, &user, &repo, perm)
-        }
-        "grant" => {
-            let (user, repo, perm) = parse_user_repo_perm(rest)?;
-            grant(opts, &user, &repo, perm)
+        "log" => log_cmd(opts, rest),
+        "check" => {
+            if !rest.is_empty() {
+                usage_exit("check takes no arguments");
+            }
+            check_cmd(opts)
         }
-        "revoke" => {
-            let (user, repo) = parse_user_repo(rest)?;
-            revoke(opts, &user, &repo)
+        "install-hooks" => {
+            if !rest.is_empty() {
+                usage_exit("install-hooks takes no arguments");
+            }
+            install_hooks(&admin_options(opts)?)
         }
-        "list" => repo_list(opts),
-        other => usage_exit(&format!("unknown repo subcommand: {other}")),
-    }
-}
-
-fn user_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
-    let (sub, rest) = split_or_usage(args, "missing user subcommand (add|remove|list|show)");
-    match sub {
-        "add" => user_add(opts, &parse_one_user(rest)?),
-        "remove" | "del" => user_remove(opts, &parse_one_user(rest)?),
-        "list" => user_list(opts),
-        "show" => user_show(opts, &parse_one_user(rest)?),
-        other => usage_exit(&format!("unknown user subcommand: {other}")),
+        "history" => match rest {
+            [] => history_cmd(opts, None),
+            [id] => history_cmd(opts, Some(id)),
+            _ => usage_exit("expected at most one <id>"),
+        },
+        "rollback" => match rest {
+            [id] => rollback(&admin_options(opts)?, id),
+            _ => usage_exit("expected <id>"),
+        },
+        "apply" => apply_cmd(&admin_options(opts)?, rest),
+        "init-admin-repo" => match rest {
+            [who] => init_admin_repo(&admin_options(opts)?, who),
+            _ => usage_exit("expected <who>"),
+        },
+        cmd => admin::run(&admin_options(opts)?, cmd, rest),
     }
 }
 
 // --- commands -------------------------------------------------------------
 
-fn repo_create(opts: &Options, user: &User, repo: &Repo, perm: Permission) -> Result<(), Error> {
-    let base = resolve_base(opts)?;
-    let repo_path = repo.to_path(&base);
-    if repo_path.exists() {
-        return Err(Error::RepoExists(repo.to_string()));
+fn install_hooks(opts: &admin::Options) -> Result<(), Error> {
+    let cfg = Config::load(&config_path(&opts.base))?;
+    for repo in repos_on_disk(&opts.base, cfg.personal_dir())? {
+        if opts.dry_run {
+            println!("would install {} in {repo}", opts.hook.display());
+            continue;
+        }
+        hook::install(
+            &repos::repo_path(&opts.base, cfg.personal_dir(), &repo),
+            &opts.hook,
+        )?;
+        println!("installed {} in {repo}", opts.hook.display());
     }
+    Ok(())
+}
 
-    // Ensure the containing directory (base/<dir>) exists.
-    if let Some(parent) = repo_path.parent() {
-        fs::create_dir_all(parent)?;
+fn init_admin_repo(opts: &admin::Options, who: &str) -> Result<(), Error> {
+    let args = ["create", who, ADMIN_REPO].map(str::to_string);
+    admin::run(opts, "repo", &args)?;
+    if opts.dry_run {
+        println!("would import the live configuration into {ADMIN_REPO}");
+        return Ok(());
     }
 
-    run_command(
-        "git",
-        &[
-            OsStr::new("init"),
-            OsStr::new("--bare"),
-            repo_path.as_os_str(),
-        ],
-    )?;
-
+    let cfg = Config::load(&config_path(&opts.base))?;
+    let repo = Repo::from_string(ADMIN_REPO.to_string())?;
+    let repo_path = repos::repo_path(&opts.base, cfg.personal_dir(), &repo);
+    let commit = adminrepo::import(&repo_path, &opts.base)?;
     if opts.chown {
         chown(opts, true, &repo_path)?;
     }
-
-    edit_config(opts, &base, |cfg| {
-        cfg.set_permission(user, repo.clone(), perm);
-        Ok(())
-    })?;
-
-    println!("created {repo} and granted {user} {perm} access");
+    println!("imported the live configuration as {commit}");
     Ok(())
 }
 
-fn grant(opts: &Options, user: &User, repo: &Repo, perm: Permission) -> Result<(), Error> {
+fn history_cmd(opts: &Options, id: Option<&String>) -> Result<(), Error> {
     let base = resolve_base(opts)?;
-    edit_config(opts, &base, |cfg| {
-        cfg.set_permission(user, repo.clone(), perm);
-        Ok(())
-    })?;
-    println!("granted {user} {perm} access to {repo}");
+    if let Some(id) = id {
+        println!("{}", history::show(&base, id)?);
+        return Ok(());
+    }
+    for entry in history::entries(&base)? {
+        println!(
+            "{}  {}  {}  {}",
+            &entry.id[..12],
+            entry.time,
+            entry.operator,
+            entry.command
+        );
+    }
     Ok(())
 }
 
-fn revoke(opts: &Options, user: &User, repo: &Repo) -> Result<(), Error> {
-    let base = resolve_base(opts)?;
-    let mut removed = false;
-    edit_config(opts, &base, |cfg| {
-        removed = cfg.revoke(user, repo);
-        Ok(())
+fn rollback(opts: &admin::Options, id: &str) -> Result<(), Error> {
+    let base = &opts.base;
+    if opts.dry_run {
+        print!("{}", history::preview(base, id)?);
+        return Ok(());
+    }
+    let _lock = lock::lock(&config_path(base))?;
+    let restored = history::recorded(base, &opts.operator, &opts.command, || {
+        history::restore(base, id)
     })?;
-    if removed {
-        println!("revoked {user} access to {repo}");
-    } else {
-        println!("{user} had no access to {repo}");
+    for name in &restored {
+        let path = base.join(name);
+        if !path.exists() {
+            println!("removed {name}");
+            continue;
+        }
+        if opts.chown {
+            chown(opts, false, &path)?;
+        }
+        println!("restored {name}");
+    }
+    if opts.chown {
+        chown(opts, true, &history::history_path(base))?;
+    }
+    match restored.is_empty() {
+        true => println!("the configuration is already as it was at {id}"),
+        false => println!("rolled back to {id}"),
     }
     Ok(())
 }
 
-fn repo_list(opts: &Options) -> Result<(), Error> {
-    let base = resolve_base(opts)?;
-    let cfg = Config::load(&config_path(&base))?;
-    for user in cfg.users() {
-        println!("@{user}");
-        if let Some(repos) = cfg.permissions_of(user) {
-            for (repo, perm) in repos {
-                println!("  {} {repo}", perm.to_char());
+fn apply_cmd(opts: &admin::Options, args: &[String]) -> Result<(), Error> {
+    let mut file: Option<PathBuf> = None;
+    let mut prune = false;
+    let mut shell = authkeys::DEFAULT_SHELL.to_string();
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--prune" => prune = true,
+            "--shell" => shell = flag_value(args, &mut i, "a path")?,
+            other if other.starts_with('-') || file.is_some() => {
+                usage_exit(&format!("unexpected argument: {other}"))
             }
+            other => file = Some(PathBuf::from(other)),
         }
+        i += 1;
     }
-    Ok(())
-}
+    let file = file.unwrap_or_else(|| usage_exit("expected <state.toml>"));
+    let state = State::read(&file)?;
 
-fn user_add(opts: &Options, user: &User) -> Result<(), Error> {
-    let base = resolve_base(opts)?;
-    edit_config(opts, &base, |cfg| cfg.add_user(user.clone()))?;
-    println!("added user {user}");
-    Ok(())
+    let base = &opts.base;
+    if opts.dry_run {
+        let plan = apply::plan(base, &state, prune, &shell)?;
+        print!("{plan}{}", plan.diff(base));
+        return Ok(());
+    }
+    let _config_lock = lock::lock(&config_path(base))?;
+    let _keys_lock = lock::lock(&base.join(".ssh").join("authorized_keys"))?;
+    let plan = apply::plan(base, &state, prune, &shell)?;
+    if plan.is_empty() {
+        println!("nothing to change");
+        return Ok(());
+    }
+    print!("{plan}");
+    apply::execute(opts, &plan)
 }
 
-fn user_remove(opts: &Options, user: &User) -> Result<(), Error> {
-    let base = resolve_base(opts)?;
-    edit_config(opts, &base, |cfg| {
-        if cfg.remove_user(user) {
-            Ok(())
-        } else {
-            Err(Error::UserUnknown(user.as_str().to_string()))
+fn check_cmd(opts: &Options) -> Result<(), Error> {
+    let report = check::check(&resolve_base(opts)?, &opts.hook);
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    let summary = format!(
+        "{} users, {} repositories, {} key files",
+        report.users, report.repos, report.key_files
+    );
+    match report.problems.len() {
+        0 => println!("ok: {summary}"),
+        n => {
+            eprintln!(
+                "gitcontrol: {n} problem{} found ({summary})",
+                if n == 1 { "" } else { "s" }
+            );
+            exit(1);
         }
-    })?;
-    println!("removed user {user}");
+    }
     Ok(())
 }
 
-fn user_list(opts: &Options) -> Result<(), Error> {
-    let base = resolve_base(opts)?;
-    let cfg = Config::load(&config_path(&base))?;
-    for user in cfg.users() {
-        println!("{user}");
+fn log_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
+    let mut filter = audit::Filter::default();
+    let mut json = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        let mut value = |what: &str| flag_value(args, &mut i, what);
+        match flag {
+            "--user" => filter.user = Some(User::from_string(value("a user")?)?.to_string()),
+            "--repo" => filter.repo = Some(Repo::from_string(value("a repository")?)?.to_string()),
+            "--command" => {
+                let v = value("a command")?;
+                filter.command = Some(match Operation::from_name(&v) {
+                    Some(op) => op.git_command().to_string(),
+                    None => v,
+                });
+            }
+            "--allowed" => filter.allowed = Some(true),
+            "--denied" => filter.allowed = Some(false),
+            "--since" => filter.since = Some(audit::Timestamp::parse(&value("a time")?)?),
+            "--until" => filter.until = Some(audit::Timestamp::parse(&value("a time")?)?),
+            "--json" => json = true,
+            other => usage_exit(&format!("unexpected argument: {other}")),
+        }
+        i += 1;
     }
-    Ok(())
-}
 
-fn user_show(opts: &Options, user: &User) -> Result<(), Error> {
     let base = resolve_base(opts)?;
-    let cfg = Config::load(&config_path(&base))?;
-    match cfg.permissions_of(user) {
-        None => Err(Error::UserUnknown(user.as_str().to_string())),
-        Some(repos) => {
-            for (repo, perm) in repos {
-                println!("{} {repo}", perm.to_char());
+    for path in audit::log_files(&base)? {
+        let contents = audit::read_log_file(&path)?;
+        for (n, line) in contents.lines().enumerate() {
+            let entry = match audit::Entry::parse(line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("gitcontrol: {}:{}: {e}", path.display(), n + 1);
+                    continue;
+                }
+            };
+            if !filter.matches(&entry) {
+                continue;
+            }
+            if json {
+                println!("{}", entry.to_json());
+            } else {
+                println!(
+                    "{} {:7} {} {} {} from {}{}",
+                    entry.timestamp,
+                    if entry.allowed { "allowed" } else { "denied" },
+                    entry.user,
+                    entry.command,
+                    entry.repo.as_deref().unwrap_or("-"),
+                    entry.source.as_deref().unwrap_or("-"),
+                    entry
+                        .code
+                        .map(|c| format!(" (code {c})"))
+                        .unwrap_or_default(),
+                );
             }
-            Ok(())
         }
     }
+    Ok(())
 }
 
 fn keys_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
@@ -293,18 +526,38 @@ fn keys_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
         i += 1;
     }
 
-    let base = resolve_base(opts)?;
-    let content = authkeys::compile(&base.join("users"), &shell)?;
-    let keys = content.lines().count();
-
+    let opts = admin_options(opts)?;
+    let base = &opts.base;
     if to_stdout {
-        print!("{content}");
+        print!("{}", authkeys::compile(&base.join("users"), &shell)?);
         return Ok(());
     }
 
     let default_path = output.is_none();
     let path = output.unwrap_or_else(|| base.join(".ssh").join("authorized_keys"));
-    write_authorized_keys(opts, &path, &content, default_path)?;
+    if opts.dry_run {
+        let content = authkeys::compile(&base.join("users"), &shell)?;
+        let current = match fs::read_to_string(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            current => current?,
+        };
+        let name = path.strip_prefix(base).unwrap_or(&path).display();
+        print!(
+            "{}",
+            diff::unified(
+                &format!("a/{name}"),
+                &format!("b/{name}"),
+                &current,
+                &content
+            )
+        );
+        return Ok(());
+    }
+    // the shell's `keys` command edits users/ under the same lock
+    let _lock = lock::lock(&path)?;
+    let content = authkeys::compile(&base.join("users"), &shell)?;
+    let keys = content.lines().count();
+    write_authorized_keys(&opts, &path, &content, default_path)?;
 
     if keys == 0 {
         eprintln!(
@@ -318,36 +571,18 @@ fn keys_cmd(opts: &Options, args: &[String]) -> Result<(), Error> {
 
 // --- helpers --------------------------------------------------------------
 
-/// Write `content` to an authorized_keys file atomically with the modes sshd
-/// expects (0600 on the file; 0700 on the enclosing `.ssh` when we own the
-/// default location). Ownership is fixed to the owner user unless disabled.
+/// Write `content` to an authorized_keys file, see
+/// [`authkeys::write_authorized_keys`]. Ownership is fixed to the owner user
+/// unless disabled.
 fn write_authorized_keys(
-    opts: &Options,
+    opts: &admin::Options,
     path: &Path,
     content: &str,
     default_path: bool,
 ) -> Result<(), Error> {
-    let parent = path.parent();
-    if let Some(parent) = parent {
-        fs::create_dir_all(parent)?;
-        if default_path {
-            fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
-        }
-    }
-
-    let mut tmp_name = path
-        .file_name()
-        .ok_or_else(|| Error::ConfigInvalid("output path has no file name".to_string()))?
-        .to_os_string();
-    tmp_name.push(".tmp");
-    let tmp = path.with_file_name(tmp_name);
-
-    fs::write(&tmp, content)?;
-    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
-    fs::rename(&tmp, path)?;
-
+    authkeys::write_authorized_keys(path, content, default_path)?;
     if opts.chown {
-        if default_path && let Some(parent) = parent {
+        if default_path && let Some(parent) = path.parent() {
             chown(opts, false, parent)?;
         }
         chown(opts, false, path)?;
@@ -355,24 +590,18 @@ fn write_authorized_keys(
     Ok(())
 }
 
-/// Load the config, apply `f`, and write it back (and, if enabled, fix its
-/// ownership). If `f` fails nothing is written.
-fn edit_config<F>(opts: &Options, base: &Path, f: F) -> Result<(), Error>
-where
-    F: FnOnce(&mut Config) -> Result<(), Error>,
-{
-    let cfg_path = config_path(base);
-    let mut cfg = Config::load(&cfg_path)?;
-    f(&mut cfg)?;
-    cfg.save(&cfg_path)?;
-    if opts.chown {
-        chown(opts, false, &cfg_path)?;
-    }
-    Ok(())
-}
-
-fn config_path(base: &Path) -> PathBuf {
-    base.join("gitcontrol.cfg")
+/// The options of the commands shared with the shell, once the base
+/// directory is known.
+fn admin_options(opts: &Options) -> Result<admin::Options, Error> {
+    Ok(admin::Options {
+        base: resolve_base(opts)?,
+        owner: opts.owner.clone(),
+        chown: opts.chown,
+        hook: opts.hook.clone(),
+        operator: history::operator(),
+        command: opts.command.clone(),
+        dry_run: opts.dry_run,
+    })
 }
 
 fn resolve_base(opts: &Options) -> Result<PathBuf, Error> {
@@ -413,74 +642,6 @@ fn home_dir_of(user: &str) -> Option<PathBuf> {
     }
 }
 
-fn chown(opts: &Options, recursive: bool, path: &Path) -> Result<(), Error> {
-    let mut args: Vec<&OsStr> = Vec::new();
-    if recursive {
-        args.push(OsStr::new("-R"));
-    }
-    args.push(OsStr::new(&opts.owner));
-    args.push(path.as_os_str());
-    run_command("chown", &args)
-}
-
-fn run_command(program: &str, args: &[&OsStr]) -> Result<(), Error> {
-    let status = Command::new(program)
-        .args(args)
-        .status()
-        .map_err(|e| Error::CommandFailed(format!("{program}: {e}")))?;
-    if status.success() {
-        Ok(())
-    } else {
-        Err(Error::CommandFailed(format!(
-            "{program} exited with {status}"
-        )))
-    }
-}
-
-// --- argument parsing -----------------------------------------------------
-
-fn split_or_usage<'a>(args: &'a [String], msg: &str) -> (&'a str, &'a [String]) {
-    match args.split_first() {
-        Some((first, rest)) => (first.as_str(), rest),
-        None => usage_exit(msg),
-    }
-}
-
-fn parse_user_repo_perm(args: &[String]) -> Result<(User, Repo, Permission), Error> {
-    let mut positional: Vec<&str> = Vec::new();
-    let mut perm = Permission::Write;
-    for a in args {
-        match a.as_str() {
-            "-r" | "--read" => perm = Permission::Read,
-            "-w" | "--write" => perm = Permission::Write,
-            s if s.starts_with('-') => usage_exit(&format!("unknown flag: {s}")),
-            s => positional.push(s),
-        }
-    }
-    if positional.len() != 2 {
-        usage_exit("expected <user> <dir/repo>");
-    }
-    let user = User::from_string(positional[0].to_string())?;
-    let repo = Repo::from_string(positional[1].to_string())?;
-    Ok((user, repo, perm))
-}
-
-fn parse_user_repo(args: &[String]) -> Result<(User, Repo), Error> {
-    if args.len() != 2 {
-        usage_exit("expected <user> <dir/repo>");
-    }
-    let user = User::from_string(args[0].clone())?;
-    let repo = Repo::from_string(args[1].clone())?;
-    Ok((user, repo))
-}
-
-fn parse_one_user(args: &[String]) -> Result<User, Error> {
-    if args.len() != 1 {
-        usage_exit("expected a single <user>");
-    }
-    User::from_string(args[0].clone())
-}
-
 fn usage_exit(msg: &str) -> ! {
     eprintln!("gitcontrol: {msg}\n");
     eprint!("{USAGE}");
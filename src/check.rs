@@ -0,0 +1,246 @@
+//! `gitcontrol check`: read-only consistency checks of a base directory, for
+//! CI and before deploying a configuration.
+//!
+//! It finds what would otherwise only show up when someone's clone fails:
+//! a `gitcontrol.cfg` that does not parse, a repository granted to someone
+//! but missing on disk, an invalid key in `users/`, a user with a section but
+//! no keys, a key file with no section, and a repository without the hooks
+//! enforcing ref rules and the force-push permission.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+use crate::admin::config_path;
+use crate::authkeys;
+use crate::config::Config;
+use crate::errors::Error;
+use crate::hook;
+use crate::repos::{is_bare_repo, repo_path, repos_on_disk};
+use crate::types::{Permission, Repo, Subject, User};
+
+const CONFIG: &str = "gitcontrol.cfg";
+const USERS: &str = "users";
+
+/// One thing wrong, and where: a file (with the line in the message when
+/// there is one), or a repository. Config errors say where they are
+/// themselves, as they may be in an included file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub location: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{location}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// What [`check`] looked at, for its summary.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub users: usize,
+    pub repos: usize,
+    pub key_files: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Check the configuration, key files and repositories under `base`, whose
+/// hooks should link to `hook`.
+pub fn check(base: &Path, hook: &Path) -> Report {
+    let mut report = Report::default();
+    let problem = |location: &str, message: String| Problem {
+        location: Some(location.to_string()),
+        message,
+    };
+
+    let cfg = match Config::read(&config_path(base)) {
+        Ok(cfg) => Some(cfg),
+        Err(e @ Error::ConfigInvalid(_)) => {
+            report.problems.push(Problem {
+                location: None,
+                message: e.to_string(),
+            });
+            None
+        }
+        Err(e) => {
+            report.problems.push(problem(CONFIG, e.to_string()));
+            None
+        }
+    };
+
+    let mut keyed = BTreeSet::new();
+    match authkeys::load_user_keys(&base.join(USERS)) {
+        Ok(entries) => {
+            report.key_files = entries.len();
+            for (user, contents) in entries {
+                let location = format!("{USERS}/{user}");
+                let (keys, invalid) = authkeys::check_keys(&contents);
+                for (line, msg) in invalid {
+                    report
+                        .problems
+                        .push(problem(&location, format!("line {line}: {msg}")));
+                }
+                if keys > 0 {
+                    keyed.insert(user.clone());
+                }
+                if let Some(cfg) = &cfg
+                    && !cfg.has_user(&user)
+                {
+                    report.problems.push(problem(
+                        &location,
+                        format!("no @{user} section in {CONFIG}"),
+                    ));
+                }
+            }
+        }
+        Err(e) => report.problems.push(problem(USERS, e.to_string())),
+    }
+
+    let Some(cfg) = cfg else {
+        return report;
+    };
+
+    let users = config_users(&cfg);
+    report.users = users.len();
+    for user in users {
+        if !keyed.contains(user) {
+            report.problems.push(problem(
+                CONFIG,
+                format!("{user} has no keys in {USERS}/{user}"),
+            ));
+        }
+    }
+
+    let granted = granted_repos(&cfg);
+    report.repos = granted.len();
+    for repo in granted {
+        let path = repo_path(base, cfg.personal_dir(), repo);
+        if !is_bare_repo(&path) {
+            report.problems.push(problem(
+                &repo.to_string(),
+                format!("granted but no repository at {}", path.display()),
+            ));
+        }
+    }
+
+    match repos_on_disk(base, cfg.personal_dir()) {
+        Ok(on_disk) => {
+            for repo in on_disk {
+                let path = repo_path(base, cfg.personal_dir(), &repo);
+                if let Some(message) = hook::problem(&path, hook) {
+                    report.problems.push(problem(
+                        &repo.to_string(),
+                        format!("{message}; run `gitcontrol install-hooks`"),
+                    ));
+                }
+            }
+        }
+        Err(e) => report.problems.push(problem(".", e.to_string())),
+    }
+
+    report
+}
+
+/// Every user named in the config: with a section, in a group, or as an
+/// admin.
+fn config_users(cfg: &Config) -> BTreeSet<&User> {
+    let members = cfg.groups().flat_map(|g| cfg.members_of(g).unwrap_or(&[]));
+    let admins = cfg.admins().iter().filter_map(|s| match s {
+        Subject::User(user) => Some(user),
+        Subject::Group(_) => None,
+    });
+    cfg.users().chain(members).chain(admins).collect()
+}
+
+/// Every repository (not pattern) a user or group is granted access to, or
+/// a user owns. Denying access to a missing repository is fine.
+fn granted_repos(cfg: &Config) -> BTreeSet<&Repo> {
+    fn allowed<'a>((repo, p): (&'a Repo, &Permission)) -> Option<&'a Repo> {
+        (*p != Permission::Deny).then_some(repo)
+    }
+
+    let mut repos = BTreeSet::new();
+    for user in cfg.users() {
+        repos.extend(
+            cfg.permissions_of(user)
+                .into_iter()
+                .flatten()
+                .filter_map(allowed),
+        );
+        repos.extend(cfg.owned_by(user));
+    }
+    for group in cfg.groups() {
+        repos.extend(
+            cfg.group_permissions_of(group)
+                .into_iter()
+                .flatten()
+                .filter_map(allowed),
+        );
+    }
+    repos.retain(|repo| !repo.is_pattern());
+    repos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs;
+
+    const ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+    fn messages(report: &Report) -> Vec<String> {
+        report.problems.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_missing_repos_keys_and_sections() {
+        let base = TestDir::new("check");
+        fs::create_dir_all(base.join("users")).unwrap();
+        fs::create_dir_all(base.join("web/site/objects")).unwrap();
+        fs::write(base.join("web/site/HEAD"), "ref: refs/heads/master\n").unwrap();
+        fs::write(
+            config_path(&base),
+            "=devs alice carol\nw web/site\nw web/app\n- secret/x\n\n@alice\nr web/*\n\n@bob\nr web/site\n",
+        )
+        .unwrap();
+        fs::write(base.join("users/alice"), format!("{ED25519}\n")).unwrap();
+        fs::write(
+            base.join("users/bob"),
+            format!("# bob\n{ED25519}\nssh-rsa !!\n"),
+        )
+        .unwrap();
+        fs::write(base.join("users/dave"), format!("{ED25519}\n")).unwrap();
+
+        let hook = Path::new("/usr/bin/gitcontrol-hook");
+        let report = check(&base, hook);
+        assert_eq!(
+            messages(&report),
+            [
+                "users/bob: line 3: key data is not valid base64",
+                "users/dave: no @dave section in gitcontrol.cfg",
+                "gitcontrol.cfg: carol has no keys in users/carol",
+                &format!(
+                    "web/app: granted but no repository at {}",
+                    base.join("web/app").display()
+                ),
+                "web/site: hooks/update is missing; run `gitcontrol install-hooks`",
+            ]
+        );
+        crate::hook::install(&base.join("web/site"), hook).unwrap();
+        assert_eq!(check(&base, hook).problems.len(), 4);
+        assert_eq!((report.users, report.repos, report.key_files), (3, 2, 3));
+
+        fs::write(config_path(&base), "@alice\nr web/*\nw web\n").unwrap();
+        assert_eq!(
+            messages(&check(&base, hook))[0],
+            "invalid config: gitcontrol.cfg: line 3: Repo Invalid \"web\": not enough /"
+        );
+    }
+}
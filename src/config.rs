@@ -7,93 +7,592 @@
 //! w dir/repo
 //! r dir/repo
 //! # comment
+//!
+//! =group user user2
+//! r dir/repo
 //! ```
 //!
-//! Unlike [`crate::userdb::read_db`], which reads permissions for a single
-//! user at request time, [`Config`] holds the whole database so the admin
-//! tool can add/remove users and grant/revoke permissions and write it back.
+//! Repositories may also be given as patterns (`dir/*`, `*/repo`, `*/*`)
+//! covering every matching repository, existing or future. A `-` line
+//! (`- dir/*`) denies access outright, overriding any read or write grant.
+//!
+//! Beyond `r` and `w`, `+` (also spelt `w+`) allows rewinding refs with
+//! force-pushes, and `d` deleting them as well.
+//!
+//! A third field restricts a grant to some refs: `w dir/repo
+//! refs/heads/main` allows fast-forward pushes to `main`, `d dir/repo
+//! refs/heads/feature/*` also allows rewinding and deleting matching refs, and
+//! `- dir/repo refs/tags/*` forbids any update. These ref rules, and the
+//! difference between `w`, `+` and `d`, are enforced by `gitcontrol-hook`.
+//!
+//! A `C` line lets a user or group create repositories matching a pattern
+//! (`C sandbox/*`) by pushing to them; `gitcontrol-shell` then records the
+//! creator with an `owner dir/repo` line in their section, as `gitcontrol repo
+//! create` does for the user it creates a repository for. Owning a repository
+//! (or, with `owner dir/*`, a whole directory) gives full (`d`) access to it,
+//! and lets the owner manage who else has access with `perms`.
+//!
+//! A `~user` directory (`~alice/scratch`) is the personal namespace of that
+//! user, who has full access to it without any grant. A `personal <dir>` line
+//! before any section sets where personal namespaces live on disk.
 //!
-//! Rewriting is canonical: user sections are kept in their original order,
-//! each user's repositories are sorted, and comments are not preserved.
+//! An `admin` line before any section (`admin alice =ops`) names the users and
+//! groups allowed to run the admin commands over SSH (`ssh git@host admin repo
+//! create ...`).
+//!
+//! A `=group` line defines a group and its members; the permission lines that
+//! follow it are granted to every member. Repeating a `=group` line adds more
+//! members to the same group.
+//!
+//! [`Config`] holds the whole database so the admin tool can add/remove users
+//! and grant/revoke permissions and write it back. The shell reads it too,
+//! through [`crate::userdb::read_db`], so both accept exactly the same lines.
+//!
+//! Rewriting is lossless: comments, blank lines, indentation and the order of
+//! sections and lines are kept, and only the lines an edit touches change, so
+//! `git diff` shows just that edit.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::errors::Error;
-use crate::types::{Permission, Repo, User};
+use crate::types::{Group, Permission, RefPattern, Repo, Subject, User};
+use crate::userdb::UserDb;
+
+/// Keyword of the lines allowing to create repositories.
+const CREATE: &str = "C";
+/// Keyword of the lines recording the owner of a repository.
+const OWNER: &str = "owner";
+/// Start of the line setting where personal namespaces live.
+const PERSONAL: &str = "personal ";
+/// Start of the lines naming admins.
+const ADMIN: &str = "admin ";
+/// Start of the lines merging another file in.
+const INCLUDE: &str = "include ";
+/// The directory next to `gitcontrol.cfg` whose `*.cfg` files are merged
+/// after it, in lexical order.
+pub const CONF_DIR: &str = "gitcontrol.d";
+
+#[derive(Clone)]
+struct GroupSection {
+    group: Group,
+    members: Vec<User>,
+    repos: BTreeMap<Repo, Permission>,
+}
 
+/// The section permission lines are currently added to.
+#[derive(Clone, Copy)]
+enum Section {
+    User(usize),
+    Group(usize),
+}
+
+/// What a line of the file holds, for [`Config`]'s `Display` to write it
+/// back.
+#[derive(Clone)]
+enum LineKind {
+    /// Blank lines and comments.
+    Other,
+    Personal,
+    Admin,
+    /// An `include` line, ending any section.
+    Include,
+    /// A `@user` or `=group` line starting (or continuing) a section.
+    Header(Subject),
+    Item(Subject, Item),
+}
+
+/// A line of a section, identified by what it grants: each holds at most one
+/// line per section.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Item {
+    Grant(Repo),
+    Ref(Repo, RefPattern),
+    Create(Repo),
+    Owner(Repo),
+}
+
+#[derive(Clone)]
+struct Line {
+    text: String,
+    kind: LineKind,
+    /// Index of the file it is from in [`Config`]'s `files`.
+    file: usize,
+}
+
+#[derive(Clone, Default)]
 pub struct Config {
     /// User sections in file order. A user may have no repositories.
     entries: Vec<(User, BTreeMap<Repo, Permission>)>,
+    /// Group sections in file order.
+    groups: Vec<GroupSection>,
+    /// Ref rules of every section.
+    refs: BTreeMap<(Subject, Repo, RefPattern), Permission>,
+    /// Patterns (or names) each section may create repositories under.
+    creates: BTreeSet<(Subject, Repo)>,
+    /// The owner of each repository that has one.
+    owners: BTreeMap<Repo, User>,
+    /// Where personal namespaces live, from a `personal <dir>` line.
+    personal_dir: Option<PathBuf>,
+    /// Users and groups named on `admin` lines, in file order.
+    admins: Vec<Subject>,
+    /// The files read, `gitcontrol.cfg` first (an empty path when parsed
+    /// from a string), their lines in the order they were merged, and the
+    /// database as read, so writing it back only changes what was edited.
+    files: Vec<PathBuf>,
+    lines: Vec<Line>,
+    original: Option<Box<Config>>,
+}
+
+/// One difference between two configurations, from [`Config::diff`]. Where
+/// something was set before or after, `None` means it was not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    PersonalDir {
+        old: Option<PathBuf>,
+        new: Option<PathBuf>,
+    },
+    AdminAdded(Subject),
+    AdminRemoved(Subject),
+    UserAdded(User),
+    UserRemoved(User),
+    GroupAdded(Group),
+    GroupRemoved(Group),
+    MemberAdded(Group, User),
+    MemberRemoved(Group, User),
+    Grant {
+        subject: Subject,
+        repo: Repo,
+        old: Option<Permission>,
+        new: Option<Permission>,
+    },
+    RefRule {
+        subject: Subject,
+        repo: Repo,
+        pattern: RefPattern,
+        old: Option<Permission>,
+        new: Option<Permission>,
+    },
+    CreateAdded(Subject, Repo),
+    CreateRemoved(Subject, Repo),
+    Owner {
+        repo: Repo,
+        old: Option<User>,
+        new: Option<User>,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::PersonalDir { old, new } => {
+                let dir = |d: &Option<PathBuf>| match d {
+                    Some(d) => d.display().to_string(),
+                    None => "none".to_string(),
+                };
+                write!(f, "personal dir: {} -> {}", dir(old), dir(new))
+            }
+            Change::AdminAdded(s) => write!(f, "add admin {s}"),
+            Change::AdminRemoved(s) => write!(f, "remove admin {s}"),
+            Change::UserAdded(u) => write!(f, "add user {u}"),
+            Change::UserRemoved(u) => write!(f, "remove user {u}"),
+            Change::GroupAdded(g) => write!(f, "add group {g}"),
+            Change::GroupRemoved(g) => write!(f, "remove group {g}"),
+            Change::MemberAdded(g, u) => write!(f, "add {u} to {g}"),
+            Change::MemberRemoved(g, u) => write!(f, "remove {u} from {g}"),
+            Change::Grant {
+                subject,
+                repo,
+                old,
+                new,
+            } => write!(f, "{subject} {repo}: {}", levels(old, new)),
+            Change::RefRule {
+                subject,
+                repo,
+                pattern,
+                old,
+                new,
+            } => write!(f, "{subject} {repo} {pattern}: {}", levels(old, new)),
+            Change::CreateAdded(s, pattern) => write!(f, "allow {s} to create {pattern}"),
+            Change::CreateRemoved(s, pattern) => write!(f, "stop {s} creating {pattern}"),
+            Change::Owner { repo, old, new } => match (old, new) {
+                (Some(old), Some(new)) => write!(f, "owner of {repo}: {old} -> {new}"),
+                (None, Some(new)) => write!(f, "owner of {repo}: {new}"),
+                (Some(old), None) => write!(f, "owner of {repo}: none (was {old})"),
+                (None, None) => write!(f, "owner of {repo}: none"),
+            },
+        }
+    }
+}
+
+/// `read -> write`, `none -> read`, ...
+fn levels(old: &Option<Permission>, new: &Option<Permission>) -> String {
+    let level = |p: &Option<Permission>| p.map_or("none".to_string(), |p| p.to_string());
+    format!("{} -> {}", level(old), level(new))
+}
+
+/// The keys whose values differ between `old` and `new`, with both values.
+fn changed<K: Ord + Clone, V: PartialEq + Clone>(
+    old: &BTreeMap<K, V>,
+    new: &BTreeMap<K, V>,
+) -> Vec<(K, Option<V>, Option<V>)> {
+    let keys: BTreeSet<&K> = old.keys().chain(new.keys()).collect();
+    keys.into_iter()
+        .filter(|k| old.get(k) != new.get(k))
+        .map(|k| (k.clone(), old.get(k).cloned(), new.get(k).cloned()))
+        .collect()
+}
+
+/// Prefix an error in a config line with where it is.
+fn at(here: &str) -> impl Fn(Error) -> Error + '_ {
+    move |e| match e {
+        Error::ConfigInvalid(_) => e,
+        e => Error::ConfigInvalid(format!("{here}: {e}")),
+    }
 }
 
 impl Config {
+    /// Parse a config given as a string, which cannot `include` files.
     pub fn parse(contents: &str) -> Result<Config, Error> {
-        let mut entries: Vec<(User, BTreeMap<Repo, Permission>)> = Vec::new();
-        let mut current: Option<usize> = None;
+        let mut cfg = Config {
+            files: vec![PathBuf::new()],
+            ..Config::default()
+        };
+        cfg.parse_file(0, contents, None)?;
+        Ok(cfg.snapshot())
+    }
+
+    /// Load the database from `path`, and the files it includes and those in
+    /// [`CONF_DIR`] next to it. A missing file yields an empty database.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        Config::read_files(path, true)
+    }
+
+    /// [`Config::load`], but a missing file is an error.
+    pub fn read(path: &Path) -> Result<Config, Error> {
+        Config::read_files(path, false)
+    }
+
+    fn read_files(path: &Path, missing_ok: bool) -> Result<Config, Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if missing_ok && e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut cfg = Config {
+            files: vec![path.to_path_buf()],
+            ..Config::default()
+        };
+        let mut stack: Vec<PathBuf> = fs::canonicalize(path).into_iter().collect();
+        cfg.parse_file(0, &contents, Some(&mut stack))?;
+
+        let conf_dir = path.with_file_name(CONF_DIR);
+        let mut names = Vec::new();
+        match fs::read_dir(&conf_dir) {
+            Ok(read) => {
+                for entry in read {
+                    let name = entry?.file_name();
+                    let name = name.to_string_lossy();
+                    if !name.starts_with('.') && name.ends_with(".cfg") {
+                        names.push(name.into_owned());
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        names.sort();
+        for name in names {
+            cfg.include(&conf_dir.join(name), &mut stack)?;
+        }
+        Ok(cfg.snapshot())
+    }
+
+    /// Read and parse the file at `path`, given by an `include` line or found
+    /// in [`CONF_DIR`]. `stack` holds the files being read, to catch loops.
+    fn include(&mut self, path: &Path, stack: &mut Vec<PathBuf>) -> Result<(), Error> {
+        let name = self.file_name(path);
+        let canonical =
+            fs::canonicalize(path).map_err(|e| Error::ConfigInvalid(format!("{name}: {e}")))?;
+        if stack.contains(&canonical) {
+            return Err(Error::ConfigInvalid(format!("{name}: includes itself")));
+        }
+        if self
+            .files
+            .iter()
+            .any(|f| fs::canonicalize(f).is_ok_and(|f| f == canonical))
+        {
+            return Err(Error::ConfigInvalid(format!("{name}: included twice")));
+        }
+        let contents = fs::read_to_string(path)?;
+        self.files.push(path.to_path_buf());
+        stack.push(canonical);
+        self.parse_file(self.files.len() - 1, &contents, Some(stack))?;
+        stack.pop();
+        Ok(())
+    }
+
+    /// Where line `lineno` of file `file` is, for errors.
+    fn location(&self, file: usize, lineno: usize) -> String {
+        match self.files[file].as_os_str().is_empty() {
+            true => format!("line {lineno}"),
+            false => format!("{}: line {lineno}", self.file_name(&self.files[file])),
+        }
+    }
+
+    /// The files the configuration was read from: `gitcontrol.cfg` first,
+    /// then included files in the order they were read.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// `path` relative to the directory of `gitcontrol.cfg`, if it is in it.
+    pub fn file_name(&self, path: &Path) -> String {
+        let dir = self.files.first().and_then(|f| f.parent());
+        dir.and_then(|dir| path.strip_prefix(dir).ok())
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    /// Parse the contents of file number `file`. Without a `stack`, `include`
+    /// lines are refused.
+    fn parse_file(
+        &mut self,
+        file: usize,
+        contents: &str,
+        mut stack: Option<&mut Vec<PathBuf>>,
+    ) -> Result<(), Error> {
+        let mut current: Option<Section> = None;
 
         for (i, raw) in contents.lines().enumerate() {
-            let lineno = i + 1;
+            let here = self.location(file, i + 1);
             let line = raw.trim();
+            let lines = &mut self.lines;
+            let mut push = |kind| {
+                lines.push(Line {
+                    text: raw.to_string(),
+                    kind,
+                    file,
+                })
+            };
             if line.is_empty() || line.starts_with('#') {
+                push(LineKind::Other);
+                continue;
+            }
+
+            if let Some(dir) = line.strip_prefix(PERSONAL) {
+                if current.is_some() {
+                    return Err(Error::ConfigInvalid(format!(
+                        "{here}: {} must come before any @user or =group",
+                        PERSONAL.trim()
+                    )));
+                }
+                self.personal_dir = Some(PathBuf::from(dir.trim()));
+                push(LineKind::Personal);
+                continue;
+            }
+
+            if let Some(names) = line.strip_prefix(ADMIN) {
+                if current.is_some() {
+                    return Err(Error::ConfigInvalid(format!(
+                        "{here}: {} must come before any @user or =group",
+                        ADMIN.trim()
+                    )));
+                }
+                for name in names.split_whitespace() {
+                    let subject = Subject::from_string(name.to_string()).map_err(at(&here))?;
+                    if !self.admins.contains(&subject) {
+                        self.admins.push(subject);
+                    }
+                }
+                push(LineKind::Admin);
+                continue;
+            }
+
+            if let Some(target) = line.strip_prefix(INCLUDE) {
+                push(LineKind::Include);
+                let Some(stack) = stack.as_deref_mut() else {
+                    return Err(Error::ConfigInvalid(format!(
+                        "{here}: include only works in a config file"
+                    )));
+                };
+                let dir = self.files[file].parent().unwrap_or(Path::new("."));
+                self.include(&dir.join(target.trim()), stack)?;
+                // the included file's sections end with it
+                current = None;
                 continue;
             }
 
             if let Some(name) = line.strip_prefix('@') {
-                let user = User::from_string(name.trim().to_string())?;
-                current = Some(match entries.iter().position(|(u, _)| u == &user) {
+                let user = User::from_string(name.trim().to_string()).map_err(at(&here))?;
+                push(LineKind::Header(Subject::User(user.clone())));
+                current = Some(Section::User(
+                    match self.entries.iter().position(|(u, _)| u == &user) {
+                        Some(idx) => idx,
+                        None => {
+                            self.entries.push((user, BTreeMap::new()));
+                            self.entries.len() - 1
+                        }
+                    },
+                ));
+                continue;
+            }
+
+            if let Some(rest) = line.strip_prefix('=') {
+                let mut words = rest.split_whitespace();
+                let name = words.next().ok_or_else(|| {
+                    Error::ConfigInvalid(format!("{here}: missing group name after ="))
+                })?;
+                let group = Group::from_string(name.to_string()).map_err(at(&here))?;
+                push(LineKind::Header(Subject::Group(group.clone())));
+                let idx = match self.groups.iter().position(|g| g.group == group) {
                     Some(idx) => idx,
                     None => {
-                        entries.push((user, BTreeMap::new()));
-                        entries.len() - 1
+                        self.groups.push(GroupSection {
+                            group,
+                            members: Vec::new(),
+                            repos: BTreeMap::new(),
+                        });
+                        self.groups.len() - 1
                     }
-                });
+                };
+                for member in words {
+                    let user = User::from_string(member.to_string()).map_err(at(&here))?;
+                    if !self.groups[idx].members.contains(&user) {
+                        self.groups[idx].members.push(user);
+                    }
+                }
+                current = Some(Section::Group(idx));
                 continue;
             }
 
-            let idx = current.ok_or_else(|| {
-                Error::ConfigInvalid(format!("line {lineno}: permission before any @user"))
+            let section = current.ok_or_else(|| {
+                Error::ConfigInvalid(format!("{here}: permission before any @user or =group"))
             })?;
 
-            let mut chars = line.chars();
-            let permission = Permission::from_char(chars.next().unwrap())?;
-            let rest = chars.as_str().strip_prefix(' ').ok_or_else(|| {
-                Error::ConfigInvalid(format!("line {lineno}: expected space after permission"))
-            })?;
-            let repo = Repo::from_string(rest.trim().to_string())?;
-            entries[idx].1.insert(repo, permission);
+            let mut words = line.split_whitespace();
+            let token = words.next().unwrap_or_default();
+            let (Some(target), refname, None) = (words.next(), words.next(), words.next()) else {
+                return Err(Error::ConfigInvalid(format!(
+                    "{here}: expected <permission> <dir/repo> [<ref>]"
+                )));
+            };
+            let subject = match section {
+                Section::User(idx) => Subject::User(self.entries[idx].0.clone()),
+                Section::Group(idx) => Subject::Group(self.groups[idx].group.clone()),
+            };
+
+            if token == OWNER || token == CREATE {
+                if refname.is_some() {
+                    return Err(Error::ConfigInvalid(format!(
+                        "{here}: expected {token} <dir/repo>"
+                    )));
+                }
+                if token == CREATE {
+                    let pattern = Repo::from_pattern(target.to_string()).map_err(at(&here))?;
+                    push(LineKind::Item(
+                        subject.clone(),
+                        Item::Create(pattern.clone()),
+                    ));
+                    self.creates.insert((subject, pattern));
+                    continue;
+                }
+                let Subject::User(user) = subject else {
+                    return Err(Error::ConfigInvalid(format!(
+                        "{here}: only users can own repositories"
+                    )));
+                };
+                let repo = Repo::from_ownable(target.to_string()).map_err(at(&here))?;
+                if self.owners.contains_key(&repo) {
+                    return Err(Error::ConfigInvalid(format!(
+                        "{here}: {repo} already has an owner"
+                    )));
+                }
+                push(LineKind::Item(
+                    Subject::User(user.clone()),
+                    Item::Owner(repo.clone()),
+                ));
+                self.owners.insert(repo, user);
+                continue;
+            }
+
+            let repo = Repo::from_pattern(target.to_string()).map_err(at(&here))?;
+            if let Some(refname) = refname {
+                let pattern = RefPattern::from_string(refname.to_string()).map_err(at(&here))?;
+                let permission = Permission::from_ref_token(token).map_err(at(&here))?;
+                push(LineKind::Item(
+                    subject.clone(),
+                    Item::Ref(repo.clone(), pattern.clone()),
+                ));
+                self.refs.insert((subject, repo, pattern), permission);
+                continue;
+            }
+
+            let permission = Permission::from_token(token).map_err(at(&here))?;
+            push(LineKind::Item(subject, Item::Grant(repo.clone())));
+            match section {
+                Section::User(idx) => self.entries[idx].1.insert(repo, permission),
+                Section::Group(idx) => self.groups[idx].repos.insert(repo, permission),
+            };
         }
 
-        Ok(Config { entries })
+        Ok(())
     }
 
-    /// Load the database from `path`. A missing file yields an empty database.
-    pub fn load(path: &Path) -> Result<Config, Error> {
-        match fs::read_to_string(path) {
-            Ok(s) => Config::parse(&s),
-            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config {
-                entries: Vec::new(),
-            }),
-            Err(e) => Err(e.into()),
-        }
-    }
-
-    /// Write the database back to `path` atomically (write to a sibling temp
-    /// file, then rename over the target).
-    pub fn save(&self, path: &Path) -> Result<(), Error> {
-        let name = path
-            .file_name()
-            .ok_or_else(|| Error::ConfigInvalid("config path has no file name".to_string()))?;
-        let mut tmp_name = name.to_os_string();
-        tmp_name.push(".tmp");
-        let tmp = path.with_file_name(tmp_name);
-
-        fs::write(&tmp, self.to_string())?;
-        fs::rename(&tmp, path)?;
-        Ok(())
+    /// Record the database as read, to write back only what changes.
+    fn snapshot(self) -> Config {
+        let original = Config {
+            lines: Vec::new(),
+            ..self.clone()
+        };
+        Config {
+            original: Some(Box::new(original)),
+            ..self
+        }
+    }
+
+    /// Write the database back atomically (each file to a sibling temp file,
+    /// renamed over it), `gitcontrol.cfg` to `path`, returning the files that
+    /// changed. Hold [`lock::lock`](crate::lock::lock) from loading to saving.
+    pub fn save(&self, path: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut written = Vec::new();
+        for (target, _, text) in self.edits(path) {
+            write_file(&target, &text)?;
+            written.push(target);
+        }
+        Ok(written)
+    }
+
+    /// The files [`save`](Config::save) would write, `gitcontrol.cfg` to
+    /// `path`, each with its contents as read and as it would be written.
+    pub fn edits(&self, path: &Path) -> Vec<(PathBuf, String, String)> {
+        let mut edits = Vec::new();
+        for (file, out) in self.render().into_iter().enumerate() {
+            let target = match file {
+                0 => path,
+                _ => &self.files[file],
+            };
+            let unchanged = self
+                .lines
+                .iter()
+                .filter(|l| l.file == file)
+                .map(|l| &l.text)
+                .eq(out.iter());
+            if unchanged && target.exists() {
+                continue;
+            }
+            let old = self
+                .lines
+                .iter()
+                .filter(|l| l.file == file)
+                .map(|l| &l.text);
+            edits.push((target.to_path_buf(), text(old), text(out.iter())));
+        }
+        edits
     }
 
     pub fn has_user(&self, user: &User) -> bool {
@@ -108,10 +607,21 @@ impl Config {
         Ok(())
     }
 
-    /// Remove a user and all their permissions. Returns `false` if unknown.
+    /// Remove a user, all their permissions and their group memberships.
+    /// Returns `false` if unknown.
     pub fn remove_user(&mut self, user: &User) -> bool {
         let before = self.entries.len();
         self.entries.retain(|(u, _)| u != user);
+        for g in self.groups.iter_mut() {
+            g.members.retain(|m| m != user);
+        }
+        self.refs
+            .retain(|(s, _, _), _| !matches!(s, Subject::User(u) if u == user));
+        self.creates
+            .retain(|(s, _)| !matches!(s, Subject::User(u) if u == user));
+        self.owners.retain(|_, owner| owner != user);
+        self.admins
+            .retain(|s| !matches!(s, Subject::User(u) if u == user));
         self.entries.len() != before
     }
 
@@ -151,32 +661,711 @@ impl Config {
             .map(|(_, repos)| repos)
     }
 
+    pub fn has_group(&self, group: &Group) -> bool {
+        self.groups.iter().any(|g| &g.group == group)
+    }
+
+    pub fn add_group(&mut self, group: Group) -> Result<(), Error> {
+        if self.has_group(&group) {
+            return Err(Error::GroupExists(group.as_str().to_string()));
+        }
+        self.groups.push(GroupSection {
+            group,
+            members: Vec::new(),
+            repos: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Remove a group and all its permissions. Returns `false` if unknown.
+    pub fn remove_group(&mut self, group: &Group) -> bool {
+        let before = self.groups.len();
+        self.groups.retain(|g| &g.group != group);
+        self.refs
+            .retain(|(s, _, _), _| !matches!(s, Subject::Group(g) if g == group));
+        self.creates
+            .retain(|(s, _)| !matches!(s, Subject::Group(g) if g == group));
+        self.admins
+            .retain(|s| !matches!(s, Subject::Group(g) if g == group));
+        self.groups.len() != before
+    }
+
+    /// Add a member to an existing group. Returns `false` if they already
+    /// were a member.
+    pub fn add_member(&mut self, group: &Group, user: User) -> Result<bool, Error> {
+        let g = self.group_mut(group)?;
+        if g.members.contains(&user) {
+            return Ok(false);
+        }
+        g.members.push(user);
+        Ok(true)
+    }
+
+    /// Remove a member from an existing group. Returns `false` if they were
+    /// not a member.
+    pub fn remove_member(&mut self, group: &Group, user: &User) -> Result<bool, Error> {
+        let g = self.group_mut(group)?;
+        let before = g.members.len();
+        g.members.retain(|m| m != user);
+        Ok(g.members.len() != before)
+    }
+
+    /// Grant (or update) a permission on a repository for every member of a
+    /// group, creating the group if it does not exist yet.
+    pub fn set_group_permission(&mut self, group: &Group, repo: Repo, permission: Permission) {
+        match self.groups.iter_mut().find(|g| &g.group == group) {
+            Some(g) => {
+                g.repos.insert(repo, permission);
+            }
+            None => {
+                let mut repos = BTreeMap::new();
+                repos.insert(repo, permission);
+                self.groups.push(GroupSection {
+                    group: group.clone(),
+                    members: Vec::new(),
+                    repos,
+                });
+            }
+        }
+    }
+
+    /// Revoke a group's permission. Returns `false` if it had no such grant.
+    pub fn revoke_group(&mut self, group: &Group, repo: &Repo) -> bool {
+        match self.groups.iter_mut().find(|g| &g.group == group) {
+            Some(g) => g.repos.remove(repo).is_some(),
+            None => false,
+        }
+    }
+
+    /// [`Config::set_permission`] or [`Config::set_group_permission`],
+    /// depending on who the grant is for.
+    pub fn set_subject_permission(
+        &mut self,
+        subject: &Subject,
+        repo: Repo,
+        permission: Permission,
+    ) {
+        match subject {
+            Subject::User(u) => self.set_permission(u, repo, permission),
+            Subject::Group(g) => self.set_group_permission(g, repo, permission),
+        }
+    }
+
+    /// [`Config::revoke`] or [`Config::revoke_group`], depending on who the
+    /// grant is for.
+    pub fn revoke_subject(&mut self, subject: &Subject, repo: &Repo) -> bool {
+        match subject {
+            Subject::User(u) => self.revoke(u, repo),
+            Subject::Group(g) => self.revoke_group(g, repo),
+        }
+    }
+
+    /// Add (or update) a ref rule, creating the user or group section if it
+    /// does not exist yet.
+    pub fn set_ref_rule(
+        &mut self,
+        subject: &Subject,
+        repo: Repo,
+        pattern: RefPattern,
+        permission: Permission,
+    ) {
+        self.ensure_section(subject);
+        self.refs
+            .insert((subject.clone(), repo, pattern), permission);
+    }
+
+    fn ensure_section(&mut self, subject: &Subject) {
+        match subject {
+            Subject::User(u) => {
+                if !self.has_user(u) {
+                    self.entries.push((u.clone(), BTreeMap::new()));
+                }
+            }
+            Subject::Group(g) => {
+                if !self.has_group(g) {
+                    self.groups.push(GroupSection {
+                        group: g.clone(),
+                        members: Vec::new(),
+                        repos: BTreeMap::new(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Remove a ref rule. Returns `false` if there was no such rule.
+    pub fn revoke_ref_rule(
+        &mut self,
+        subject: &Subject,
+        repo: &Repo,
+        pattern: &RefPattern,
+    ) -> bool {
+        self.refs
+            .remove(&(subject.clone(), repo.clone(), pattern.clone()))
+            .is_some()
+    }
+
+    /// A user's or group's ref rules, sorted by repository and ref.
+    pub fn ref_rules_of<'a>(
+        &'a self,
+        subject: &'a Subject,
+    ) -> impl Iterator<Item = (&'a Repo, &'a RefPattern, Permission)> {
+        self.refs
+            .iter()
+            .filter(move |((s, _, _), _)| s == subject)
+            .map(|((_, repo, pattern), permission)| (repo, pattern, *permission))
+    }
+
+    /// Allow a user or group to create repositories matching `pattern`,
+    /// creating the section if it does not exist yet.
+    pub fn set_create(&mut self, subject: &Subject, pattern: Repo) {
+        self.ensure_section(subject);
+        self.creates.insert((subject.clone(), pattern));
+    }
+
+    /// Remove a create permission. Returns `false` if there was none.
+    pub fn revoke_create(&mut self, subject: &Subject, pattern: &Repo) -> bool {
+        self.creates.remove(&(subject.clone(), pattern.clone()))
+    }
+
+    /// The patterns a user or group may create repositories under.
+    pub fn creates_of<'a>(&'a self, subject: &'a Subject) -> impl Iterator<Item = &'a Repo> {
+        self.creates
+            .iter()
+            .filter(move |(s, _)| s == subject)
+            .map(|(_, pattern)| pattern)
+    }
+
+    /// Record `user` as the owner of `repo`, replacing any previous owner and
+    /// creating the user section if it does not exist yet.
+    pub fn set_owner(&mut self, repo: Repo, user: &User) {
+        self.ensure_section(&Subject::User(user.clone()));
+        self.owners.insert(repo, user.clone());
+    }
+
+    /// Returns the previous owner, if any.
+    pub fn remove_owner(&mut self, repo: &Repo) -> Option<User> {
+        self.owners.remove(repo)
+    }
+
+    pub fn owner_of(&self, repo: &Repo) -> Option<&User> {
+        self.owners.get(repo)
+    }
+
+    /// Whether `user` owns `repo`: directly, through its directory, or as
+    /// their personal namespace.
+    pub fn owns(&self, user: &User, repo: &Repo) -> bool {
+        repo.personal_owner().as_ref() == Some(user)
+            || [repo.clone(), repo.directory()]
+                .iter()
+                .any(|r| self.owner_of(r) == Some(user))
+    }
+
+    /// The repositories `user` owns.
+    pub fn owned_by<'a>(&'a self, user: &'a User) -> impl Iterator<Item = &'a Repo> {
+        self.owners
+            .iter()
+            .filter(move |(_, owner)| *owner == user)
+            .map(|(repo, _)| repo)
+    }
+
+    /// Where personal namespaces live, if configured; see
+    /// [`crate::repos::repo_path`].
+    pub fn personal_dir(&self) -> Option<&Path> {
+        self.personal_dir.as_deref()
+    }
+
+    /// The users and groups named as admins.
+    pub fn admins(&self) -> &[Subject] {
+        &self.admins
+    }
+
+    /// Name `subject` as an admin. Returns `false` if it already was one.
+    pub fn add_admin(&mut self, subject: Subject) -> bool {
+        if self.admins.contains(&subject) {
+            return false;
+        }
+        self.admins.push(subject);
+        true
+    }
+
+    /// Returns `false` if `subject` was not an admin.
+    pub fn remove_admin(&mut self, subject: &Subject) -> bool {
+        let before = self.admins.len();
+        self.admins.retain(|s| s != subject);
+        self.admins.len() != before
+    }
+
+    /// Whether `user` is an admin, named directly or through a group.
+    pub fn is_admin(&self, user: &User) -> bool {
+        self.admins.iter().any(|s| match s {
+            Subject::User(u) => u == user,
+            Subject::Group(g) => self.members_of(g).is_some_and(|m| m.contains(user)),
+        })
+    }
+
+    /// All groups in file order.
+    pub fn groups(&self) -> impl Iterator<Item = &Group> {
+        self.groups.iter().map(|g| &g.group)
+    }
+
+    /// A group's members, or `None` if the group is unknown.
+    pub fn members_of(&self, group: &Group) -> Option<&[User]> {
+        self.groups
+            .iter()
+            .find(|g| &g.group == group)
+            .map(|g| g.members.as_slice())
+    }
+
+    /// A group's repositories, or `None` if the group is unknown.
+    pub fn group_permissions_of(&self, group: &Group) -> Option<&BTreeMap<Repo, Permission>> {
+        self.groups
+            .iter()
+            .find(|g| &g.group == group)
+            .map(|g| &g.repos)
+    }
+
+    /// The groups `user` is a member of.
+    pub fn groups_of<'a>(&'a self, user: &'a User) -> impl Iterator<Item = &'a Group> {
+        self.groups
+            .iter()
+            .filter(move |g| g.members.contains(user))
+            .map(|g| &g.group)
+    }
+
+    fn group_mut(&mut self, group: &Group) -> Result<&mut GroupSection, Error> {
+        self.groups
+            .iter_mut()
+            .find(|g| &g.group == group)
+            .ok_or_else(|| Error::GroupUnknown(group.as_str().to_string()))
+    }
+
+    /// The effective permissions of `user`, as the shell checks them: their
+    /// own grants and ref rules merged with those of their groups.
+    pub fn user_db(&self, user: &User) -> UserDb {
+        let mut repos = self.permissions_of(user).cloned().unwrap_or_default();
+        for repo in self.owned_by(user) {
+            let p = repos.entry(repo.clone()).or_insert(Permission::Delete);
+            *p = p.combine(Permission::Delete);
+        }
+        let mut refs = Vec::new();
+        let mut creates = Vec::new();
+        let mut subjects = vec![Subject::User(user.clone())];
+        let groups: Vec<Group> = self.groups_of(user).cloned().collect();
+        for group in &groups {
+            for (repo, permission) in self.group_permissions_of(group).into_iter().flatten() {
+                let p = repos.entry(repo.clone()).or_insert(*permission);
+                *p = p.combine(*permission);
+            }
+            subjects.push(Subject::Group(group.clone()));
+        }
+        for subject in &subjects {
+            for (repo, pattern, permission) in self.ref_rules_of(subject) {
+                refs.push((repo.clone(), pattern.clone(), permission));
+            }
+            creates.extend(self.creates_of(subject).cloned());
+        }
+        let known = self.has_user(user) || !groups.is_empty();
+        let mut db = UserDb {
+            repos,
+            refs,
+            creates,
+            groups,
+            personal_dir: self.personal_dir.clone(),
+        };
+        if known {
+            db.add_personal_namespace(user);
+        }
+        db
+    }
+
+    /// Every section's grant on `repo` itself (not on patterns covering it),
+    /// groups first.
+    pub fn grants_on(&self, repo: &Repo) -> Vec<(Subject, Permission)> {
+        let groups = self.groups.iter().filter_map(|g| {
+            let p = g.repos.get(repo)?;
+            Some((Subject::Group(g.group.clone()), *p))
+        });
+        let users = self.entries.iter().filter_map(|(u, repos)| {
+            let p = repos.get(repo)?;
+            Some((Subject::User(u.clone()), *p))
+        });
+        groups.chain(users).collect()
+    }
+
     /// Every `(user, repo, permission)` triple across the database.
     pub fn grants(&self) -> impl Iterator<Item = (&User, &Repo, Permission)> {
         self.entries
             .iter()
             .flat_map(|(u, repos)| repos.iter().map(move |(r, p)| (u, r, *p)))
     }
+
+    /// What changes from this configuration to `other`: the settings, then
+    /// users and groups, then everything granted. Only what the
+    /// configurations say counts, not how the files are laid out.
+    pub fn diff(&self, other: &Config) -> Vec<Change> {
+        let mut changes = Vec::new();
+        if self.personal_dir != other.personal_dir {
+            changes.push(Change::PersonalDir {
+                old: self.personal_dir.clone(),
+                new: other.personal_dir.clone(),
+            });
+        }
+        for s in self.admins.iter().filter(|s| !other.admins.contains(s)) {
+            changes.push(Change::AdminRemoved(s.clone()));
+        }
+        for s in other.admins.iter().filter(|s| !self.admins.contains(s)) {
+            changes.push(Change::AdminAdded(s.clone()));
+        }
+        for user in self.users().filter(|u| !other.has_user(u)) {
+            changes.push(Change::UserRemoved(user.clone()));
+        }
+        for user in other.users().filter(|u| !self.has_user(u)) {
+            changes.push(Change::UserAdded(user.clone()));
+        }
+        for group in self.groups().filter(|g| !other.has_group(g)) {
+            changes.push(Change::GroupRemoved(group.clone()));
+        }
+        for group in other.groups().filter(|g| !self.has_group(g)) {
+            changes.push(Change::GroupAdded(group.clone()));
+        }
+        let groups: BTreeSet<&Group> = self.groups().chain(other.groups()).collect();
+        for group in groups {
+            let old = self.members_of(group).unwrap_or_default();
+            let new = other.members_of(group).unwrap_or_default();
+            for user in old.iter().filter(|u| !new.contains(u)) {
+                changes.push(Change::MemberRemoved(group.clone(), user.clone()));
+            }
+            for user in new.iter().filter(|u| !old.contains(u)) {
+                changes.push(Change::MemberAdded(group.clone(), user.clone()));
+            }
+        }
+
+        let empty = BTreeMap::new();
+        let subjects: BTreeSet<Subject> = self
+            .users()
+            .chain(other.users())
+            .map(|u| Subject::User(u.clone()))
+            .chain(
+                self.groups()
+                    .chain(other.groups())
+                    .map(|g| Subject::Group(g.clone())),
+            )
+            .collect();
+        for subject in subjects {
+            let repos = |cfg: &Config| match &subject {
+                Subject::User(user) => cfg.permissions_of(user).cloned(),
+                Subject::Group(group) => cfg.group_permissions_of(group).cloned(),
+            };
+            let (old, new) = (repos(self), repos(other));
+            let (old, new) = (
+                old.as_ref().unwrap_or(&empty),
+                new.as_ref().unwrap_or(&empty),
+            );
+            for (repo, old, new) in changed(old, new) {
+                changes.push(Change::Grant {
+                    subject: subject.clone(),
+                    repo,
+                    old,
+                    new,
+                });
+            }
+        }
+        for ((subject, repo, pattern), old, new) in changed(&self.refs, &other.refs) {
+            changes.push(Change::RefRule {
+                subject,
+                repo,
+                pattern,
+                old,
+                new,
+            });
+        }
+        for (subject, pattern) in self.creates.difference(&other.creates) {
+            changes.push(Change::CreateRemoved(subject.clone(), pattern.clone()));
+        }
+        for (subject, pattern) in other.creates.difference(&self.creates) {
+            changes.push(Change::CreateAdded(subject.clone(), pattern.clone()));
+        }
+        for (repo, old, new) in changed(&self.owners, &other.owners) {
+            changes.push(Change::Owner { repo, old, new });
+        }
+        changes
+    }
+}
+
+/// `lines` as the text of a file.
+fn text<'a>(lines: impl Iterator<Item = &'a String>) -> String {
+    lines.fold(String::new(), |text, line| text + line + "\n")
 }
 
+fn write_file(path: &Path, text: &str) -> Result<(), Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::ConfigInvalid("config path has no file name".to_string()))?;
+    let mut tmp_name = name.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Writes `gitcontrol.cfg` back, as [`Config::save`] would, without the
+/// included files.
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for (i, (user, repos)) in self.entries.iter().enumerate() {
-            if i > 0 {
-                writeln!(f)?;
+        for line in self.render().swap_remove(0) {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// The lines of each file as they were read, changing only what was
+    /// edited: a changed grant is rewritten in place, a new one goes after the
+    /// last line of its section, a new section at the end of `gitcontrol.cfg`,
+    /// and a removed section goes with its comments, those right above it
+    /// included (but not those right above the next section).
+    fn render(&self) -> Vec<Vec<String>> {
+        let empty = Config::default();
+        let original = self.original.as_deref().unwrap_or(&empty);
+        let lines = &self.lines;
+
+        // sections end at the next section, include or the end of their file
+        let boundary = |i: usize, file: usize| {
+            lines[i].file != file
+                || matches!(lines[i].kind, LineKind::Header(_) | LineKind::Include)
+        };
+        let mut dropped = vec![false; lines.len()];
+        for (header, line) in lines.iter().enumerate() {
+            let LineKind::Header(subject) = &line.kind else {
+                continue;
+            };
+            if self.has_section(subject) {
+                continue;
             }
-            writeln!(f, "@{user}")?;
-            for (repo, permission) in repos {
-                writeln!(f, "{} {repo}", permission.to_char())?;
+            let in_file = |i: usize| lines[i].file == line.file;
+            let mut start = header;
+            while start > 0 && in_file(start - 1) && lines[start - 1].text.trim().starts_with('#') {
+                start -= 1;
             }
+            let mut end = header + 1;
+            while end < lines.len() && !boundary(end, line.file) {
+                end += 1;
+            }
+            if end < lines.len() && in_file(end) {
+                while end > start && lines[end - 1].text.trim().starts_with('#') {
+                    end -= 1;
+                }
+            } else {
+                // the last section of a file takes the blank lines before it
+                while start > 0 && in_file(start - 1) && lines[start - 1].text.trim().is_empty() {
+                    start -= 1;
+                }
+            }
+            dropped[start..end].fill(true);
+        }
+
+        // where each section ends, for its new lines
+        let mut ends = BTreeMap::new();
+        let mut current = None;
+        for (i, line) in lines.iter().enumerate() {
+            match &line.kind {
+                LineKind::Header(subject) => current = Some(subject),
+                LineKind::Item(..) => {}
+                _ => continue,
+            }
+            if let Some(subject) = current {
+                ends.retain(|_, s| s != &subject);
+                ends.insert(i, subject);
+            }
+        }
+
+        let mut outs: Vec<Vec<String>> = vec![Vec::new(); self.files.len().max(1)];
+        let mut personal_at = None;
+        let (mut personal_done, mut admin_done) = (false, false);
+        let mut rewritten = BTreeSet::new();
+        for (i, line) in lines.iter().enumerate() {
+            let out = &mut outs[line.file];
+            let indent = &line.text[..line.text.len() - line.text.trim_start().len()];
+            match &line.kind {
+                _ if dropped[i] => {}
+                LineKind::Personal if self.personal_dir == original.personal_dir => {
+                    personal_at = Some((line.file, out.len()));
+                    out.push(line.text.clone());
+                }
+                LineKind::Personal => {
+                    if let (Some(dir), false) = (&self.personal_dir, personal_done) {
+                        personal_at = Some((line.file, out.len()));
+                        out.push(format!("{PERSONAL}{}", dir.display()));
+                    }
+                    personal_done = true;
+                }
+                LineKind::Admin if self.admins != original.admins => {
+                    if !self.admins.is_empty() && !admin_done {
+                        out.push(self.admin_line());
+                    }
+                    admin_done = true;
+                }
+                LineKind::Header(subject @ Subject::Group(group))
+                    if self.members_of(group) != original.members_of(group) =>
+                {
+                    out.push(if rewritten.insert((subject.clone(), None)) {
+                        self.header_line(subject)
+                    } else {
+                        format!("={group}")
+                    });
+                }
+                LineKind::Item(subject, item) => {
+                    let now = self.item_line(subject, item);
+                    if now == original.item_line(subject, item) {
+                        out.push(line.text.clone());
+                    } else if let Some(now) = now
+                        && rewritten.insert((subject.clone(), Some(item.clone())))
+                    {
+                        out.push(format!("{indent}{now}"));
+                    }
+                }
+                _ => out.push(line.text.clone()),
+            }
+
+            if let Some(&subject) = ends.get(&i)
+                && self.has_section(subject)
+            {
+                let indent = if let LineKind::Item(..) = line.kind {
+                    indent
+                } else {
+                    ""
+                };
+                for item in self.items(subject) {
+                    if original.item_line(subject, &item).is_none()
+                        && let Some(now) = self.item_line(subject, &item)
+                    {
+                        out.push(format!("{indent}{now}"));
+                    }
+                }
+            }
+        }
+
+        let out = &mut outs[0];
+        let sections = self.groups.iter().map(|g| Subject::Group(g.group.clone()));
+        let sections = sections.chain(self.entries.iter().map(|(u, _)| Subject::User(u.clone())));
+        for subject in sections.filter(|s| !original.has_section(s)) {
+            if out.last().is_some_and(|l| !l.trim().is_empty()) {
+                out.push(String::new());
+            }
+            out.push(self.header_line(&subject));
+            out.extend(
+                self.items(&subject)
+                    .iter()
+                    .filter_map(|item| self.item_line(&subject, item)),
+            );
+        }
+
+        let mut head = Vec::new();
+        if let (Some(dir), None) = (&self.personal_dir, &original.personal_dir) {
+            head.push(format!("{PERSONAL}{}", dir.display()));
+        }
+        if !self.admins.is_empty() && original.admins.is_empty() {
+            head.push(self.admin_line());
+        }
+        if !head.is_empty() {
+            let at = match personal_at {
+                Some((0, i)) => i + 1,
+                _ => 0,
+            };
+            if at == 0 && out.first().is_some_and(|l| !l.trim().is_empty()) {
+                head.push(String::new());
+            }
+            out.splice(at..at, head);
+        }
+        outs
+    }
+
+    fn has_section(&self, subject: &Subject) -> bool {
+        match subject {
+            Subject::User(user) => self.has_user(user),
+            Subject::Group(group) => self.has_group(group),
+        }
+    }
+
+    fn header_line(&self, subject: &Subject) -> String {
+        match subject {
+            Subject::User(user) => format!("@{user}"),
+            Subject::Group(group) => {
+                let mut line = format!("={group}");
+                for member in self.members_of(group).unwrap_or_default() {
+                    line.push_str(&format!(" {member}"));
+                }
+                line
+            }
+        }
+    }
+
+    fn admin_line(&self) -> String {
+        let names: Vec<String> = self.admins.iter().map(|s| s.to_string()).collect();
+        format!("{ADMIN}{}", names.join(" "))
+    }
+
+    /// The items of a section in the order a new section lists them: grants
+    /// (sorted), ref rules, creates, then owned repositories.
+    fn items(&self, subject: &Subject) -> Vec<Item> {
+        let repos = match subject {
+            Subject::User(user) => self.permissions_of(user),
+            Subject::Group(group) => self.group_permissions_of(group),
+        };
+        let mut items: Vec<Item> = repos
+            .into_iter()
+            .flatten()
+            .map(|(repo, _)| Item::Grant(repo.clone()))
+            .collect();
+        items.extend(
+            self.ref_rules_of(subject)
+                .map(|(repo, pattern, _)| Item::Ref(repo.clone(), pattern.clone())),
+        );
+        items.extend(self.creates_of(subject).map(|p| Item::Create(p.clone())));
+        if let Subject::User(user) = subject {
+            items.extend(self.owned_by(user).map(|repo| Item::Owner(repo.clone())));
+        }
+        items
+    }
+
+    /// How `item` is written in the section of `subject`, or `None` if the
+    /// section does not hold it.
+    fn item_line(&self, subject: &Subject, item: &Item) -> Option<String> {
+        match item {
+            Item::Grant(repo) => {
+                let repos = match subject {
+                    Subject::User(user) => self.permissions_of(user),
+                    Subject::Group(group) => self.group_permissions_of(group),
+                };
+                Some(format!("{} {repo}", repos?.get(repo)?.to_char()))
+            }
+            Item::Ref(repo, pattern) => {
+                let key = (subject.clone(), repo.clone(), pattern.clone());
+                let permission = self.refs.get(&key)?;
+                Some(format!("{} {repo} {pattern}", permission.to_char()))
+            }
+            Item::Create(pattern) => self
+                .creates
+                .contains(&(subject.clone(), pattern.clone()))
+                .then(|| format!("{CREATE} {pattern}")),
+            Item::Owner(repo) => match subject {
+                Subject::User(user) => {
+                    (self.owner_of(repo) == Some(user)).then(|| format!("{OWNER} {repo}"))
+                }
+                Subject::Group(_) => None,
+            },
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testdir::TestDir;
 
     fn user(s: &str) -> User {
         User::from_string(s.to_string()).unwrap()
@@ -186,6 +1375,10 @@ mod tests {
         Repo::from_string(s.to_string()).unwrap()
     }
 
+    fn pattern(s: &str) -> Repo {
+        Repo::from_pattern(s.to_string()).unwrap()
+    }
+
     #[test]
     fn parses_users_and_permissions() {
         let cfg = Config::parse("@alice\nw dir/repo\nr a/b\n# comment\n\n@bob\n").unwrap();
@@ -209,10 +1402,77 @@ mod tests {
     }
 
     #[test]
-    fn round_trips_and_sorts_repos() {
-        let cfg = Config::parse("@alice\nw z/z\nr a/a\n").unwrap();
-        // repositories are emitted in sorted order
-        assert_eq!(cfg.to_string(), "@alice\nr a/a\nw z/z\n");
+    fn round_trips_in_file_order() {
+        let mut cfg = Config::parse("@alice\nw z/z\nr a/a\n").unwrap();
+        assert_eq!(cfg.to_string(), "@alice\nw z/z\nr a/a\n");
+        // a new section lists its repositories sorted
+        cfg.set_permission(&user("bob"), repo("z/z"), Permission::Read);
+        cfg.set_permission(&user("bob"), repo("a/a"), Permission::Write);
+        assert_eq!(
+            cfg.to_string(),
+            "@alice\nw z/z\nr a/a\n\n@bob\nw a/a\nr z/z\n"
+        );
+    }
+
+    #[test]
+    fn edits_keep_comments_and_layout() {
+        let text = "\
+# access to our repositories
+personal /srv/personal
+
+# the web team
+=web alice bob
+    w web/*
+    r doc/*
+
+# alice, moving to ops
+@alice
+# legacy
+r old/site
+w+ web/site
+
+# carol runs ops
+@carol
+r ops/*
+
+# dave's sandbox
+@dave
+w dave/x
+";
+        let mut cfg = Config::parse(text).unwrap();
+        assert_eq!(cfg.to_string(), text);
+
+        let web = group("web");
+        cfg.set_group_permission(&web, pattern("doc/*"), Permission::Write);
+        cfg.set_group_permission(&web, repo("web/api"), Permission::Read);
+        cfg.remove_member(&web, &user("bob")).unwrap();
+        cfg.revoke(&user("alice"), &repo("old/site"));
+        cfg.set_create(&Subject::User(user("carol")), pattern("ops/*"));
+        cfg.add_admin(Subject::User(user("carol")));
+        cfg.remove_user(&user("alice"));
+        cfg.remove_user(&user("dave"));
+        cfg.add_user(user("erin")).unwrap();
+        assert_eq!(
+            cfg.to_string(),
+            "\
+# access to our repositories
+personal /srv/personal
+admin carol
+
+# the web team
+=web
+    w web/*
+    w doc/*
+    r web/api
+
+# carol runs ops
+@carol
+r ops/*
+C ops/*
+
+@erin
+"
+        );
     }
 
     #[test]
@@ -259,8 +1519,329 @@ mod tests {
         assert!(!cfg.remove_user(&user("alice")));
     }
 
+    #[test]
+    fn parses_patterns() {
+        let cfg = Config::parse("@alice\nw web/*\nr */*\n").unwrap();
+        let repos = cfg.permissions_of(&user("alice")).unwrap();
+        assert!(repos.keys().all(|r| r.is_pattern()));
+        assert_eq!(cfg.to_string(), "@alice\nw web/*\nr */*\n");
+        assert!(Config::parse("@alice\nw web/si*\n").is_err());
+    }
+
+    #[test]
+    fn deny_round_trips() {
+        let cfg = Config::parse("=devs alice\n- secret/*\n@alice\n- web/site\nr */*\n").unwrap();
+        assert_eq!(
+            cfg.permissions_of(&user("alice"))
+                .unwrap()
+                .get(&repo("web/site")),
+            Some(&Permission::Deny)
+        );
+        assert_eq!(
+            Config::parse(&cfg.to_string()).unwrap().to_string(),
+            cfg.to_string()
+        );
+        assert!(cfg.to_string().contains("- secret/*\n"));
+    }
+
+    #[test]
+    fn parses_ref_rules() {
+        let text = "=devs alice\nr web/site\nd web/site refs/heads/feature/*\n\n@alice\nw web/site\nw web/site refs/heads/main\n- web/site refs/tags/*\n";
+        let cfg = Config::parse(text).unwrap();
+        let alice = Subject::User(user("alice"));
+        let rules: Vec<String> = cfg
+            .ref_rules_of(&alice)
+            .map(|(r, p, permission)| format!("{} {r} {p}", permission.to_char()))
+            .collect();
+        assert_eq!(
+            rules,
+            vec!["w web/site refs/heads/main", "- web/site refs/tags/*"]
+        );
+        assert_eq!(cfg.to_string(), text);
+
+        assert!(Config::parse("@alice\nr web/site refs/heads/main\n").is_err());
+        assert!(Config::parse("@alice\nw web/site heads/main\n").is_err());
+        assert!(Config::parse("@alice\nr+ web/site\n").is_err());
+        assert!(Config::parse("@alice\nw web/site refs/heads/a extra\n").is_err());
+    }
+
+    #[test]
+    fn force_and_delete_levels() {
+        let cfg =
+            Config::parse("@alice\nw+ web/site\nd web/api\n+ web/doc refs/heads/*\n").unwrap();
+        let repos = cfg.permissions_of(&user("alice")).unwrap();
+        assert_eq!(repos.get(&repo("web/site")), Some(&Permission::Force));
+        assert_eq!(repos.get(&repo("web/api")), Some(&Permission::Delete));
+        // `w+` is written back as `+` once changed
+        let mut cfg = cfg;
+        cfg.set_permission(&user("alice"), repo("web/site"), Permission::Force);
+        cfg.set_permission(&user("alice"), repo("web/api"), Permission::Force);
+        assert_eq!(
+            cfg.to_string(),
+            "@alice\nw+ web/site\n+ web/api\n+ web/doc refs/heads/*\n"
+        );
+    }
+
+    #[test]
+    fn creates_and_owners() {
+        let text = "=devs alice\nC sandbox/*\n\n@alice\nr web/site\nowner sandbox/one\n";
+        let mut cfg = Config::parse(text).unwrap();
+        assert_eq!(cfg.to_string(), text);
+        assert_eq!(cfg.owner_of(&repo("sandbox/one")), Some(&user("alice")));
+        let alice = cfg.user_db(&user("alice"));
+        assert!(alice.can_create(&repo("sandbox/two")));
+        assert_eq!(alice.repos[&repo("sandbox/one")], Permission::Delete);
+
+        cfg.set_owner(repo("sandbox/two"), &user("bob"));
+        assert!(cfg.to_string().ends_with("@bob\nowner sandbox/two\n"));
+        cfg.remove_user(&user("bob"));
+        assert_eq!(cfg.owner_of(&repo("sandbox/two")), None);
+
+        assert!(Config::parse("=devs\nowner web/site\n").is_err());
+        assert!(Config::parse("@a\nowner */site\n").is_err());
+        assert!(Config::parse("@a\nowner web/site\n@b\nowner web/site\n").is_err());
+        assert!(Config::parse("@a\nC web/* refs/heads/main\n").is_err());
+    }
+
+    #[test]
+    fn directory_owners() {
+        let text =
+            "=devs carol\nr web/site\n\n@alice\nowner web/*\n\n@bob\nw web/site\nowner web/site\n";
+        let mut cfg = Config::parse(text).unwrap();
+        assert_eq!(cfg.to_string(), text);
+        let alice = user("alice");
+        assert!(cfg.owns(&alice, &repo("web/site")));
+        assert!(cfg.owns(&alice, &Repo::from_pattern("web/*".to_string()).unwrap()));
+        assert!(cfg.owns(&user("bob"), &repo("web/site")));
+        assert!(!cfg.owns(&user("bob"), &repo("web/api")));
+        assert!(cfg.owns(&user("carol"), &repo("~carol/x")));
+        assert_eq!(
+            cfg.user_db(&alice).permission(&repo("web/api")).unwrap().1,
+            Permission::Delete
+        );
+        assert_eq!(
+            cfg.grants_on(&repo("web/site")),
+            vec![
+                (Subject::Group(group("devs")), Permission::Read),
+                (Subject::User(user("bob")), Permission::Write)
+            ]
+        );
+
+        assert_eq!(cfg.remove_owner(&repo("web/site")), Some(user("bob")));
+        assert!(!cfg.owns(&user("bob"), &repo("web/site")));
+    }
+
+    #[test]
+    fn personal_dir_setting() {
+        let text = "personal /srv/personal\n\n@alice\nr ~bob/scratch\n";
+        let cfg = Config::parse(text).unwrap();
+        assert_eq!(cfg.personal_dir(), Some(Path::new("/srv/personal")));
+        assert_eq!(cfg.to_string(), text);
+        let alice = cfg.user_db(&user("alice"));
+        assert!(alice.can_write(&repo("~alice/x")));
+        assert!(alice.can_read(&repo("~bob/scratch")));
+        assert!(Config::parse("@alice\npersonal dir\n").is_err());
+    }
+
+    #[test]
+    fn admins() {
+        let text = "personal /srv/personal\nadmin alice =ops\n\n=ops carol\n\n@alice\n";
+        let mut cfg = Config::parse(text).unwrap();
+        assert_eq!(cfg.to_string(), text);
+        assert!(cfg.is_admin(&user("alice")));
+        assert!(cfg.is_admin(&user("carol")));
+        assert!(!cfg.is_admin(&user("bob")));
+
+        assert!(cfg.add_admin(Subject::User(user("bob"))));
+        assert!(!cfg.add_admin(Subject::User(user("bob"))));
+        assert!(cfg.is_admin(&user("bob")));
+        assert!(cfg.remove_group(&group("ops")));
+        assert!(!cfg.is_admin(&user("carol")));
+        assert!(cfg.remove_admin(&Subject::User(user("bob"))));
+        assert!(!cfg.remove_admin(&Subject::User(user("bob"))));
+        assert_eq!(cfg.admins(), &[Subject::User(user("alice"))]);
+
+        assert!(Config::parse("@alice\nadmin alice\n").is_err());
+    }
+
+    #[test]
+    fn ref_rule_edits() {
+        let mut cfg = Config::parse("").unwrap();
+        let bob = Subject::User(user("bob"));
+        let main = RefPattern::from_string("refs/heads/main".to_string()).unwrap();
+        cfg.set_ref_rule(&bob, repo("web/site"), main.clone(), Permission::Write);
+        assert!(cfg.has_user(&user("bob")));
+        assert_eq!(cfg.to_string(), "@bob\nw web/site refs/heads/main\n");
+        assert!(cfg.revoke_ref_rule(&bob, &repo("web/site"), &main));
+        assert!(!cfg.revoke_ref_rule(&bob, &repo("web/site"), &main));
+    }
+
+    #[test]
+    fn user_db_merges_groups_and_ref_rules() {
+        let cfg = Config::parse(
+            "=devs alice\nr web/*\nw+ web/site refs/heads/feature/*\n@alice\n- web/secret\nw web/site refs/heads/main\n@bob\nw web/site\n",
+        )
+        .unwrap();
+        let alice = cfg.user_db(&user("alice"));
+        assert_eq!(alice.groups, vec![group("devs")]);
+        assert!(alice.can_read(&repo("web/api")));
+        assert!(!alice.can_read(&repo("web/secret")));
+        assert_eq!(alice.ref_rules(&repo("web/site")).count(), 2);
+        assert!(cfg.user_db(&user("carol")).is_empty());
+    }
+
     #[test]
     fn rejects_permission_before_user() {
         assert!(Config::parse("w d/r\n").is_err());
     }
+
+    fn group(s: &str) -> Group {
+        Group::from_string(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parses_groups_and_their_grants() {
+        let cfg =
+            Config::parse("=devs alice bob\nw web/site\n@alice\nr a/b\n=devs carol\n").unwrap();
+        assert_eq!(
+            cfg.members_of(&group("devs")).unwrap(),
+            &[user("alice"), user("bob"), user("carol")]
+        );
+        assert_eq!(
+            cfg.group_permissions_of(&group("devs"))
+                .unwrap()
+                .get(&repo("web/site")),
+            Some(&Permission::Write)
+        );
+        // alice's own section is unaffected by the group's grants
+        assert_eq!(cfg.permissions_of(&user("alice")).unwrap().len(), 1);
+        assert_eq!(
+            cfg.groups_of(&user("carol")).collect::<Vec<_>>(),
+            vec![&group("devs")]
+        );
+        assert!(Config::parse("=\n").is_err());
+        assert!(Config::parse("=devs bad!user\n").is_err());
+    }
+
+    #[test]
+    fn group_round_trip() {
+        let cfg = Config::parse("@alice\nr a/a\n=devs alice bob\nw z/z\n").unwrap();
+        assert_eq!(cfg.to_string(), "@alice\nr a/a\n=devs alice bob\nw z/z\n");
+        assert_eq!(
+            Config::parse(&cfg.to_string()).unwrap().to_string(),
+            cfg.to_string()
+        );
+    }
+
+    #[test]
+    fn group_membership_edits() {
+        let mut cfg = Config::parse("@alice\n").unwrap();
+        assert!(cfg.add_member(&group("devs"), user("alice")).is_err());
+        cfg.add_group(group("devs")).unwrap();
+        assert!(cfg.add_group(group("devs")).is_err());
+        assert!(cfg.add_member(&group("devs"), user("alice")).unwrap());
+        assert!(!cfg.add_member(&group("devs"), user("alice")).unwrap());
+        cfg.set_group_permission(&group("devs"), repo("web/site"), Permission::Read);
+        assert!(cfg.revoke_group(&group("devs"), &repo("web/site")));
+
+        // removing a user also drops their memberships
+        cfg.remove_user(&user("alice"));
+        assert!(cfg.members_of(&group("devs")).unwrap().is_empty());
+        assert!(!cfg.remove_member(&group("devs"), &user("alice")).unwrap());
+        assert!(cfg.remove_group(&group("devs")));
+        assert!(!cfg.has_group(&group("devs")));
+    }
+
+    #[test]
+    fn includes_and_conf_dir() {
+        let dir = TestDir::new("config");
+        let path = dir.join("gitcontrol.cfg");
+        fs::create_dir_all(dir.join(CONF_DIR)).unwrap();
+        fs::write(&path, "@alice\nr web/site\ninclude ops.cfg\n").unwrap();
+        fs::write(dir.join("ops.cfg"), "@carol\nw ops/deploy\n").unwrap();
+        fs::write(dir.join("gitcontrol.d/20-web.cfg"), "@alice\nw web/app\n").unwrap();
+        fs::write(dir.join("gitcontrol.d/10-bob.cfg"), "@bob\nr web/site\n").unwrap();
+        fs::write(dir.join("gitcontrol.d/.hidden.cfg"), "not a config").unwrap();
+        fs::write(dir.join("gitcontrol.d/notes.txt"), "not a config").unwrap();
+
+        let mut cfg = Config::read(&path).unwrap();
+        let names: Vec<_> = cfg.files().iter().map(|f| cfg.file_name(f)).collect();
+        assert_eq!(
+            names,
+            [
+                "gitcontrol.cfg",
+                "ops.cfg",
+                "gitcontrol.d/10-bob.cfg",
+                "gitcontrol.d/20-web.cfg"
+            ]
+        );
+        assert_eq!(cfg.users().count(), 3);
+        let alice = cfg.permissions_of(&user("alice")).unwrap();
+        assert_eq!(alice.get(&repo("web/app")), Some(&Permission::Write));
+
+        // edits go to the file holding what they change
+        cfg.set_permission(&user("carol"), repo("ops/deploy"), Permission::Read);
+        cfg.set_permission(&user("dave"), repo("web/site"), Permission::Read);
+        assert_eq!(
+            cfg.save(&path).unwrap(),
+            [path.clone(), dir.join("ops.cfg")]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("ops.cfg")).unwrap(),
+            "@carol\nr ops/deploy\n"
+        );
+        assert!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .ends_with("\n@dave\nr web/site\n")
+        );
+
+        // errors say which file and line
+        fs::write(dir.join("gitcontrol.d/10-bob.cfg"), "@bob\nr web\n").unwrap();
+        assert!(matches!(Config::read(&path),
+            Err(Error::ConfigInvalid(e)) if e.starts_with("gitcontrol.d/10-bob.cfg: line 2: ")));
+        fs::write(dir.join("gitcontrol.d/10-bob.cfg"), "").unwrap();
+        fs::write(dir.join("ops.cfg"), "include gitcontrol.cfg\n").unwrap();
+        assert!(matches!(Config::read(&path),
+            Err(Error::ConfigInvalid(e)) if e == "gitcontrol.cfg: includes itself"));
+        fs::write(dir.join("ops.cfg"), "include gitcontrol.d/10-bob.cfg\n").unwrap();
+        assert!(matches!(Config::read(&path),
+            Err(Error::ConfigInvalid(e)) if e == "gitcontrol.d/10-bob.cfg: included twice"));
+        assert!(Config::parse("include ops.cfg\n").is_err());
+    }
+
+    #[test]
+    fn diff_lists_changes() {
+        let old =
+            Config::parse("admin alice\n@alice\nr web/site\nw web/app\n\n=devs alice\nr docs/*\n")
+                .unwrap();
+        let mut new = old.clone();
+        new.set_permission(&user("alice"), repo("web/site"), Permission::Write);
+        new.revoke(&user("alice"), &repo("web/app"));
+        new.add_user(user("bob")).unwrap();
+        new.add_member(&group("devs"), user("bob")).unwrap();
+        new.set_owner(repo("web/site"), &user("bob"));
+        new.remove_admin(&Subject::User(user("alice")));
+
+        let changes: Vec<String> = old.diff(&new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            [
+                "remove admin alice",
+                "add user bob",
+                "add bob to devs",
+                "alice web/app: write -> none",
+                "alice web/site: read -> write",
+                "owner of web/site: bob",
+            ]
+        );
+        assert_eq!(new.diff(&old)[1], Change::UserRemoved(user("bob")));
+        assert!(old.diff(&old.clone()).is_empty());
+        // the layout does not count
+        let reordered = Config::parse(
+            "admin alice\n=devs alice\nr docs/*\n# alice\n@alice\nw web/app\nr web/site\n",
+        )
+        .unwrap();
+        assert!(old.diff(&reordered).is_empty());
+    }
 }
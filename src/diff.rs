@@ -0,0 +1,149 @@
+//! Unified diffs of text files, as `diff -u` writes them, for `--dry-run`.
+
+/// How many unchanged lines surround each change.
+const CONTEXT: usize = 3;
+/// Above this many cells (lines by lines, once the common start and end are
+/// left out), the changed part is shown as replaced outright rather than
+/// diffed line by line.
+const MAX_CELLS: usize = 4_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// The unified diff turning `old` into `new`, the files named `old_name` and
+/// `new_name`; empty if they are the same.
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let script = edit_script(&a, &b);
+    if script.iter().all(|(op, _)| *op == Op::Same) {
+        return String::new();
+    }
+
+    let mut out = format!("--- {old_name}\n+++ {new_name}\n");
+    for (start, end) in hunks(&script) {
+        // lines of each side before the hunk
+        let before = |side: Op| script[..start].iter().filter(|(op, _)| *op != side).count();
+        let count = |side: Op| {
+            script[start..end]
+                .iter()
+                .filter(|(op, _)| *op != side)
+                .count()
+        };
+        let (old_start, old_count) = (before(Op::Added), count(Op::Added));
+        let (new_start, new_count) = (before(Op::Removed), count(Op::Removed));
+        // an empty side is numbered by the line before it
+        let first = |start: usize, count: usize| start + usize::from(count > 0);
+        out.push_str(&format!(
+            "@@ -{},{old_count} +{},{new_count} @@\n",
+            first(old_start, old_count),
+            first(new_start, new_count)
+        ));
+        for (op, line) in &script[start..end] {
+            let mark = match op {
+                Op::Same => ' ',
+                Op::Removed => '-',
+                Op::Added => '+',
+            };
+            out.push(mark);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Every line of `a` and `b` in order, each marked as kept, removed or
+/// added, keeping as many as possible (removals first where there is a
+/// choice).
+fn edit_script<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (x, y) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut script: Vec<(Op, &str)> = a[..prefix].iter().map(|l| (Op::Same, *l)).collect();
+    if x.len().saturating_mul(y.len()) > MAX_CELLS {
+        script.extend(x.iter().map(|l| (Op::Removed, *l)));
+        script.extend(y.iter().map(|l| (Op::Added, *l)));
+    } else {
+        // lcs[i][j]: the longest common subsequence of x[i..] and y[j..]
+        let width = y.len() + 1;
+        let mut lcs = vec![0u32; (x.len() + 1) * width];
+        for i in (0..x.len()).rev() {
+            for j in (0..y.len()).rev() {
+                lcs[i * width + j] = match x[i] == y[j] {
+                    true => lcs[(i + 1) * width + j + 1] + 1,
+                    false => lcs[(i + 1) * width + j].max(lcs[i * width + j + 1]),
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < x.len() || j < y.len() {
+            if i < x.len() && j < y.len() && x[i] == y[j] {
+                script.push((Op::Same, x[i]));
+                (i, j) = (i + 1, j + 1);
+            } else if j == y.len()
+                || (i < x.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+            {
+                script.push((Op::Removed, x[i]));
+                i += 1;
+            } else {
+                script.push((Op::Added, y[j]));
+                j += 1;
+            }
+        }
+    }
+    script.extend(a[a.len() - suffix..].iter().map(|l| (Op::Same, *l)));
+    script
+}
+
+/// The ranges of `script` shown as hunks: each change with [`CONTEXT`]
+/// lines around it, merged where they touch.
+fn hunks(script: &[(Op, &str)]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in script
+        .iter()
+        .enumerate()
+        .filter(|(_, (op, _))| *op != Op::Same)
+    {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + 1 + CONTEXT).min(script.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_like_diff_u() {
+        assert_eq!(unified("a", "b", "x\ny\n", "x\ny\n"), "");
+        assert_eq!(
+            unified("a", "b", "", "@alice\nr web/site\n"),
+            "--- a\n+++ b\n@@ -0,0 +1,2 @@\n+@alice\n+r web/site\n"
+        );
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n14\n15\n";
+        assert_eq!(
+            unified("a/gitcontrol.cfg", "b/gitcontrol.cfg", old, new),
+            "--- a/gitcontrol.cfg\n+++ b/gitcontrol.cfg\n\
+             @@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n\
+             @@ -10,5 +10,5 @@\n 10\n 11\n 12\n-13\n 14\n+15\n"
+        );
+    }
+}
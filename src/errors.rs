@@ -4,18 +4,31 @@ use std::io;
 
 #[derive(Debug)]
 pub enum Error {
-    UsageInvalid(&'static str),
+    UsageInvalid(String),
     VarError(env::VarError),
     UserInvalid(String),
+    GroupInvalid(String),
     RepoInvalid((String, &'static str)),
+    ProtocolInvalid((String, &'static str)),
+    RefInvalid((String, &'static str)),
     PermissionInvalid(char),
     AccessDenied(&'static str),
     Io(io::Error),
     UserExists(String),
     UserUnknown(String),
+    GroupExists(String),
+    GroupUnknown(String),
     RepoExists(String),
+    KeyInvalid(String),
+    KeyExists(String),
+    KeyUnknown(String),
     ConfigInvalid(String),
     CommandFailed(String),
+    TimestampInvalid(String),
+    LogInvalid(String),
+    Locked(String),
+    HistoryUnknown(String),
+    StateInvalid(String),
 }
 
 impl fmt::Display for Error {
@@ -30,9 +43,18 @@ impl fmt::Display for Error {
             Error::UserInvalid(s) => {
                 write!(f, "User Invalid {}", s)
             }
+            Error::GroupInvalid(s) => {
+                write!(f, "Group Invalid {}", s)
+            }
             Error::RepoInvalid((s, t)) => {
                 write!(f, "Repo Invalid \"{}\": {}", s, t)
             }
+            Error::RefInvalid((s, t)) => {
+                write!(f, "Ref Invalid \"{}\": {}", s, t)
+            }
+            Error::ProtocolInvalid((s, t)) => {
+                write!(f, "GIT_PROTOCOL Invalid {:?}: {}", s, t)
+            }
             Error::PermissionInvalid(s) => {
                 write!(f, "Permission Invalid {}", s)
             }
@@ -48,15 +70,49 @@ impl fmt::Display for Error {
             Error::UserUnknown(s) => {
                 write!(f, "unknown user: {}", s)
             }
+            Error::GroupExists(s) => {
+                write!(f, "group already exists: {}", s)
+            }
+            Error::GroupUnknown(s) => {
+                write!(f, "unknown group: {}", s)
+            }
             Error::RepoExists(s) => {
                 write!(f, "repository already exists: {}", s)
             }
+            Error::KeyInvalid(s) => {
+                write!(f, "invalid key: {}", s)
+            }
+            Error::KeyExists(s) => {
+                write!(f, "key already registered: {}", s)
+            }
+            Error::KeyUnknown(s) => {
+                write!(f, "unknown key: {}", s)
+            }
             Error::ConfigInvalid(s) => {
                 write!(f, "invalid config: {}", s)
             }
             Error::CommandFailed(s) => {
                 write!(f, "command failed: {}", s)
             }
+            Error::TimestampInvalid(s) => {
+                write!(
+                    f,
+                    "invalid time \"{}\" (expected YYYY-MM-DD[THH:MM[:SS]])",
+                    s
+                )
+            }
+            Error::LogInvalid(s) => {
+                write!(f, "invalid log entry: {}", s)
+            }
+            Error::Locked(s) => {
+                write!(f, "could not lock {}", s)
+            }
+            Error::HistoryUnknown(s) => {
+                write!(f, "no such change in the history: {}", s)
+            }
+            Error::StateInvalid(s) => {
+                write!(f, "invalid state file: {}", s)
+            }
         }
     }
 }
@@ -0,0 +1,444 @@
+//! The history of the configuration: every change to `gitcontrol.cfg`, its
+//! included files and `gitcontrol.d/` is committed to a local bare
+//! repository, [`HISTORY_REPO`] in the base directory, so an earlier state
+//! can be looked at and restored.
+//!
+//! Each commit is authored by whoever made the change (the SSH user, or
+//! `SUDO_USER`/`USER` for `gitcontrol`), with the command line as its message.
+//! Edits made by hand are committed, as [`OUTSIDE`], before the next change
+//! goes in.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::admin::config_path;
+use crate::adminrepo::{export, git};
+use crate::audit::Timestamp;
+use crate::config::{CONF_DIR, Config};
+use crate::errors::Error;
+use crate::repos::is_bare_repo;
+
+/// The history repository, relative to the base directory.
+pub const HISTORY_REPO: &str = ".gitcontrol-history.git";
+/// The message of changes found in the files, made without gitcontrol.
+pub const OUTSIDE: &str = "changes made outside gitcontrol";
+
+/// One recorded change, newest first in [`entries`].
+#[derive(Debug)]
+pub struct Entry {
+    pub id: String,
+    pub time: Timestamp,
+    pub operator: String,
+    pub command: String,
+}
+
+pub fn history_path(base: &Path) -> PathBuf {
+    base.join(HISTORY_REPO)
+}
+
+/// The user running this, behind `sudo` if that is how.
+pub fn operator() -> String {
+    ["SUDO_USER", "USER"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Run `f`, which changes the configuration under `base`, recording what it
+/// did as `command` by `operator`. Hold the config's lock around this.
+///
+/// Edits made by hand are recorded first, and not being able to do so stops
+/// `f` from running. Once `f` has run, its change stands even if it cannot
+/// be recorded; that is only warned about.
+pub fn recorded<T, F>(base: &Path, operator: &str, command: &str, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    record(base, operator, OUTSIDE)?;
+    let result = f()?;
+    if let Err(e) = record(base, operator, command) {
+        eprintln!("gitcontrol: warning: the change was not recorded in {HISTORY_REPO}: {e}");
+    }
+    Ok(result)
+}
+
+/// Commit the configuration files under `base` as they are now, unless they
+/// did not change since the last commit, returning the new commit. The
+/// history repository is created if needed.
+pub fn record(base: &Path, operator: &str, message: &str) -> Result<Option<String>, Error> {
+    let history = history_path(base);
+    if !is_bare_repo(&history) {
+        git(
+            [
+                OsStr::new("init"),
+                OsStr::new("--bare"),
+                OsStr::new("-q"),
+                history.as_os_str(),
+            ],
+            None,
+        )?;
+    }
+    let git_dir = history.as_os_str();
+
+    let files = config_files(base);
+    let parent = head(base);
+    if parent.is_none() && files.is_empty() {
+        return Ok(None);
+    }
+    let tree = write_tree(base, &files)?;
+    if let Some(parent) = &parent {
+        let parent_tree = format!("{parent}^{{tree}}");
+        let parent_tree = git(
+            [
+                OsStr::new("--git-dir"),
+                git_dir,
+                OsStr::new("rev-parse"),
+                OsStr::new(&parent_tree),
+            ],
+            None,
+        )?;
+        if parent_tree == tree {
+            return Ok(None);
+        }
+    }
+
+    let name = format!("user.name={operator}");
+    let mut commit = vec![
+        OsStr::new("-c"),
+        OsStr::new(&name),
+        OsStr::new("-c"),
+        OsStr::new("user.email=gitcontrol@localhost"),
+        OsStr::new("--git-dir"),
+        git_dir,
+        OsStr::new("commit-tree"),
+        OsStr::new(&tree),
+        OsStr::new("-m"),
+        OsStr::new(message),
+    ];
+    if let Some(parent) = &parent {
+        commit.extend([OsStr::new("-p"), OsStr::new(parent)]);
+    }
+    let commit = git(commit, None)?;
+    git(
+        [
+            OsStr::new("--git-dir"),
+            git_dir,
+            OsStr::new("update-ref"),
+            OsStr::new("HEAD"),
+            OsStr::new(&commit),
+        ],
+        None,
+    )?;
+    Ok(Some(commit))
+}
+
+/// The recorded changes, newest first; none before the first one.
+pub fn entries(base: &Path) -> Result<Vec<Entry>, Error> {
+    if head(base).is_none() {
+        return Ok(Vec::new());
+    }
+    let history = history_path(base);
+    let out = git(
+        [
+            OsStr::new("--git-dir"),
+            history.as_os_str(),
+            OsStr::new("log"),
+            OsStr::new("--format=%H%x09%at%x09%an%x09%s"),
+        ],
+        None,
+    )?;
+    out.lines()
+        .map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let mut next = || fields.next().unwrap_or_default().to_string();
+            let (id, time, operator, command) = (next(), next(), next(), next());
+            let time = time
+                .parse()
+                .map_err(|_| Error::CommandFailed(format!("git log: bad line {line:?}")))?;
+            Ok(Entry {
+                id,
+                time: Timestamp(time),
+                operator,
+                command,
+            })
+        })
+        .collect()
+}
+
+/// The change recorded as `id`, as a patch.
+pub fn show(base: &Path, id: &str) -> Result<String, Error> {
+    let commit = resolve(base, id)?;
+    let history = history_path(base);
+    git(
+        [
+            OsStr::new("--git-dir"),
+            history.as_os_str(),
+            OsStr::new("show"),
+            OsStr::new("--format=%H%nby %an, %ad%n%n    %s%n"),
+            OsStr::new("--date=iso"),
+            OsStr::new(&commit),
+        ],
+        None,
+    )
+}
+
+/// Put the configuration files under `base` back as they were at `id`,
+/// returning the names of those written or removed. The restored
+/// configuration must parse; otherwise nothing is changed. The current state
+/// must be recorded first (see [`recorded`]).
+pub fn restore(base: &Path, id: &str) -> Result<Vec<String>, Error> {
+    let commit = resolve(base, id)?;
+    let history = history_path(base);
+    let changes = git(
+        [
+            OsStr::new("--git-dir"),
+            history.as_os_str(),
+            OsStr::new("diff-tree"),
+            OsStr::new("-r"),
+            OsStr::new("--no-renames"),
+            OsStr::new("--name-status"),
+            OsStr::new("HEAD"),
+            OsStr::new(&commit),
+        ],
+        None,
+    )?;
+
+    let stage = base.join(format!(".gitcontrol-rollback.{}", std::process::id()));
+    let result = export(&history, &commit, &stage)
+        .and_then(|()| Config::read(&config_path(&stage)).map(|_| ()))
+        .and_then(|()| {
+            let mut names = Vec::new();
+            for line in changes.lines() {
+                let Some((status, name)) = line.split_once('\t') else {
+                    continue;
+                };
+                let path = base.join(name);
+                if status == "D" {
+                    fs::remove_file(&path)?;
+                } else {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::rename(stage.join(name), &path)?;
+                }
+                names.push(name.to_string());
+            }
+            Ok(names)
+        });
+    let _ = fs::remove_dir_all(&stage);
+    result
+}
+
+/// What rolling back to `id` would change, as a patch.
+pub fn preview(base: &Path, id: &str) -> Result<String, Error> {
+    let commit = resolve(base, id)?;
+    let tree = write_tree(base, &config_files(base))?;
+    let history = history_path(base);
+    git(
+        [
+            OsStr::new("--git-dir"),
+            history.as_os_str(),
+            OsStr::new("diff-tree"),
+            OsStr::new("-p"),
+            OsStr::new("--no-renames"),
+            OsStr::new(&tree),
+            OsStr::new(&commit),
+        ],
+        None,
+    )
+    .map(|patch| match patch.is_empty() {
+        true => patch,
+        false => patch + "\n",
+    })
+}
+
+/// Store `files` under `base` in the history repository as a tree,
+/// returning it.
+fn write_tree(base: &Path, files: &[PathBuf]) -> Result<String, Error> {
+    let history = history_path(base);
+    let git_dir = history.as_os_str();
+    // a fresh index each time, so removed files drop out of the tree
+    let index = history.join("gitcontrol-record.index");
+    let _ = fs::remove_file(&index);
+    let mut add = vec![
+        OsStr::new("--git-dir"),
+        git_dir,
+        OsStr::new("--work-tree"),
+        base.as_os_str(),
+        OsStr::new("add"),
+        OsStr::new("--"),
+    ];
+    add.extend(files.iter().map(|f| f.as_os_str()));
+    let tree = match files.is_empty() {
+        true => Ok(String::new()),
+        false => git(add, Some(&index)),
+    }
+    .and_then(|_| {
+        git(
+            [OsStr::new("--git-dir"), git_dir, OsStr::new("write-tree")],
+            Some(&index),
+        )
+    });
+    let _ = fs::remove_file(&index);
+    tree
+}
+
+/// The latest commit, if any.
+fn head(base: &Path) -> Option<String> {
+    let history = history_path(base);
+    git(
+        [
+            OsStr::new("--git-dir"),
+            history.as_os_str(),
+            OsStr::new("rev-parse"),
+            OsStr::new("--verify"),
+            OsStr::new("-q"),
+            OsStr::new("HEAD"),
+        ],
+        None,
+    )
+    .ok()
+}
+
+/// The full id of the commit `id` names.
+fn resolve(base: &Path, id: &str) -> Result<String, Error> {
+    let history = history_path(base);
+    let spec = format!("{id}^{{commit}}");
+    git(
+        [
+            OsStr::new("--git-dir"),
+            history.as_os_str(),
+            OsStr::new("rev-parse"),
+            OsStr::new("--verify"),
+            OsStr::new("-q"),
+            OsStr::new(&spec),
+        ],
+        None,
+    )
+    .map_err(|_| Error::HistoryUnknown(id.to_string()))
+}
+
+/// The configuration files under `base`, relative to it: `gitcontrol.cfg`,
+/// `gitcontrol.d/` and the files included from inside `base`.
+fn config_files(base: &Path) -> Vec<PathBuf> {
+    let cfg_path = config_path(base);
+    let mut files = Vec::new();
+    if cfg_path.is_file() {
+        files.push(PathBuf::from("gitcontrol.cfg"));
+    }
+    if base.join(CONF_DIR).is_dir() {
+        files.push(PathBuf::from(CONF_DIR));
+    }
+    let included: Vec<PathBuf> = match Config::load(&cfg_path) {
+        Ok(cfg) => cfg
+            .files()
+            .iter()
+            .skip(1)
+            .map(|file| PathBuf::from(cfg.file_name(file)))
+            .collect(),
+        // a config that does not parse is recorded all the same, with the
+        // files recorded last time
+        Err(_) => tracked(base).into_iter().map(PathBuf::from).collect(),
+    };
+    for name in included {
+        let inside = name.components().all(|c| matches!(c, Component::Normal(_)));
+        if inside
+            && !files.contains(&name)
+            && !name.starts_with(CONF_DIR)
+            && base.join(&name).is_file()
+        {
+            files.push(name);
+        }
+    }
+    files
+}
+
+/// The files in the latest commit.
+fn tracked(base: &Path) -> Vec<String> {
+    let history = history_path(base);
+    git(
+        [
+            OsStr::new("--git-dir"),
+            history.as_os_str(),
+            OsStr::new("ls-tree"),
+            OsStr::new("-r"),
+            OsStr::new("--name-only"),
+            OsStr::new("HEAD"),
+        ],
+        None,
+    )
+    .map(|out| out.lines().map(str::to_string).collect())
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn records_and_restores() {
+        let base = TestDir::new("history");
+        fs::write(config_path(&base), "@alice\nr web/site\ninclude ops.cfg\n").unwrap();
+        fs::write(base.join("ops.cfg"), "@carol\n").unwrap();
+
+        let first = record(&base, "root", "initial").unwrap().unwrap();
+        assert_eq!(record(&base, "root", "again").unwrap(), None);
+
+        fs::write(config_path(&base), "@alice\nw web/site\ninclude ops.cfg\n").unwrap();
+        recorded(
+            &base,
+            "alice",
+            "gitcontrol repo grant alice web/site",
+            || {
+                fs::create_dir_all(base.join(CONF_DIR))?;
+                fs::write(base.join("gitcontrol.d/bob.cfg"), "@bob\n")?;
+                Ok(())
+            },
+        )
+        .unwrap();
+        let log = entries(&base).unwrap();
+        let logged: Vec<_> = log
+            .iter()
+            .map(|e| (e.operator.as_str(), e.command.as_str()))
+            .collect();
+        assert_eq!(
+            logged,
+            [
+                ("alice", "gitcontrol repo grant alice web/site"),
+                ("alice", OUTSIDE),
+                ("root", "initial")
+            ]
+        );
+        assert!(show(&base, &log[0].id[..12]).unwrap().contains("+@bob"));
+
+        // a broken state is recorded, but not restored
+        fs::write(base.join("ops.cfg"), "@carol\nw x\n").unwrap();
+        let broken = record(&base, "root", "broken").unwrap().unwrap();
+        assert!(show(&base, &broken).unwrap().contains("+w x"));
+        assert!(restore(&base, &broken).is_err());
+
+        let patch = preview(&base, &first).unwrap();
+        assert!(patch.contains("-w x") && patch.contains("-@bob"), "{patch}");
+        assert_eq!(
+            restore(&base, &first).unwrap(),
+            ["gitcontrol.cfg", "gitcontrol.d/bob.cfg", "ops.cfg"]
+        );
+        assert_eq!(
+            fs::read_to_string(config_path(&base)).unwrap(),
+            "@alice\nr web/site\ninclude ops.cfg\n"
+        );
+        assert_eq!(
+            fs::read_to_string(base.join("ops.cfg")).unwrap(),
+            "@carol\n"
+        );
+        assert!(!base.join("gitcontrol.d/bob.cfg").exists());
+        assert!(matches!(
+            restore(&base, "nope"),
+            Err(Error::HistoryUnknown(_))
+        ));
+    }
+}
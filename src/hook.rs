@@ -0,0 +1,154 @@
+//! Support for `gitcontrol-hook`, the `update` hook enforcing ref rules and
+//! the force-push (`+`) and delete (`d`) permissions.
+//!
+//! `gitcontrol-shell` passes the pushing user, the repository and the base
+//! directory to `git-receive-pack` in the environment; git hands them on to
+//! the hook, which is called once per updated ref with the old and new
+//! object names, works out the kind of update from them with [`classify`],
+//! and decides with [`crate::policy::decide_ref`].
+//!
+//! The same program is installed as the `post-receive` hook, telling the two
+//! apart by the name git runs it under; see [`crate::adminrepo`].
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::process::Command;
+
+use crate::errors::Error;
+use crate::policy::RefUpdate;
+
+/// The user pushing, as authenticated by the shell.
+pub const ENV_USER: &str = "GITCONTROL_USER";
+/// The repository pushed to, as `dir/repo`.
+pub const ENV_REPO: &str = "GITCONTROL_REPO";
+/// The base directory holding `gitcontrol.cfg`.
+pub const ENV_BASE: &str = "GITCONTROL_BASE";
+
+pub const DEFAULT_HOOK: &str = "/usr/bin/gitcontrol-hook";
+
+/// The hooks [`install`] links to `gitcontrol-hook`.
+pub const HOOKS: [&str; 2] = ["update", "post-receive"];
+
+/// true for the all-zero object name git uses for a missing ref
+pub fn is_zero(sha: &str) -> bool {
+    !sha.is_empty() && sha.bytes().all(|b| b == b'0')
+}
+
+/// What updating a ref from `old` to `new` does, run in the repository being
+/// pushed to (the hook's working directory).
+pub fn classify(old: &str, new: &str) -> Result<RefUpdate, Error> {
+    if is_zero(old) {
+        return Ok(RefUpdate::Create);
+    }
+    if is_zero(new) {
+        return Ok(RefUpdate::Delete);
+    }
+    let status = Command::new("git")
+        .args(["merge-base", "--is-ancestor", old, new])
+        .status()
+        .map_err(|e| Error::CommandFailed(format!("git merge-base: {e}")))?;
+    match status.code() {
+        Some(0) => Ok(RefUpdate::FastForward),
+        Some(1) => Ok(RefUpdate::Rewind),
+        _ => Err(Error::CommandFailed(format!(
+            "git merge-base exited with {status}"
+        ))),
+    }
+}
+
+/// Install `hook` as the [`HOOKS`] of the bare repository at `repo_path`, as
+/// symlinks. An existing symlink is replaced; any other existing hook of the
+/// same name is left alone and reported as an error.
+pub fn install(repo_path: &Path, hook: &Path) -> Result<(), Error> {
+    let hooks = repo_path.join("hooks");
+    fs::create_dir_all(&hooks)?;
+    for name in HOOKS {
+        let link = hooks.join(name);
+        match fs::symlink_metadata(&link) {
+            Ok(meta) if meta.file_type().is_symlink() => fs::remove_file(&link)?,
+            Ok(_) => {
+                return Err(Error::CommandFailed(format!(
+                    "{} exists and is not a symlink; not replacing it",
+                    link.display()
+                )));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        symlink(hook, &link)?;
+    }
+    Ok(())
+}
+
+/// What is wrong with the [`HOOKS`] of the repository at `repo_path`, if
+/// they are not links to `hook` as [`install`] makes them, e.g. in a
+/// repository created before the hook existed.
+pub fn problem(repo_path: &Path, hook: &Path) -> Option<String> {
+    for name in HOOKS {
+        let link = repo_path.join("hooks").join(name);
+        match fs::read_link(&link) {
+            Ok(target) if target == hook => {}
+            Ok(target) => {
+                return Some(format!(
+                    "hooks/{name} links to {}, not {}",
+                    target.display(),
+                    hook.display()
+                ));
+            }
+            Err(_) if link.exists() => {
+                return Some(format!("hooks/{name} is not a link to {}", hook.display()));
+            }
+            Err(_) => return Some(format!("hooks/{name} is missing")),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn zero_shas_create_and_delete() {
+        let zero = "0000000000000000000000000000000000000000";
+        let sha = "1f7a7a472abf3dd9643fd615f6da379c4acb3e3a";
+        assert!(is_zero(zero));
+        assert!(!is_zero(sha));
+        assert!(!is_zero(""));
+        assert_eq!(classify(zero, sha).unwrap(), RefUpdate::Create);
+        assert_eq!(classify(sha, zero).unwrap(), RefUpdate::Delete);
+    }
+
+    #[test]
+    fn install_replaces_symlinks_only() {
+        let repo = TestDir::new("hook");
+        assert_eq!(
+            problem(&repo, Path::new("/usr/bin/one")).as_deref(),
+            Some("hooks/update is missing")
+        );
+        install(&repo, Path::new("/usr/bin/one")).unwrap();
+        assert_eq!(problem(&repo, Path::new("/usr/bin/one")), None);
+        install(&repo, Path::new("/usr/bin/two")).unwrap();
+        assert_eq!(
+            problem(&repo, Path::new("/usr/bin/one")).as_deref(),
+            Some("hooks/update links to /usr/bin/two, not /usr/bin/one")
+        );
+        let update = repo.join("hooks/update");
+        assert_eq!(fs::read_link(&update).unwrap(), Path::new("/usr/bin/two"));
+        assert_eq!(
+            fs::read_link(repo.join("hooks/post-receive")).unwrap(),
+            Path::new("/usr/bin/two")
+        );
+
+        fs::remove_file(&update).unwrap();
+        fs::write(&update, "#!/bin/sh\n").unwrap();
+        assert!(install(&repo, Path::new("/usr/bin/two")).is_err());
+        assert_eq!(
+            problem(&repo, Path::new("/usr/bin/two")).as_deref(),
+            Some("hooks/update is not a link to /usr/bin/two")
+        );
+    }
+}
@@ -1,5 +1,19 @@
+pub mod admin;
+pub mod adminrepo;
+pub mod apply;
+pub mod audit;
 pub mod authkeys;
+pub mod check;
 pub mod config;
+pub mod diff;
 pub mod errors;
+pub mod history;
+pub mod hook;
+pub mod lock;
+pub mod policy;
+pub mod repos;
+pub mod state;
+#[cfg(test)]
+mod testdir;
 pub mod types;
 pub mod userdb;
@@ -0,0 +1,96 @@
+//! Advisory locks around read-modify-write cycles of shared files.
+//!
+//! `gitcontrol`, the shell's admin and `keys` commands, push-to-create and
+//! the admin repository's hook all load a file, change it and write it back.
+//! Two of them running at once would silently lose one change, so each holds
+//! a lock for the whole cycle: `flock` on a `<file>.lock` next to the file,
+//! released when the [`Lock`] is dropped (or the process dies).
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::Error;
+
+/// How long to wait for another process to finish its edit.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL: Duration = Duration::from_millis(50);
+
+/// A held lock.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+/// Lock `path` (e.g. `gitcontrol.cfg`), waiting up to [`TIMEOUT`].
+pub fn lock(path: &Path) -> Result<Lock, Error> {
+    lock_within(path, TIMEOUT)
+}
+
+/// Lock `path`, waiting up to `timeout` for whoever holds it.
+pub fn lock_within(path: &Path, timeout: Duration) -> Result<Lock, Error> {
+    let lock_path = lock_path(path);
+    if let Some(parent) = lock_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = match OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+    {
+        // created by another user, e.g. root running gitcontrol: flock
+        // needs no write access
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => File::open(&lock_path)?,
+        file => file?,
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(Lock { _file: file }),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(POLL),
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::Locked(format!(
+                    "{}: still held by another process after {}s",
+                    lock_path.display(),
+                    timeout.as_secs()
+                )));
+            }
+        }
+    }
+}
+
+/// `<path>.lock`.
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn waits_then_gives_up() {
+        let dir = TestDir::new("lock");
+        let path = dir.join("gitcontrol.cfg");
+
+        let held = lock(&path).unwrap();
+        assert!(dir.join("gitcontrol.cfg.lock").exists());
+        let start = Instant::now();
+        match lock_within(&path, Duration::from_millis(200)) {
+            Err(Error::Locked(msg)) => assert!(msg.contains("gitcontrol.cfg.lock"), "{msg}"),
+            other => panic!("{other:?}"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        drop(held);
+        lock_within(&path, Duration::ZERO).unwrap();
+    }
+}
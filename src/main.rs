@@ -1,12 +1,26 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Read};
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::{env, process};
 
+use gitcontrol_shell::admin;
+use gitcontrol_shell::adminrepo;
+use gitcontrol_shell::audit::{self, Timestamp};
+use gitcontrol_shell::authkeys;
+use gitcontrol_shell::config::Config;
 use gitcontrol_shell::errors::Error;
-use gitcontrol_shell::types::{Repo, User};
+use gitcontrol_shell::history;
+use gitcontrol_shell::hook;
+use gitcontrol_shell::lock;
+use gitcontrol_shell::policy::{Operation, decide, decide_create, needs_update_hook};
+use gitcontrol_shell::repos::{self, repos_on_disk};
+use gitcontrol_shell::types::{GitProtocol, Repo, User};
 use gitcontrol_shell::userdb::{UserDb, read_db};
 
+#[derive(Clone, Copy)]
 enum ErrorCode {
     FailedReadingCmdArgs = 1,
     NoHomeEnvironment = 2,
@@ -17,6 +31,11 @@ enum ErrorCode {
     UnknownGitCommand = 7,
     PermissionCheckFailed = 8,
     CannotReadDbFile = 9,
+    InvalidGitProtocol = 10,
+    CreatingRepositoryFailed = 11,
+    AdminCommandFailed = 12,
+    KeyCommandFailed = 13,
+    HookMissing = 14,
 }
 
 impl ErrorCode {
@@ -31,6 +50,11 @@ impl ErrorCode {
             ErrorCode::UnknownGitCommand => "unknown git command",
             ErrorCode::PermissionCheckFailed => "Permission insufficient",
             ErrorCode::CannotReadDbFile => "cannot read db file",
+            ErrorCode::InvalidGitProtocol => "invalid GIT_PROTOCOL",
+            ErrorCode::CreatingRepositoryFailed => "creating repository failed",
+            ErrorCode::AdminCommandFailed => "admin command failed",
+            ErrorCode::KeyCommandFailed => "key command failed",
+            ErrorCode::HookMissing => "update hook not installed",
         }
     }
 
@@ -59,61 +83,110 @@ fn fail_optional<A>(e: Option<A>, s: ErrorCode) -> A {
     }
 }
 
+/// Records the outcome of a connection in the access log.
+struct Audit {
+    path: PathBuf,
+    user: User,
+    command: String,
+    source: Option<String>,
+}
+
+impl Audit {
+    fn new(home: &Path, user: &User, cmd_str: &str) -> Self {
+        Audit {
+            path: audit::log_path(home),
+            user: user.clone(),
+            command: cmd_str.split_whitespace().next().unwrap_or("").to_string(),
+            source: env::var("SSH_CONNECTION")
+                .ok()
+                .and_then(|s| audit::source_of_ssh_connection(&s)),
+        }
+    }
+
+    fn record(&self, repo: Option<&str>, code: Option<ErrorCode>) {
+        let entry = audit::Entry {
+            timestamp: Timestamp::now(),
+            user: self.user.to_string(),
+            command: self.command.clone(),
+            repo: repo.map(|r| r.to_string()),
+            allowed: code.is_none(),
+            source: self.source.clone(),
+            code: code.map(|c| c as i32),
+        };
+        // never turn away a client because the log is unwritable
+        if let Err(e) = audit::append(&self.path, &entry) {
+            eprintln!("warning: cannot write access log: {}", e);
+        }
+    }
+
+    fn allow(&self, repo: Option<&str>) {
+        self.record(repo, None)
+    }
+
+    /// Like [`fail`], recording the denial before exiting.
+    fn fail<A>(&self, e: Result<A, Error>, repo: Option<&str>, s: ErrorCode) -> A {
+        if e.is_err() {
+            self.record(repo, Some(s));
+        }
+        fail(e, s)
+    }
+
+    fn deny(&self, repo: Option<&str>, s: ErrorCode, msg: &str) -> ! {
+        self.record(repo, Some(s));
+        eprintln!("{}", msg);
+        s.exit()
+    }
+}
+
 const GIT_RECEIVE_PACK: &str = "git-receive-pack ";
 const GIT_UPLOAD_PACK: &str = "git-upload-pack ";
+const GIT_UPLOAD_ARCHIVE: &str = "git-upload-archive ";
+const INFO: &str = "info";
+const ADMIN: &str = "admin";
+const KEYS: &str = "keys";
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum GitCommand {
     GitReceivePack(Repo),
     GitUploadPack(Repo),
+    GitUploadArchive(Repo),
 }
 
 impl GitCommand {
-    pub fn check_permission(&self, db: &UserDb) -> Result<(), Error> {
+    pub fn operation(&self) -> Operation {
         match self {
-            GitCommand::GitReceivePack(repo) => {
-                if !db.can_read(repo) {
-                    Err(Error::AccessDenied("no read permission"))
-                } else {
-                    Ok(())
-                }
-            }
-            GitCommand::GitUploadPack(repo) => {
-                if !db.can_write(repo) {
-                    Err(Error::AccessDenied("no write permission"))
-                } else {
-                    Ok(())
-                }
-            }
+            GitCommand::GitReceivePack(_) => Operation::Push,
+            GitCommand::GitUploadPack(_) => Operation::Fetch,
+            GitCommand::GitUploadArchive(_) => Operation::Archive,
         }
     }
 
-    pub fn execute(&self, home: PathBuf) {
-        let mut command = match self {
-            GitCommand::GitReceivePack(_) => Command::new("git-receive-pack"),
-            GitCommand::GitUploadPack(_) => Command::new("git-upload-pack"),
-        };
+    pub fn repo(&self) -> &Repo {
         match self {
-            GitCommand::GitReceivePack(repo) => command.args(&[repo.to_path(&home)]),
-            GitCommand::GitUploadPack(repo) => command.args(&[repo.to_path(&home)]),
-        };
+            GitCommand::GitReceivePack(repo) => repo,
+            GitCommand::GitUploadPack(repo) => repo,
+            GitCommand::GitUploadArchive(repo) => repo,
+        }
+    }
+
+    pub fn execute(&self, home: &Path, path: &Path, user: &User, protocol: Option<GitProtocol>) {
+        let mut command = Command::new(self.operation().git_command());
+        command.arg(path);
+        // for gitcontrol-hook, run by git-receive-pack
+        command.env(hook::ENV_USER, user.as_str());
+        command.env(hook::ENV_REPO, self.repo().to_string());
+        command.env(hook::ENV_BASE, home);
+        // only ever hand git a GIT_PROTOCOL we validated ourselves
+        command.env_remove("GIT_PROTOCOL");
+        if let Some(protocol) = protocol {
+            command.env("GIT_PROTOCOL", protocol.as_str());
+        }
         let e = command.exec();
         fail::<()>(Err(e.into()), ErrorCode::ExecutingCommandFailed)
     }
 }
 
-/*
-fn read_git_control(repo: PathBuf) -> process::Output {
-    const GIT_DIR_PREFIX : &'static str = "--git-dir=";
-    let mut opt = String::new();
-    opt.push_str(GIT_DIR_PREFIX);
-    opt.push_str(repo.to_str().unwrap());
-    Command::new("git").args(&["show", "master:gitcontrol.cfg"])
-                       .output()
-                       .expect("failed to execute process")
-}
-*/
-
 fn repository_of_path(s: &str) -> Result<Repo, Error> {
     if s.starts_with('\'') && s.ends_with('\'') {
         Repo::from_string(s[1..(s.len() - 1)].into())
@@ -122,6 +195,16 @@ fn repository_of_path(s: &str) -> Result<Repo, Error> {
     }
 }
 
+/// The client's `GIT_PROTOCOL` (forwarded by sshd when configured with
+/// `AcceptEnv GIT_PROTOCOL`), if any.
+fn git_protocol() -> Result<Option<GitProtocol>, Error> {
+    match env::var("GIT_PROTOCOL") {
+        Ok(s) => GitProtocol::from_string(s).map(Some),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
 fn gitcontrol_config_path(home: &Path) -> PathBuf {
     let mut config_path = PathBuf::new();
     config_path.push(home);
@@ -136,31 +219,348 @@ pub enum Mode {
 
 fn normal(user: User) {
     let home = fail_optional(env::home_dir(), ErrorCode::NoHomeEnvironment);
+    let cmd_str = match env::var("SSH_ORIGINAL_COMMAND") {
+        Ok(s) => Ok(s),
+        // a plain `ssh git@host` has no command: greet them with `info`
+        Err(env::VarError::NotPresent) => Ok(INFO.to_string()),
+        Err(e) => Err(e),
+    };
+    let audit = Audit::new(&home, &user, cmd_str.as_deref().unwrap_or(""));
+    let cmd_str = audit.fail(
+        cmd_str.map_err(|e| e.into()),
+        None,
+        ErrorCode::NoSshOriginalCommand,
+    );
+
     let config_path = gitcontrol_config_path(&home);
-    let db = fail(read_db(&config_path, user), ErrorCode::CannotReadDbFile);
 
-    if db.is_empty() {
-        eprintln!("user not found (or empty)");
-        ErrorCode::UserNotFound.exit()
+    if let Some(args) = command_arguments(&cmd_str, ADMIN) {
+        run_admin(&home, &config_path, &user, &audit, &args);
+        return;
+    }
+    if let Some(args) = command_arguments(&cmd_str, admin::PERMS) {
+        run_perms(&home, &config_path, &user, &audit, &args);
+        return;
+    }
+    if let Some(args) = command_arguments(&cmd_str, KEYS) {
+        match args.as_slice() {
+            ["list"] | ["add"] | ["remove", _] => audit.allow(None),
+            _ => audit.deny(
+                None,
+                ErrorCode::UnknownGitCommand,
+                "usage: keys list|add|remove <fingerprint>",
+            ),
+        }
+        fail(keys(&home, &user, &args), ErrorCode::KeyCommandFailed);
+        return;
     }
 
-    let cmd_str = fail(
-        env::var("SSH_ORIGINAL_COMMAND").map_err(|e| e.into()),
-        ErrorCode::NoSshOriginalCommand,
+    let db = audit.fail(
+        read_db(&config_path, user.clone()),
+        None,
+        ErrorCode::CannotReadDbFile,
     );
 
+    if db.is_empty() {
+        audit.deny(None, ErrorCode::UserNotFound, "user not found (or empty)")
+    }
+
+    if let Some(args) = command_arguments(&cmd_str, INFO) {
+        let prefix = match args.as_slice() {
+            [] => None,
+            [prefix] => Some(*prefix),
+            _ => audit.deny(None, ErrorCode::UnknownGitCommand, "usage: info [prefix]"),
+        };
+        audit.allow(None);
+        info(&home, &user, &db, prefix);
+        return;
+    }
+
     let cmd = if let Some(s) = cmd_str.strip_prefix(GIT_RECEIVE_PACK) {
-        let repo = fail(repository_of_path(s), ErrorCode::PathOfRepositoryInvalid);
+        let repo = audit.fail(
+            repository_of_path(s),
+            Some(s),
+            ErrorCode::PathOfRepositoryInvalid,
+        );
         GitCommand::GitReceivePack(repo)
     } else if let Some(s) = cmd_str.strip_prefix(GIT_UPLOAD_PACK) {
-        let repo = fail(repository_of_path(s), ErrorCode::PathOfRepositoryInvalid);
+        let repo = audit.fail(
+            repository_of_path(s),
+            Some(s),
+            ErrorCode::PathOfRepositoryInvalid,
+        );
         GitCommand::GitUploadPack(repo)
+    } else if let Some(s) = cmd_str.strip_prefix(GIT_UPLOAD_ARCHIVE) {
+        let repo = audit.fail(
+            repository_of_path(s),
+            Some(s),
+            ErrorCode::PathOfRepositoryInvalid,
+        );
+        GitCommand::GitUploadArchive(repo)
     } else {
-        eprintln!("unknown command {}", cmd_str);
-        ErrorCode::UnknownGitCommand.exit()
+        audit.deny(
+            None,
+            ErrorCode::UnknownGitCommand,
+            &format!("unknown command {}", cmd_str),
+        )
     };
-    fail(cmd.check_permission(&db), ErrorCode::PermissionCheckFailed);
-    cmd.execute(home)
+    let repo = cmd.repo().to_string();
+    // pushing to a missing repository creates it, given a matching C pattern
+    let path = repos::repo_path(&home, db.personal_dir.as_deref(), cmd.repo());
+    let create = cmd.operation() == Operation::Push && !path.exists();
+    let decision = if create {
+        decide_create(&db, cmd.repo())
+    } else {
+        decide(&db, cmd.repo(), cmd.operation())
+    };
+    audit.fail(
+        decision.into_result(),
+        Some(&repo),
+        ErrorCode::PermissionCheckFailed,
+    );
+    // without the hook, git would take any push: refuse rather than let
+    // ref rules or the force-push permission be silently ignored
+    if cmd.operation() == Operation::Push
+        && !create
+        && (needs_update_hook(&db, cmd.repo()) || adminrepo::is_admin_repo(cmd.repo()))
+        && let Some(problem) = hook::problem(&path, &hook_path())
+    {
+        audit.deny(
+            Some(&repo),
+            ErrorCode::HookMissing,
+            &format!("{repo}: {problem}; ask an admin to run `gitcontrol install-hooks`"),
+        )
+    }
+    let protocol = audit.fail(git_protocol(), Some(&repo), ErrorCode::InvalidGitProtocol);
+    if create {
+        audit.fail(
+            create_repository(&path, &home, &user, cmd.repo()),
+            Some(&repo),
+            ErrorCode::CreatingRepositoryFailed,
+        );
+        eprintln!("created {repo}");
+    }
+    audit.allow(Some(&repo));
+    cmd.execute(&home, &path, &user, protocol)
+}
+
+/// Initialise `repo` at `path` and record `user` as its owner, unless it is
+/// in their personal namespace. If the config cannot be updated the
+/// repository is removed again, rather than left without owner.
+fn create_repository(path: &Path, home: &Path, user: &User, repo: &Repo) -> Result<(), Error> {
+    repos::init_bare(path, &hook_path())?;
+    if repo.personal_owner().is_some() {
+        return Ok(());
+    }
+    let config_path = gitcontrol_config_path(home);
+    let command = format!("create {repo} by pushing");
+    let recorded = lock::lock(&config_path).and_then(|_lock| {
+        history::recorded(home, user.as_str(), &command, || {
+            let mut cfg = Config::load(&config_path)?;
+            cfg.set_owner(repo.clone(), user);
+            cfg.save(&config_path).map(|_| ())
+        })
+    });
+    if recorded.is_err() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+    recorded
+}
+
+/// `perms <dir/repo|dir/*> ...` for the owners of that repository or
+/// directory (or admins).
+fn run_perms(home: &Path, config_path: &Path, user: &User, audit: &Audit, args: &[&str]) {
+    let Some(target) = args.first() else {
+        audit.deny(
+            None,
+            ErrorCode::UnknownGitCommand,
+            "usage: perms <dir/repo|dir/*> list|grant <who> <perm>|revoke <who>",
+        )
+    };
+    let repo = audit.fail(
+        Repo::from_ownable(target.to_string()),
+        Some(target),
+        ErrorCode::PathOfRepositoryInvalid,
+    );
+    let cfg = audit.fail(
+        Config::load(config_path),
+        Some(target),
+        ErrorCode::CannotReadDbFile,
+    );
+    if !cfg.owns(user, &repo) && !cfg.is_admin(user) {
+        audit.fail(
+            Err::<(), _>(Error::AccessDenied("only owners can manage access to it")),
+            Some(target),
+            ErrorCode::PermissionCheckFailed,
+        );
+    }
+    audit.allow(Some(target));
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    fail(
+        admin::run_from_shell(
+            &admin_options(home, user, &format!("{} {}", admin::PERMS, args.join(" "))),
+            admin::PERMS,
+            &args,
+        ),
+        ErrorCode::AdminCommandFailed,
+    )
+}
+
+/// Admin commands run as the git user: what they create is left owned by it.
+/// The history records `user` running `command`.
+fn admin_options(home: &Path, user: &User, command: &str) -> admin::Options {
+    admin::Options {
+        base: home.to_path_buf(),
+        owner: String::new(),
+        chown: false,
+        hook: hook_path(),
+        operator: user.to_string(),
+        command: command.to_string(),
+        dry_run: false,
+    }
+}
+
+/// `keys list|add|remove <fingerprint>`: manage the user's own key file,
+/// `<home>/users/<user>`, regenerating `authorized_keys` after a change. `add`
+/// reads the key from stdin.
+fn keys(home: &Path, user: &User, args: &[&str]) -> Result<(), Error> {
+    let users_dir = home.join("users");
+    let authorized_keys = home.join(".ssh").join("authorized_keys");
+    let _lock = lock::lock(&authorized_keys)?;
+    let mut entries = authkeys::load_user_keys(&users_dir)?;
+    let (done, fingerprint) = match args {
+        ["list"] => {
+            let contents = entries
+                .iter()
+                .find(|(u, _)| u == user)
+                .map(|(_, c)| c.as_str())
+                .unwrap_or_default();
+            for (fingerprint, line) in authkeys::keys_in(contents)? {
+                let mut words = line.split_whitespace();
+                let kind = words.next().unwrap_or_default();
+                let comment: Vec<&str> = words.skip(1).collect();
+                println!("{fingerprint} {kind} {}", comment.join(" "));
+            }
+            return Ok(());
+        }
+        ["add"] => {
+            let mut key = String::new();
+            io::stdin().read_to_string(&mut key)?;
+            ("added", authkeys::add_key(&mut entries, user, &key)?)
+        }
+        ["remove", fingerprint] => {
+            // needs `ExposeAuthInfo yes` in sshd_config; without it nothing
+            // can be removed
+            let connected = env::var("SSH_USER_AUTH")
+                .ok()
+                .and_then(|path| fs::read_to_string(path).ok())
+                .and_then(|info| authkeys::connected_key(&info));
+            authkeys::remove_key(&mut entries, user, fingerprint, connected.as_deref())?;
+            ("removed", fingerprint.to_string())
+        }
+        _ => return Err(Error::UsageInvalid(args.join(" "))),
+    };
+    authkeys::save_user_keys(&users_dir, &entries, user, &authorized_keys, &shell_path())?;
+    println!("{done} {fingerprint}");
+    Ok(())
+}
+
+/// This shell, forced again on every key of the regenerated
+/// `authorized_keys`.
+fn shell_path() -> String {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.to_str().map(str::to_string))
+        .unwrap_or_else(|| authkeys::DEFAULT_SHELL.to_string())
+}
+
+/// `gitcontrol-hook` installed alongside this shell, or the default one.
+fn hook_path() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("gitcontrol-hook")))
+        .filter(|hook| hook.is_file())
+        .unwrap_or_else(|| PathBuf::from(hook::DEFAULT_HOOK))
+}
+
+/// The arguments of `command` (`info`, `admin`) if that is what `cmd_str`
+/// asks for, or `None` for anything else.
+fn command_arguments<'a>(cmd_str: &'a str, command: &str) -> Option<Vec<&'a str>> {
+    let mut words = cmd_str.split_whitespace();
+    if words.next() != Some(command) {
+        return None;
+    }
+    Some(words.collect())
+}
+
+/// Run `admin <command> <args>...` for an admin named in the config.
+fn run_admin(home: &Path, config_path: &Path, user: &User, audit: &Audit, args: &[&str]) {
+    let cfg = audit.fail(Config::load(config_path), None, ErrorCode::CannotReadDbFile);
+    if !cfg.is_admin(user) {
+        audit.fail(
+            Err::<(), _>(Error::AccessDenied("admin commands are for admins only")),
+            None,
+            ErrorCode::PermissionCheckFailed,
+        );
+    }
+    let Some((cmd, rest)) = args
+        .split_first()
+        .filter(|(cmd, _)| admin::SHELL_COMMANDS.contains(cmd))
+    else {
+        audit.deny(
+            None,
+            ErrorCode::UnknownGitCommand,
+            &format!("usage: admin {} ...", admin::SHELL_COMMANDS.join("|")),
+        )
+    };
+    audit.allow(None);
+    let rest: Vec<String> = rest.iter().map(|a| a.to_string()).collect();
+    fail(
+        admin::run_from_shell(
+            &admin_options(home, user, &format!("{ADMIN} {}", args.join(" "))),
+            cmd,
+            &rest,
+        ),
+        ErrorCode::AdminCommandFailed,
+    )
+}
+
+/// Print the repositories the user can reach, gitolite style, optionally
+/// restricted to those starting with `prefix`. Patterns the user may create
+/// repositories under come first, marked `C`.
+fn info(home: &Path, user: &User, db: &UserDb, prefix: Option<&str>) {
+    println!(
+        "hello {user}, this is gitcontrol-shell {}",
+        env!("CARGO_PKG_VERSION")
+    );
+    println!();
+
+    // exact grants, plus whatever exists on disk that a pattern may cover
+    let mut repos: BTreeSet<Repo> = db
+        .repos
+        .keys()
+        .filter(|r| !r.is_pattern())
+        .cloned()
+        .collect();
+    match repos_on_disk(home, db.personal_dir.as_deref()) {
+        Ok(on_disk) => repos.extend(on_disk),
+        Err(e) => eprintln!("warning: cannot list repositories: {}", e),
+    }
+
+    for pattern in &db.creates {
+        let name = pattern.to_string();
+        if prefix.is_none_or(|p| name.starts_with(p)) {
+            println!(" C  \t{name}");
+        }
+    }
+    for repo in repos {
+        let name = repo.to_string();
+        if prefix.is_some_and(|p| !name.starts_with(p)) || !db.can_read(&repo) {
+            continue;
+        }
+        let w = if db.can_write(&repo) { 'W' } else { ' ' };
+        println!(" R {w}\t{name}");
+    }
 }
 
 fn debug(config_path: PathBuf, ouser: Option<User>) {
@@ -179,11 +579,11 @@ fn debug(config_path: PathBuf, ouser: Option<User>) {
 
 fn parse_argument(args: &[String]) -> Result<Mode, Error> {
     if args.len() < 2 {
-        return Err(Error::UsageInvalid("no arguments"));
+        return Err(Error::UsageInvalid("no arguments".to_string()));
     }
     if args[1] == "--debug" {
         if args.len() == 2 {
-            return Err(Error::UsageInvalid("no arguments"));
+            return Err(Error::UsageInvalid("no arguments".to_string()));
         }
         let mut cfg = PathBuf::new();
         cfg.push(args[2].clone());
@@ -196,7 +596,7 @@ fn parse_argument(args: &[String]) -> Result<Mode, Error> {
         }
     } else {
         if args.len() != 2 {
-            return Err(Error::UsageInvalid("too many arguments"));
+            return Err(Error::UsageInvalid("too many arguments".to_string()));
         }
         let user = User::from_string(args[1].clone())?;
         Ok(Mode::Normal(user))
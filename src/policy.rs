@@ -0,0 +1,379 @@
+//! Access decisions for operations requested over SSH.
+//!
+//! Every git command the shell serves maps to an [`Operation`], and every
+//! operation requires a single [`Permission`]. [`decide`] is the one place
+//! that answers "may this user do this to that repository", so the shell and
+//! the admin tool cannot disagree about it.
+//!
+//! Pushes are then checked ref by ref, by `gitcontrol-hook` calling
+//! [`decide_ref`] with the kind of [`RefUpdate`] being made.
+
+use std::fmt;
+
+use crate::errors::Error;
+use crate::types::{Permission, Repo, resolve_ref};
+use crate::userdb::UserDb;
+
+/// What a client is trying to do to a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// `git fetch` / `git clone` (served by `git-upload-pack`)
+    Fetch,
+    /// `git push` (served by `git-receive-pack`)
+    Push,
+    /// `git archive --remote` (served by `git-upload-archive`)
+    Archive,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 3] = [Operation::Fetch, Operation::Push, Operation::Archive];
+
+    /// The permission a user needs on the repository for this operation.
+    pub fn required_permission(self) -> Permission {
+        match self {
+            Operation::Fetch => Permission::Read,
+            Operation::Push => Permission::Write,
+            Operation::Archive => Permission::Read,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Fetch => "fetch",
+            Operation::Push => "push",
+            Operation::Archive => "archive",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        Operation::ALL.into_iter().find(|op| op.as_str() == s)
+    }
+
+    /// The git program serving this operation.
+    pub fn git_command(self) -> &'static str {
+        match self {
+            Operation::Fetch => "git-upload-pack",
+            Operation::Push => "git-receive-pack",
+            Operation::Archive => "git-upload-archive",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    /// Denied, with a short reason suitable for showing to the client.
+    Deny(&'static str),
+}
+
+impl Decision {
+    pub fn is_allowed(self) -> bool {
+        self == Decision::Allow
+    }
+
+    pub fn into_result(self) -> Result<(), Error> {
+        match self {
+            Decision::Allow => Ok(()),
+            Decision::Deny(reason) => Err(Error::AccessDenied(reason)),
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Decision::Allow => write!(f, "allowed"),
+            Decision::Deny(reason) => write!(f, "denied ({reason})"),
+        }
+    }
+}
+
+/// Decide whether the user described by `user` may perform `operation` on
+/// `repo`.
+pub fn decide(user: &UserDb, repo: &Repo, operation: Operation) -> Decision {
+    if let Some((_, Permission::Deny)) = user.permission(repo) {
+        return Decision::Deny("access explicitly denied");
+    }
+    match operation.required_permission() {
+        Permission::Deny => Decision::Deny("access explicitly denied"),
+        Permission::Read => {
+            if user.can_read(repo) {
+                Decision::Allow
+            } else {
+                Decision::Deny("no read permission")
+            }
+        }
+        Permission::Write | Permission::Force | Permission::Delete => {
+            if user.can_write(repo) {
+                Decision::Allow
+            } else {
+                Decision::Deny("no write permission")
+            }
+        }
+    }
+}
+
+/// Decide whether the user may create `repo` by pushing to it.
+pub fn decide_create(user: &UserDb, repo: &Repo) -> Decision {
+    if let Some((_, Permission::Deny)) = user.permission(repo) {
+        Decision::Deny("access explicitly denied")
+    } else if user.can_create(repo) {
+        Decision::Allow
+    } else {
+        Decision::Deny("no create permission")
+    }
+}
+
+/// How a push changes a single ref.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefUpdate {
+    Create,
+    /// the new commit descends from the old one
+    FastForward,
+    /// anything else: a force-push rewriting history
+    Rewind,
+    Delete,
+}
+
+impl RefUpdate {
+    /// The permission a user needs on the ref (or, without ref rules, on the
+    /// repository) for this update.
+    pub fn required_permission(self) -> Permission {
+        match self {
+            RefUpdate::Create | RefUpdate::FastForward => Permission::Write,
+            RefUpdate::Rewind => Permission::Force,
+            RefUpdate::Delete => Permission::Delete,
+        }
+    }
+
+    fn denial(self) -> &'static str {
+        match self {
+            RefUpdate::Create | RefUpdate::FastForward => "no write permission",
+            RefUpdate::Rewind => "rewinding this ref needs force-push (+) permission",
+            RefUpdate::Delete => "deleting this ref needs delete (d) permission",
+        }
+    }
+}
+
+/// Decide whether the user may make `update` to `refname` while pushing to
+/// `repo`.
+///
+/// Without any ref rule for the repository, the permission on the repository
+/// must allow the update. Once a ref rule applies, only refs matching a rule
+/// may be updated, and that rule's permission must allow the update.
+pub fn decide_ref(user: &UserDb, repo: &Repo, refname: &str, update: RefUpdate) -> Decision {
+    let decision = decide(user, repo, Operation::Push);
+    if !decision.is_allowed() {
+        return decision;
+    }
+
+    let mut rules = user.ref_rules(repo).peekable();
+    let permission = if rules.peek().is_none() {
+        user.permission(repo).map(|(_, p)| p)
+    } else {
+        match resolve_ref(rules, refname) {
+            None => return Decision::Deny("no rule allows updating this ref"),
+            Some((_, Permission::Deny)) => return Decision::Deny("updating this ref is denied"),
+            Some((_, p)) => Some(p),
+        }
+    };
+    if permission.is_some_and(|p| p >= update.required_permission()) {
+        Decision::Allow
+    } else {
+        Decision::Deny(update.denial())
+    }
+}
+
+/// true if pushes by `user` to `repo` are only limited as configured while
+/// the update hook runs: a ref rule applies, or the user may not rewrite
+/// history. Without the hook, git would allow any update.
+pub fn needs_update_hook(user: &UserDb, repo: &Repo) -> bool {
+    user.ref_rules(repo).next().is_some()
+        || user
+            .permission(repo)
+            .is_none_or(|(_, p)| p < Permission::Force)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RefPattern;
+    use std::collections::BTreeMap;
+
+    fn repo(s: &str) -> Repo {
+        Repo::from_string(s.to_string()).unwrap()
+    }
+
+    fn db_with(permission: Option<Permission>) -> UserDb {
+        let mut repos = BTreeMap::new();
+        if let Some(p) = permission {
+            repos.insert(repo("dir/repo"), p);
+        }
+        UserDb {
+            repos,
+            refs: Vec::new(),
+            creates: Vec::new(),
+            groups: Vec::new(),
+            personal_dir: None,
+        }
+    }
+
+    #[test]
+    fn every_operation_and_permission_pair() {
+        use Operation::*;
+        let table: &[(Operation, Option<Permission>, bool)] = &[
+            (Fetch, None, false),
+            (Fetch, Some(Permission::Read), true),
+            (Fetch, Some(Permission::Write), true),
+            (Fetch, Some(Permission::Deny), false),
+            (Push, None, false),
+            (Push, Some(Permission::Read), false),
+            (Push, Some(Permission::Write), true),
+            (Push, Some(Permission::Deny), false),
+            (Archive, None, false),
+            (Archive, Some(Permission::Read), true),
+            (Archive, Some(Permission::Write), true),
+            (Archive, Some(Permission::Deny), false),
+        ];
+        for (op, perm, allowed) in table {
+            let decision = decide(&db_with(*perm), &repo("dir/repo"), *op);
+            assert_eq!(decision.is_allowed(), *allowed, "{op} with {perm:?}");
+        }
+    }
+
+    #[test]
+    fn grant_on_other_repo_does_not_leak() {
+        for op in Operation::ALL {
+            let decision = decide(&db_with(Some(Permission::Write)), &repo("dir/other"), op);
+            assert!(!decision.is_allowed(), "{op}");
+        }
+    }
+
+    #[test]
+    fn push_requires_write_and_fetch_read() {
+        assert_eq!(Operation::Push.required_permission(), Permission::Write);
+        assert_eq!(Operation::Fetch.required_permission(), Permission::Read);
+        assert_eq!(Operation::Archive.required_permission(), Permission::Read);
+    }
+
+    fn db_with_refs(repo_grant: Option<Permission>, rules: &[(&str, &str)]) -> UserDb {
+        let mut db = db_with(repo_grant);
+        for (token, pattern) in rules {
+            db.refs.push((
+                repo("dir/repo"),
+                RefPattern::from_string(pattern.to_string()).unwrap(),
+                Permission::from_ref_token(token).unwrap(),
+            ));
+        }
+        db
+    }
+
+    #[test]
+    fn ref_updates_against_rules() {
+        use RefUpdate::*;
+        let main = "refs/heads/main";
+        let feature = "refs/heads/feature/x";
+        let tag = "refs/tags/v1";
+        let rules = [
+            ("w", "refs/heads/main"),
+            ("+", "refs/heads/release/*"),
+            ("d", "refs/heads/feature/*"),
+            ("-", "refs/tags/*"),
+        ];
+        let release = "refs/heads/release/1";
+        // (repository grant, ref rules, ref, update, allowed)
+        type Case<'a> = (
+            Option<Permission>,
+            &'a [(&'a str, &'a str)],
+            &'a str,
+            RefUpdate,
+            bool,
+        );
+        let table: &[Case] = &[
+            // no ref rules: the repository permission decides
+            (Some(Permission::Write), &[], main, Create, true),
+            (Some(Permission::Write), &[], main, FastForward, true),
+            (Some(Permission::Write), &[], main, Rewind, false),
+            (Some(Permission::Write), &[], main, Delete, false),
+            (Some(Permission::Force), &[], main, Rewind, true),
+            (Some(Permission::Force), &[], main, Delete, false),
+            (Some(Permission::Delete), &[], main, Rewind, true),
+            (Some(Permission::Delete), &[], main, Delete, true),
+            (Some(Permission::Read), &[], main, FastForward, false),
+            (None, &[], main, Create, false),
+            // with rules
+            (Some(Permission::Write), &rules, main, FastForward, true),
+            (Some(Permission::Write), &rules, main, Create, true),
+            (Some(Permission::Write), &rules, main, Rewind, false),
+            (Some(Permission::Write), &rules, main, Delete, false),
+            (Some(Permission::Write), &rules, release, Rewind, true),
+            (Some(Permission::Write), &rules, release, Delete, false),
+            (Some(Permission::Write), &rules, feature, Rewind, true),
+            (Some(Permission::Write), &rules, feature, Delete, true),
+            (Some(Permission::Write), &rules, tag, Create, false),
+            (
+                Some(Permission::Write),
+                &rules,
+                "refs/heads/other",
+                FastForward,
+                false,
+            ),
+            // rules alone are enough to push, but a deny still wins
+            (None, &rules, main, FastForward, true),
+            (Some(Permission::Read), &rules, feature, Rewind, true),
+            // the ref rule decides, even below the repository permission
+            (Some(Permission::Delete), &rules, main, Rewind, false),
+            (Some(Permission::Deny), &rules, main, FastForward, false),
+        ];
+        for (grant, rules, refname, update, allowed) in table {
+            let db = db_with_refs(*grant, rules);
+            let decision = decide_ref(&db, &repo("dir/repo"), refname, *update);
+            assert_eq!(
+                decision.is_allowed(),
+                *allowed,
+                "{update:?} {refname} with {grant:?} and {rules:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_force_without_ref_rules_needs_no_hook() {
+        let dir_repo = repo("dir/repo");
+        for (grant, needed) in [
+            (Some(Permission::Write), true),
+            (Some(Permission::Force), false),
+            (Some(Permission::Delete), false),
+            (None, true),
+        ] {
+            assert_eq!(needs_update_hook(&db_with(grant), &dir_repo), needed);
+        }
+        let db = db_with_refs(Some(Permission::Delete), &[("+", "refs/heads/*")]);
+        assert!(needs_update_hook(&db, &dir_repo));
+    }
+
+    #[test]
+    fn create_needs_a_matching_pattern() {
+        let mut db = db_with(Some(Permission::Deny));
+        db.creates
+            .push(Repo::from_pattern("dir/*".to_string()).unwrap());
+        assert!(decide_create(&db, &repo("dir/new")).is_allowed());
+        assert!(!decide_create(&db, &repo("other/new")).is_allowed());
+        // dir/repo is denied
+        assert!(!decide_create(&db, &repo("dir/repo")).is_allowed());
+    }
+
+    #[test]
+    fn operation_names_round_trip() {
+        for op in Operation::ALL {
+            assert_eq!(Operation::from_name(op.as_str()), Some(op));
+        }
+        assert_eq!(Operation::from_name("delete"), None);
+        assert_eq!(Operation::Push.git_command(), "git-receive-pack");
+    }
+}
@@ -0,0 +1,158 @@
+//! The bare repositories present under the base directory.
+//!
+//! Repositories live two levels deep, at `<base>/<dir>/<repo>`, as laid out
+//! by [`Repo::to_path`]. Anything else found there (hidden entries, the
+//! config, key files, names that are not valid repositories, plain
+//! directories that are not git repositories) is ignored.
+//!
+//! Personal namespaces (`~alice/scratch`) are at `<base>/~alice/scratch`
+//! too, unless the config sets a personal directory: they are then at
+//! `<personal>/alice/scratch`, `<personal>` being relative to the base
+//! directory unless absolute.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::errors::Error;
+use crate::hook;
+use crate::types::Repo;
+
+/// Where `repo` lives on disk, given the personal directory configured (if
+/// any).
+pub fn repo_path(base: &Path, personal_dir: Option<&Path>, repo: &Repo) -> PathBuf {
+    match (personal_dir, repo.personal_owner()) {
+        (Some(dir), Some(owner)) => base.join(dir).join(owner.as_str()).join(repo.name()),
+        _ => repo.to_path(base),
+    }
+}
+
+/// Every repository on disk under `base` (and the personal directory, if
+/// configured), sorted.
+pub fn repos_on_disk(base: &Path, personal_dir: Option<&Path>) -> Result<Vec<Repo>, Error> {
+    let mut repos = Vec::new();
+    for dir in read_dirs(base)? {
+        for name in read_dirs(&base.join(&dir))? {
+            let Ok(repo) = Repo::from_string(format!("{dir}/{name}")) else {
+                continue;
+            };
+            // with a personal directory, ~user entries in base are not used
+            if personal_dir.is_some() && repo.personal_owner().is_some() {
+                continue;
+            }
+            if is_bare_repo(&repo.to_path(base)) {
+                repos.push(repo);
+            }
+        }
+    }
+    if let Some(personal_dir) = personal_dir {
+        let root = base.join(personal_dir);
+        for user in read_dirs(&root)? {
+            for name in read_dirs(&root.join(&user))? {
+                let Ok(repo) = Repo::from_string(format!("~{user}/{name}")) else {
+                    continue;
+                };
+                if is_bare_repo(&repo_path(base, Some(personal_dir), &repo)) {
+                    repos.push(repo);
+                }
+            }
+        }
+    }
+    repos.sort();
+    repos.dedup();
+    Ok(repos)
+}
+
+/// true if `path` looks like a bare git repository
+pub fn is_bare_repo(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir()
+}
+
+/// Initialise an empty bare repository at `path`, creating its directory,
+/// and install `hook` as its update hook. Nothing is printed on stdout, which
+/// the shell uses to talk to git.
+pub fn init_bare(path: &Path, hook: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let out = Command::new("git")
+        .args(["init", "--bare", "--quiet"])
+        .arg(path)
+        .output()
+        .map_err(|e| Error::CommandFailed(format!("git init: {e}")))?;
+    if !out.status.success() {
+        return Err(Error::CommandFailed(format!(
+            "git init exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    hook::install(path, hook)
+}
+
+/// Names of the non-hidden directories in `path` (none if it is missing).
+fn read_dirs(path: &Path) -> Result<Vec<String>, Error> {
+    let read = match fs::read_dir(path) {
+        Ok(read) => read,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    for entry in read {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !name.starts_with('.') && fs::metadata(entry.path())?.is_dir() {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn finds_bare_repositories_only() {
+        let base = TestDir::new("repos");
+        for repo in ["web/site", "web/api", "doc/guide"] {
+            let path = base.join(repo);
+            fs::create_dir_all(path.join("objects")).unwrap();
+            fs::write(path.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        }
+        // not repositories
+        fs::create_dir_all(base.join("web/empty")).unwrap();
+        fs::create_dir_all(base.join(".ssh")).unwrap();
+        fs::create_dir_all(base.join("users")).unwrap();
+        fs::write(base.join("gitcontrol.cfg"), "").unwrap();
+
+        let names = |personal_dir: Option<&Path>| -> Vec<String> {
+            repos_on_disk(&base, personal_dir)
+                .unwrap()
+                .iter()
+                .map(|r| r.to_string())
+                .collect()
+        };
+        assert_eq!(names(None), vec!["doc/guide", "web/api", "web/site"]);
+
+        let hook = Path::new("/usr/bin/gitcontrol-hook");
+        let created = Repo::from_string("new/repo".to_string()).unwrap();
+        init_bare(&created.to_path(&base), hook).unwrap();
+        assert!(repos_on_disk(&base, None).unwrap().contains(&created));
+
+        // personal namespaces, in base or in the personal directory
+        let scratch = Repo::from_string("~alice/scratch".to_string()).unwrap();
+        let personal = Path::new("personal");
+        init_bare(&repo_path(&base, None, &scratch), hook).unwrap();
+        assert!(base.join("~alice/scratch").is_dir());
+        assert!(names(None).contains(&"~alice/scratch".to_string()));
+        assert!(!names(Some(personal)).contains(&"~alice/scratch".to_string()));
+        init_bare(&repo_path(&base, Some(personal), &scratch), hook).unwrap();
+        assert!(base.join("personal/alice/scratch").is_dir());
+        assert!(names(Some(personal)).contains(&"~alice/scratch".to_string()));
+    }
+}
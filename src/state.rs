@@ -0,0 +1,462 @@
+//! The desired state `gitcontrol apply` brings the server to: users and
+//! their keys, groups and repositories with their grants, in a small subset
+//! of TOML.
+//!
+//! ```toml
+//! [users.alice]
+//! keys = ["ssh-ed25519 AAAA... alice@laptop"]
+//!
+//! [users.bob]     # no keys: users/bob is left as it is
+//!
+//! [groups.devs]
+//! members = ["alice", "bob"]
+//!
+//! [repos."web/site"]
+//! owner = "alice"
+//! write = ["=devs"]
+//! read = ["carol"]
+//!
+//! [repos."docs/*"]
+//! read = ["=devs"]
+//! ```
+//!
+//! A repository table holds every grant on that name or pattern, one list
+//! per level (`deny`, `read`, `write`, `force`, `delete`), and its owner.
+//! Only tables (`[kind.name]`), strings and arrays of strings are supported.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+use crate::errors::Error;
+use crate::types::{Group, Permission, Repo, Subject, User};
+
+/// The grants on a repository or pattern, and its owner.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RepoState {
+    pub owner: Option<User>,
+    pub grants: BTreeMap<Subject, Permission>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct State {
+    /// Each user's keys, one per line of `users/<user>`; `None` leaves the
+    /// key file alone.
+    pub users: BTreeMap<User, Option<Vec<String>>>,
+    pub groups: BTreeMap<Group, Vec<User>>,
+    pub repos: BTreeMap<Repo, RepoState>,
+}
+
+/// The keys of a repository table naming a level, and the level.
+const LEVELS: [(&str, Permission); 5] = [
+    ("deny", Permission::Deny),
+    ("read", Permission::Read),
+    ("write", Permission::Write),
+    ("force", Permission::Force),
+    ("delete", Permission::Delete),
+];
+
+enum Value {
+    String(String),
+    Array(Vec<String>),
+}
+
+impl State {
+    /// Read the state file at `path`; errors name it.
+    pub fn read(path: &Path) -> Result<State, Error> {
+        let contents = fs::read_to_string(path)?;
+        State::parse(&contents).map_err(|e| match e {
+            Error::StateInvalid(msg) => Error::StateInvalid(format!("{}: {msg}", path.display())),
+            e => e,
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<State, Error> {
+        let mut state = State::default();
+        let mut parser = Parser {
+            chars: contents.chars().peekable(),
+            line: 1,
+        };
+        // the table being filled, and where it started
+        let mut table: Option<(String, String, usize)> = None;
+        let mut seen: Vec<(String, String)> = Vec::new();
+        let mut keys: Vec<String> = Vec::new();
+
+        loop {
+            parser.skip_blank();
+            let line = parser.line;
+            let err = |msg: String| Error::StateInvalid(format!("line {line}: {msg}"));
+            let wrap = |e: Error| match e {
+                Error::StateInvalid(msg) => err(msg),
+                e => err(e.to_string()),
+            };
+            match parser.peek() {
+                None => break,
+                Some('[') => {
+                    parser.bump();
+                    parser.skip_space();
+                    let kind = parser.key()?;
+                    parser.expect('.')?;
+                    let name = parser.key()?;
+                    parser.skip_space();
+                    parser.expect(']')?;
+                    parser.end_of_line()?;
+                    if seen.contains(&(kind.clone(), name.clone())) {
+                        return Err(err(format!("[{kind}.{name}] is defined twice")));
+                    }
+                    seen.push((kind.clone(), name.clone()));
+                    state.add_table(&kind, &name).map_err(wrap)?;
+                    table = Some((kind, name, line));
+                    keys.clear();
+                }
+                Some(_) => {
+                    let key = parser.key()?;
+                    parser.skip_space();
+                    parser.expect('=')?;
+                    parser.skip_space();
+                    let value = parser.value()?;
+                    parser.end_of_line()?;
+                    let Some((kind, name, _)) = &table else {
+                        return Err(err(format!("{key} is outside any table")));
+                    };
+                    if keys.contains(&key) {
+                        return Err(err(format!("{key} is set twice")));
+                    }
+                    keys.push(key.clone());
+                    state.set(kind, name, &key, value).map_err(wrap)?;
+                }
+            }
+        }
+        state.check()?;
+        Ok(state)
+    }
+
+    /// Start the table `[kind.name]`.
+    fn add_table(&mut self, kind: &str, name: &str) -> Result<(), Error> {
+        match kind {
+            "users" => {
+                self.users
+                    .insert(User::from_string(name.to_string())?, None);
+            }
+            "groups" => {
+                self.groups
+                    .insert(Group::from_string(name.to_string())?, Vec::new());
+            }
+            "repos" => {
+                self.repos
+                    .insert(Repo::from_pattern(name.to_string())?, RepoState::default());
+            }
+            _ => {
+                return Err(Error::StateInvalid(format!(
+                    "unknown table [{kind}.{name}] (expected users, groups or repos)"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set `key` of the table `[kind.name]`.
+    fn set(&mut self, kind: &str, name: &str, key: &str, value: Value) -> Result<(), Error> {
+        let invalid = |what: &str| Error::StateInvalid(format!("{key} must be {what}"));
+        let list = |value: Value| match value {
+            Value::Array(items) => Ok(items),
+            Value::String(_) => Err(invalid("a list of strings")),
+        };
+        match (kind, key) {
+            ("users", "keys") => {
+                let user = User::from_string(name.to_string())?;
+                self.users.insert(user, Some(list(value)?));
+            }
+            ("groups", "members") => {
+                let members = list(value)?
+                    .into_iter()
+                    .map(User::from_string)
+                    .collect::<Result<_, _>>()?;
+                self.groups
+                    .insert(Group::from_string(name.to_string())?, members);
+            }
+            ("repos", "owner") => {
+                let Value::String(owner) = value else {
+                    return Err(invalid("a user name"));
+                };
+                let repo = Repo::from_ownable(name.to_string())?;
+                let owner = User::from_string(owner)?;
+                self.repos.entry(repo).or_default().owner = Some(owner);
+            }
+            ("repos", key) => {
+                let Some((_, level)) = LEVELS.iter().find(|(k, _)| *k == key) else {
+                    return Err(Error::StateInvalid(format!(
+                        "unknown key {key} (expected owner, deny, read, write, force or delete)"
+                    )));
+                };
+                let repo = self
+                    .repos
+                    .entry(Repo::from_pattern(name.to_string())?)
+                    .or_default();
+                for who in list(value)? {
+                    let subject = Subject::from_string(who)?;
+                    if repo.grants.insert(subject.clone(), *level).is_some() {
+                        return Err(Error::StateInvalid(format!(
+                            "{subject} is listed twice for {name}"
+                        )));
+                    }
+                }
+            }
+            (kind, key) => {
+                return Err(Error::StateInvalid(format!("unknown key {key} in {kind}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every user and group named must be declared.
+    fn check(&self) -> Result<(), Error> {
+        let user = |user: &User, place: &str| match self.users.contains_key(user) {
+            true => Ok(()),
+            false => Err(Error::StateInvalid(format!(
+                "{place}: {user} is not declared in [users]"
+            ))),
+        };
+        for (group, members) in &self.groups {
+            for member in members {
+                user(member, &format!("groups.{group}"))?;
+            }
+        }
+        for (repo, state) in &self.repos {
+            let place = format!("repos.\"{repo}\"");
+            if let Some(owner) = &state.owner {
+                user(owner, &place)?;
+            }
+            for subject in state.grants.keys() {
+                match subject {
+                    Subject::User(u) => user(u, &place)?,
+                    Subject::Group(g) if !self.groups.contains_key(g) => {
+                        return Err(Error::StateInvalid(format!(
+                            "{place}: {subject} is not declared in [groups]"
+                        )));
+                    }
+                    Subject::Group(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn error(&self, msg: impl std::fmt::Display) -> Error {
+        Error::StateInvalid(format!("line {}: {msg}", self.line))
+    }
+
+    fn expect(&mut self, want: char) -> Result<(), Error> {
+        match self.bump() {
+            Some(c) if c == want => Ok(()),
+            Some('\n') | None => {
+                Err(self.error(format!("expected {want:?} before the end of the line")))
+            }
+            Some(c) => Err(self.error(format!("expected {want:?}, found {c:?}"))),
+        }
+    }
+
+    /// Spaces and tabs.
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+            self.bump();
+        }
+    }
+
+    /// Whitespace, line ends and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.bump();
+                }
+                Some('#') => {
+                    while !matches!(self.peek(), Some('\n') | None) {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Nothing but a comment up to the end of the line.
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_space();
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), Some('\n') | None) {
+                self.bump();
+            }
+        }
+        match self.peek() {
+            Some('\n') | None => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected {c:?}"))),
+        }
+    }
+
+    /// A bare key (letters, digits, `_` and `-`) or a quoted one.
+    fn key(&mut self) -> Result<String, Error> {
+        if self.peek() == Some('"') {
+            return self.string();
+        }
+        let mut key = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || "_-".contains(*c))
+        {
+            key.push(c);
+            self.bump();
+        }
+        match key.is_empty() {
+            true => Err(self.error("expected a key")),
+            false => Ok(key),
+        }
+    }
+
+    /// A basic string, with `\"`, `\\`, `\n` and `\t` escapes.
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.bump() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    c => {
+                        return Err(
+                            self.error(format!("unsupported escape \\{}", c.unwrap_or(' ')))
+                        );
+                    }
+                },
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// A string, or an array of strings which may span lines.
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.bump();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank();
+                    if self.peek() == Some(']') {
+                        self.bump();
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.string()?);
+                    self.skip_blank();
+                    match self.peek() {
+                        Some(',') => {
+                            self.bump();
+                        }
+                        Some(']') => {}
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            _ => Err(self.error("expected a string or a list of strings")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(s: &str) -> User {
+        User::from_string(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parses_the_toml_subset() {
+        let state = State::parse(
+            "# the team\n[users.alice]\nkeys = [\n  \"ssh-ed25519 AAAA a@b\", # laptop\n]\n\n\
+             [users.bob]\n[groups.devs]\nmembers = [\"alice\", \"bob\"]\n\
+             [repos.\"web/site\"]\nowner = \"alice\"\nwrite = [\"=devs\"]\nread = [\"bob\"]\n\
+             [repos.\"docs/*\"]\nread = [\"=devs\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            state.users[&user("alice")],
+            Some(vec!["ssh-ed25519 AAAA a@b".to_string()])
+        );
+        assert_eq!(state.users[&user("bob")], None);
+        let devs = Group::from_string("devs".to_string()).unwrap();
+        assert_eq!(state.groups[&devs], [user("alice"), user("bob")]);
+        let site = &state.repos[&Repo::from_string("web/site".to_string()).unwrap()];
+        assert_eq!(site.owner, Some(user("alice")));
+        assert_eq!(
+            site.grants
+                .iter()
+                .map(|(s, p)| (s.to_string(), *p))
+                .collect::<Vec<_>>(),
+            [
+                ("bob".to_string(), Permission::Read),
+                ("=devs".to_string(), Permission::Write)
+            ]
+        );
+
+        for (bad, msg) in [
+            ("keys = []\n", "line 1: keys is outside any table"),
+            (
+                "[users.alice]\n[users.alice]\n",
+                "line 2: [users.alice] is defined twice",
+            ),
+            (
+                "[users.alice]\nkeys = \"x\"\n",
+                "line 2: keys must be a list of strings",
+            ),
+            (
+                "[users.alice]\nkeys = [\"x\"\n",
+                "line 3: expected ',' or ']'",
+            ),
+            ("[hosts.a]\n", "line 1: unknown table [hosts.a]"),
+            (
+                "[repos.\"web/x\"]\nadmin = []\n",
+                "line 2: unknown key admin",
+            ),
+            (
+                "[repos.\"web/x\"]\nread = [\"bob\"]\n",
+                "repos.\"web/x\": bob is not declared",
+            ),
+            (
+                "[users.bob]\n[repos.\"web/x\"]\nread = [\"bob\"]\nwrite = [\"bob\"]\n",
+                "line 4: bob is listed twice for web/x",
+            ),
+            ("[repos.\"*/x\"]\nowner = \"bob\"\n", "line 2: Repo Invalid"),
+        ] {
+            match State::parse(bad) {
+                Err(Error::StateInvalid(e)) => assert!(e.starts_with(msg), "{e}"),
+                other => panic!("{bad:?}: {other:?}"),
+            }
+        }
+    }
+}
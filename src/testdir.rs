@@ -0,0 +1,45 @@
+//! Scratch directories for tests.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Numbers the directories of one test run, which may share a name.
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory under the system's temporary directory, removed with
+/// everything in it when dropped, including when the test panics.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// `gitcontrol-<name>-test-<pid>-<n>`, emptied if an earlier run left it.
+    pub fn new(name: &str) -> TestDir {
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("gitcontrol-{name}-test-{}-{n}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
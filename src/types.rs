@@ -32,11 +32,91 @@ impl fmt::Display for User {
     }
 }
 
+/// A named set of users, written `=name` in the config.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Group(String);
+
+impl Group {
+    pub fn from_string(s: String) -> Result<Self, Error> {
+        if s.is_empty() || s.find(pattern_not_accepted).is_some() {
+            return Err(Error::GroupInvalid(s));
+        }
+        Ok(Group(s))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Who a grant applies to: a single user (`alice`) or a group (`=devs`).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subject {
+    User(User),
+    Group(Group),
+}
+
+impl Subject {
+    pub fn from_string(s: String) -> Result<Self, Error> {
+        match s.strip_prefix('=') {
+            Some(g) => Ok(Subject::Group(Group::from_string(g.to_string())?)),
+            None => Ok(Subject::User(User::from_string(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subject::User(u) => write!(f, "{u}"),
+            Subject::Group(g) => write!(f, "={g}"),
+        }
+    }
+}
+
+/// A repository `dir/repo`, or, when parsed with [`Repo::from_pattern`], a
+/// pattern where either component may be `*` to match any name.
+///
+/// A directory `~user` is that user's personal namespace (`~alice/scratch`).
+/// A `*` directory does not match personal namespaces: only grants naming
+/// them reach into them.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Repo([String; 2]);
 
+/// The component of a pattern matching any name.
+const WILDCARD: &str = "*";
+/// The prefix of personal namespaces, followed by the user name.
+pub const PERSONAL_PREFIX: char = '~';
+
 impl Repo {
     pub fn from_string(s: String) -> Result<Self, Error> {
+        Repo::parse(s, false)
+    }
+
+    /// Parse a repository or a pattern such as `web/*`, `*/docs` or `*/*`.
+    pub fn from_pattern(s: String) -> Result<Self, Error> {
+        Repo::parse(s, true)
+    }
+
+    /// Parse a repository or a directory `dir/*`: what may have an owner.
+    pub fn from_ownable(s: String) -> Result<Self, Error> {
+        let repo = Repo::parse(s, true)?;
+        if repo.is_pattern() && !repo.is_directory() {
+            return Err(Error::RepoInvalid((
+                repo.to_string(),
+                "only repositories and dir/* have owners",
+            )));
+        }
+        Ok(repo)
+    }
+
+    fn parse(s: String, allow_pattern: bool) -> Result<Self, Error> {
         if !s.is_ascii() {
             return Err(Error::RepoInvalid((s, "repo contains non ASCII")));
         }
@@ -56,17 +136,66 @@ impl Repo {
 
         let dir = ss[0];
         let repo = ss[1];
+        let wildcard = |c: &str| allow_pattern && c == WILDCARD;
+        // `~user`: the namespace of a (valid, non-empty) user name
+        let name = dir.strip_prefix(PERSONAL_PREFIX).unwrap_or(dir);
 
-        if dir.find(pattern_not_accepted).is_some() {
+        if dir.starts_with(PERSONAL_PREFIX) && name.is_empty() {
+            return Err(Error::RepoInvalid((s, "personal namespace without user")));
+        }
+        if !wildcard(dir) && name.find(pattern_not_accepted).is_some() {
             return Err(Error::RepoInvalid((s, "directory is not alphanumeric")));
         }
-        if repo.find(pattern_not_accepted).is_some() {
+        if !wildcard(repo) && repo.find(pattern_not_accepted).is_some() {
             return Err(Error::RepoInvalid((s, "repo is not alphanumeric")));
         }
 
         Ok(Repo([dir.to_string(), repo.to_string()]))
     }
 
+    /// true if either component is a wildcard
+    pub fn is_pattern(&self) -> bool {
+        self.0.iter().any(|c| c == WILDCARD)
+    }
+
+    /// true for `dir/*`, the pattern covering a whole directory
+    pub fn is_directory(&self) -> bool {
+        self.0[0] != WILDCARD && self.0[1] == WILDCARD
+    }
+
+    /// The directory `dir/*` this repository is in.
+    pub fn directory(&self) -> Repo {
+        Repo([self.0[0].clone(), WILDCARD.to_string()])
+    }
+
+    /// The pattern covering every repository in `user`'s personal namespace.
+    pub fn personal_namespace(user: &User) -> Repo {
+        Repo([format!("{PERSONAL_PREFIX}{user}"), WILDCARD.to_string()])
+    }
+
+    /// The user whose personal namespace this repository is in, if any.
+    pub fn personal_owner(&self) -> Option<User> {
+        let name = self.0[0].strip_prefix(PERSONAL_PREFIX)?;
+        User::from_string(name.to_string()).ok()
+    }
+
+    /// true if `repo` is this repository, or is covered by this pattern
+    pub fn matches(&self, repo: &Repo) -> bool {
+        if self.0[0] == WILDCARD && repo.0[0].starts_with(PERSONAL_PREFIX) {
+            return false;
+        }
+        self.0
+            .iter()
+            .zip(repo.0.iter())
+            .all(|(p, c)| p == WILDCARD || p == c)
+    }
+
+    /// How specific a pattern is: exact names beat `dir/*`, which beats
+    /// `*/repo`, which beats `*/*`.
+    fn specificity(&self) -> (bool, bool) {
+        (self.0[0] != WILDCARD, self.0[1] != WILDCARD)
+    }
+
     pub fn to_path(&self, prefix: &Path) -> PathBuf {
         [
             prefix.to_path_buf(),
@@ -94,25 +223,71 @@ impl fmt::Display for Repo {
     }
 }
 
+/// Access levels, each allowing everything the ones before it (other than
+/// [`Permission::Deny`]) allow.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Permission {
+    /// No access at all, whatever else is granted.
+    Deny,
     Read,
+    /// Push new commits: create refs and fast-forward them.
     Write,
+    /// Also rewind refs (force-push), written `+`.
+    Force,
+    /// Also delete refs, written `d`.
+    Delete,
 }
 
 impl Permission {
     pub fn from_char(c: char) -> Result<Self, Error> {
         match c {
+            '-' => Ok(Permission::Deny),
             'r' => Ok(Permission::Read),
             'w' => Ok(Permission::Write),
+            '+' => Ok(Permission::Force),
+            'd' => Ok(Permission::Delete),
             _ => Err(Error::PermissionInvalid(c)),
         }
     }
 
+    /// Parse the permission field of a config line: a single character, or
+    /// `w+` as gitolite spells [`Permission::Force`].
+    pub fn from_token(s: &str) -> Result<Self, Error> {
+        let mut chars = s.chars();
+        let c = chars.next().ok_or(Error::PermissionInvalid(' '))?;
+        match (c, chars.as_str()) {
+            (c, "") => Permission::from_char(c),
+            ('w', "+") => Ok(Permission::Force),
+            (_, rest) => Err(Error::PermissionInvalid(rest.chars().next().unwrap_or(c))),
+        }
+    }
+
+    /// Like [`Permission::from_token`], for the permission of a ref rule,
+    /// which cannot be read-only.
+    pub fn from_ref_token(s: &str) -> Result<Self, Error> {
+        match Permission::from_token(s)? {
+            Permission::Read => Err(Error::PermissionInvalid('r')),
+            permission => Ok(permission),
+        }
+    }
+
     pub fn to_char(self) -> char {
         match self {
+            Permission::Deny => '-',
             Permission::Read => 'r',
             Permission::Write => 'w',
+            Permission::Force => '+',
+            Permission::Delete => 'd',
+        }
+    }
+
+    /// The permission resulting from two grants on the same repository or
+    /// pattern: a deny if either is one, otherwise the highest.
+    pub fn combine(self, other: Permission) -> Permission {
+        if self == Permission::Deny || other == Permission::Deny {
+            Permission::Deny
+        } else {
+            self.max(other)
         }
     }
 }
@@ -120,8 +295,350 @@ impl Permission {
 impl fmt::Display for Permission {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Permission::Deny => write!(f, "deny"),
             Permission::Read => write!(f, "read"),
             Permission::Write => write!(f, "write"),
+            Permission::Force => write!(f, "force-push"),
+            Permission::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// Find the grant deciding access to `repo` among `grants` (exact names and
+/// patterns). A matching deny always decides, however general its pattern;
+/// otherwise it is the most specific grant matching the repository, the
+/// highest permission winning between equally specific grants. Returns the
+/// granting name or pattern along with its permission.
+pub fn resolve<'a, I>(grants: I, repo: &Repo) -> Option<(&'a Repo, Permission)>
+where
+    I: IntoIterator<Item = (&'a Repo, Permission)>,
+{
+    grants
+        .into_iter()
+        .filter(|(pattern, _)| pattern.matches(repo))
+        .max_by_key(|(pattern, permission)| {
+            (
+                *permission == Permission::Deny,
+                pattern.specificity(),
+                *permission,
+            )
+        })
+}
+
+/// A git ref name (`refs/heads/main`), or a prefix pattern ending in `*`
+/// (`refs/heads/feature/*`) matching every ref starting with it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RefPattern(String);
+
+impl RefPattern {
+    pub fn from_string(s: String) -> Result<Self, Error> {
+        if !s.starts_with("refs/") {
+            return Err(Error::RefInvalid((s, "ref does not start with refs/")));
+        }
+        if !s.is_ascii() || s.chars().any(|c| c.is_ascii_whitespace() || c.is_control()) {
+            return Err(Error::RefInvalid((s, "ref contains invalid characters")));
+        }
+        if s.find('*').is_some_and(|i| i != s.len() - 1) {
+            return Err(Error::RefInvalid((s, "* is only allowed at the end")));
+        }
+        Ok(RefPattern(s))
+    }
+
+    /// true if `refname` is this ref, or starts with this pattern's prefix
+    pub fn matches(&self, refname: &str) -> bool {
+        match self.0.strip_suffix('*') {
+            Some(prefix) => refname.starts_with(prefix),
+            None => self.0 == refname,
+        }
+    }
+
+    /// exact names beat patterns, longer patterns beat shorter ones
+    fn specificity(&self) -> (bool, usize) {
+        (!self.0.ends_with('*'), self.0.len())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RefPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Find the ref rule deciding updates to `refname` among `rules`: the most
+/// specific ref pattern matching it, a deny winning between equals.
+pub fn resolve_ref<'a, I>(rules: I, refname: &str) -> Option<(&'a RefPattern, Permission)>
+where
+    I: IntoIterator<Item = (&'a RefPattern, Permission)>,
+{
+    rules
+        .into_iter()
+        .filter(|(pattern, _)| pattern.matches(refname))
+        .max_by_key(|(pattern, permission)| {
+            (
+                pattern.specificity(),
+                *permission == Permission::Deny,
+                *permission,
+            )
+        })
+}
+
+/// Keys the shell accepts in `GIT_PROTOCOL`, and whether each needs a value.
+const GIT_PROTOCOL_KEYS: &[(&str, bool)] = &[("version", true)];
+
+/// A validated `GIT_PROTOCOL` value, as sent by SSH clients (`SendEnv`) to
+/// negotiate the wire protocol version, e.g. `version=2`.
+///
+/// The value is a `:` separated list of `key` or `key=value` entries. Only
+/// known keys are accepted, and `version` must be a number, so nothing
+/// unexpected is handed to git through the environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitProtocol(String);
+
+impl GitProtocol {
+    pub fn from_string(s: String) -> Result<Self, Error> {
+        if s.is_empty() {
+            return Err(Error::ProtocolInvalid((s, "empty value")));
+        }
+        for entry in s.split(':') {
+            let (key, value) = match entry.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (entry, None),
+            };
+            let needs_value = match GIT_PROTOCOL_KEYS.iter().find(|(k, _)| *k == key) {
+                Some((_, needs_value)) => *needs_value,
+                None => return Err(Error::ProtocolInvalid((s, "unknown key"))),
+            };
+            match value {
+                None if needs_value => {
+                    return Err(Error::ProtocolInvalid((s, "missing value")));
+                }
+                Some(_) if !needs_value => {
+                    return Err(Error::ProtocolInvalid((s, "unexpected value")));
+                }
+                _ => {}
+            }
+            if key == "version"
+                && !value.is_some_and(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()))
+            {
+                return Err(Error::ProtocolInvalid((s, "version is not a number")));
+            }
+        }
+        Ok(GitProtocol(s))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for GitProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol(s: &str) -> Result<GitProtocol, Error> {
+        GitProtocol::from_string(s.to_string())
+    }
+
+    fn pattern(s: &str) -> Repo {
+        Repo::from_pattern(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn patterns_only_parse_as_patterns() {
+        assert!(Repo::from_string("web/*".to_string()).is_err());
+        assert!(Repo::from_pattern("web/si*".to_string()).is_err());
+        assert!(pattern("*/*").is_pattern());
+        assert!(!pattern("web/site").is_pattern());
+    }
+
+    #[test]
+    fn patterns_match_repositories() {
+        let site = pattern("web/site");
+        assert!(pattern("web/*").matches(&site));
+        assert!(pattern("*/site").matches(&site));
+        assert!(pattern("*/*").matches(&site));
+        assert!(pattern("web/site").matches(&site));
+        assert!(!pattern("*/docs").matches(&site));
+        assert!(!pattern("web/other").matches(&site));
+    }
+
+    #[test]
+    fn personal_namespaces() {
+        let scratch = Repo::from_string("~alice/scratch".to_string()).unwrap();
+        assert_eq!(scratch.personal_owner().unwrap().as_str(), "alice");
+        assert_eq!(pattern("web/site").personal_owner(), None);
+        for bad in ["~/scratch", "~~alice/x", "~al ice/x", "a~b/x"] {
+            assert!(Repo::from_string(bad.to_string()).is_err(), "{bad}");
+        }
+
+        let alice = User::from_string("alice".to_string()).unwrap();
+        let namespace = Repo::personal_namespace(&alice);
+        assert_eq!(namespace.to_string(), "~alice/*");
+        assert!(namespace.matches(&scratch));
+        assert!(pattern("~alice/scratch").matches(&scratch));
+        assert!(!pattern("~bob/*").matches(&scratch));
+        // admin-wide patterns stay out of personal namespaces
+        assert!(!pattern("*/*").matches(&scratch));
+        assert!(!pattern("*/scratch").matches(&scratch));
+    }
+
+    #[test]
+    fn ownable_repositories_and_directories() {
+        let site = Repo::from_ownable("web/site".to_string()).unwrap();
+        let web = Repo::from_ownable("web/*".to_string()).unwrap();
+        assert!(web.is_directory() && !site.is_directory());
+        assert_eq!(site.directory(), web);
+        assert_eq!(web.directory(), web);
+        for bad in ["*/site", "*/*"] {
+            assert!(Repo::from_ownable(bad.to_string()).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn most_specific_grant_wins() {
+        let grants = [
+            (pattern("*/*"), Permission::Write),
+            (pattern("*/site"), Permission::Read),
+            (pattern("web/*"), Permission::Write),
+            (pattern("web/site"), Permission::Read),
+        ];
+        let resolve_in = |n: usize, repo: &str| {
+            resolve(grants[..n].iter().map(|(r, p)| (r, *p)), &pattern(repo))
+                .map(|(r, p)| (r.to_string(), p))
+        };
+        assert_eq!(
+            resolve_in(4, "web/site"),
+            Some(("web/site".to_string(), Permission::Read))
+        );
+        assert_eq!(
+            resolve_in(3, "web/site"),
+            Some(("web/*".to_string(), Permission::Write))
+        );
+        assert_eq!(
+            resolve_in(2, "web/site"),
+            Some(("*/site".to_string(), Permission::Read))
+        );
+        assert_eq!(
+            resolve_in(4, "doc/guide"),
+            Some(("*/*".to_string(), Permission::Write))
+        );
+        assert_eq!(resolve_in(0, "doc/guide"), None);
+    }
+
+    #[test]
+    fn deny_beats_more_specific_grants() {
+        let grants = [
+            (pattern("secret/*"), Permission::Deny),
+            (pattern("secret/keys"), Permission::Write),
+            (pattern("*/*"), Permission::Read),
+        ];
+        let resolved = |repo: &str| {
+            resolve(grants.iter().map(|(r, p)| (r, *p)), &pattern(repo)).map(|(_, p)| p)
+        };
+        assert_eq!(resolved("secret/keys"), Some(Permission::Deny));
+        assert_eq!(resolved("web/site"), Some(Permission::Read));
+    }
+
+    #[test]
+    fn permission_chars_round_trip() {
+        for p in [Permission::Deny, Permission::Read, Permission::Write] {
+            assert_eq!(Permission::from_char(p.to_char()).unwrap(), p);
+        }
+        assert!(Permission::from_char('x').is_err());
+        assert_eq!(
+            Permission::Read.combine(Permission::Write),
+            Permission::Write
+        );
+        assert_eq!(
+            Permission::Write.combine(Permission::Deny),
+            Permission::Deny
+        );
+    }
+
+    fn refpat(s: &str) -> RefPattern {
+        RefPattern::from_string(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn ref_patterns() {
+        assert!(refpat("refs/heads/feature/*").matches("refs/heads/feature/x/y"));
+        assert!(!refpat("refs/heads/feature/*").matches("refs/heads/main"));
+        assert!(refpat("refs/heads/main").matches("refs/heads/main"));
+        assert!(!refpat("refs/heads/main").matches("refs/heads/main2"));
+        for bad in ["heads/main", "refs/*/main", "refs/heads/ma in", ""] {
+            assert!(RefPattern::from_string(bad.to_string()).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn permission_tokens_and_order() {
+        for c in ['-', 'r', 'w', '+', 'd'] {
+            assert_eq!(Permission::from_char(c).unwrap().to_char(), c);
+        }
+        assert_eq!(Permission::from_token("w+").unwrap(), Permission::Force);
+        for bad in ["r+", "-+", "w++", "x", "", "rw"] {
+            assert!(Permission::from_token(bad).is_err(), "{bad}");
+        }
+        assert!(Permission::from_ref_token("r").is_err());
+        assert_eq!(Permission::from_ref_token("-").unwrap(), Permission::Deny);
+
+        assert!(Permission::Deny < Permission::Read);
+        assert!(Permission::Read < Permission::Write);
+        assert!(Permission::Write < Permission::Force);
+        assert!(Permission::Force < Permission::Delete);
+        assert_eq!(
+            Permission::Force.combine(Permission::Write),
+            Permission::Force
+        );
+        assert_eq!(
+            Permission::Delete.combine(Permission::Deny),
+            Permission::Deny
+        );
+    }
+
+    #[test]
+    fn most_specific_ref_rule_wins() {
+        let rules = [
+            (refpat("refs/heads/*"), Permission::Force),
+            (refpat("refs/heads/main"), Permission::Write),
+        ];
+        let resolved = |r: &str| resolve_ref(rules.iter().map(|(p, r)| (p, *r)), r).map(|(_, r)| r);
+        assert_eq!(resolved("refs/heads/main"), Some(Permission::Write));
+        assert_eq!(resolved("refs/heads/topic"), Some(Permission::Force));
+        assert_eq!(resolved("refs/tags/v1"), None);
+    }
+
+    #[test]
+    fn accepts_protocol_versions() {
+        assert_eq!(protocol("version=2").unwrap().as_str(), "version=2");
+        assert!(protocol("version=0").is_ok());
+        assert!(protocol("version=1:version=2").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_protocol_values() {
+        for bad in [
+            "",
+            "version",
+            "version=",
+            "version=two",
+            "version=2;rm",
+            "version=2:",
+            "object-format=sha1",
+            "version=2:foo=bar",
+            "version=2\nfoo",
+        ] {
+            assert!(protocol(bad).is_err(), "{bad:?}");
         }
     }
 }
@@ -1,81 +1,254 @@
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io;
-use std::io::BufRead;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use crate::config::Config;
 use crate::errors::Error;
-use crate::types::{Permission, Repo, User};
+use crate::types::{Group, Permission, RefPattern, Repo, User, resolve};
 
 pub struct UserDb {
+    /// Effective grants, exact names and patterns: the user's own grants
+    /// merged with those of every group they belong to, combined with
+    /// [`Permission::combine`] for the same name or pattern.
     pub repos: BTreeMap<Repo, Permission>,
+    /// Ref rules from the user's own section and from their groups.
+    pub refs: Vec<(Repo, RefPattern, Permission)>,
+    /// Patterns the user, or one of their groups, may create repositories
+    /// under.
+    pub creates: Vec<Repo>,
+    /// The groups the user is a member of.
+    pub groups: Vec<Group>,
+    /// Where personal namespaces live, if configured; see
+    /// [`crate::repos::repo_path`].
+    pub personal_dir: Option<PathBuf>,
 }
 
 impl UserDb {
+    /// The grant (exact name or pattern) deciding access to `repo`, and the
+    /// permission it gives; see [`resolve`].
+    pub fn permission(&self, repo: &Repo) -> Option<(&Repo, Permission)> {
+        resolve(self.repos.iter().map(|(r, p)| (r, *p)), repo)
+    }
     pub fn can_read(&self, repo: &Repo) -> bool {
-        match self.repos.get(repo) {
+        match self.permission(repo) {
             None => false,
-            Some(Permission::Read) => true,
-            Some(Permission::Write) => true,
+            Some((_, permission)) => permission >= Permission::Read,
         }
     }
+    /// true if the user may push to `repo`: a write (or higher) grant, or a
+    /// ref rule allowing writes to some of its refs.
     pub fn can_write(&self, repo: &Repo) -> bool {
-        match self.repos.get(repo) {
-            None => false,
-            Some(Permission::Read) => false,
-            Some(Permission::Write) => true,
+        match self.permission(repo) {
+            Some((_, Permission::Deny)) => false,
+            Some((_, permission)) if permission >= Permission::Write => true,
+            _ => self
+                .ref_rules(repo)
+                .any(|(_, permission)| permission >= Permission::Write),
         }
     }
+    /// The ref rules applying to `repo`, to be resolved with
+    /// [`resolve_ref`](crate::types::resolve_ref).
+    pub fn ref_rules<'a>(
+        &'a self,
+        repo: &'a Repo,
+    ) -> impl Iterator<Item = (&'a RefPattern, Permission)> {
+        self.refs
+            .iter()
+            .filter(move |(r, _, _)| r.matches(repo))
+            .map(|(_, pattern, permission)| (pattern, *permission))
+    }
+    /// true if the user may create `repo` by pushing to it: a create
+    /// pattern matches it, and they are not denied access to it.
+    pub fn can_create(&self, repo: &Repo) -> bool {
+        !matches!(self.permission(repo), Some((_, Permission::Deny)))
+            && self.creates.iter().any(|pattern| pattern.matches(repo))
+    }
+    /// Give the user full control of their personal namespace: all access to
+    /// the repositories in it, and creating them.
+    pub(crate) fn add_personal_namespace(&mut self, user: &User) {
+        let namespace = Repo::personal_namespace(user);
+        let p = self
+            .repos
+            .entry(namespace.clone())
+            .or_insert(Permission::Delete);
+        *p = p.combine(Permission::Delete);
+        self.creates.push(namespace);
+    }
     pub fn is_empty(&self) -> bool {
-        self.repos.is_empty()
+        self.repos.is_empty() && self.refs.is_empty() && self.creates.is_empty()
     }
 }
 
-// format:
-//
-// @user
-// w repo
-// r repo
-// # comment
-
+/// Read the effective permissions of `user` from the config at
+/// `config_path` (and the files it includes), exactly as `gitcontrol` reads
+/// it; see [`Config::read`] and [`Config::user_db`].
 pub fn read_db(config_path: &Path, user: User) -> Result<UserDb, Error> {
-    let mut repos = BTreeMap::new();
-
-    //println!("path: {:?}", config_path);
-
-    let file = File::open(config_path)?;
-    // true if this is the current user
-    let mut on_user = false;
-
-    for line in io::BufReader::new(file).lines() {
-        let s = line?;
-        let mut cs = s.chars();
-        match cs.next() {
-            None => {}
-            Some(c) => {
-                if c == '@' {
-                    on_user = user.is_eq(&s[1..]);
-                } else {
-                    if !on_user {
-                        continue;
-                    }
-                    if c == '#' {
-                        continue;
-                    }
-
-                    let permission = Permission::from_char(c)?;
-
-                    let v = cs.next().unwrap();
-                    if v != ' ' {
-                        panic!("expecting space after permission {:?}", permission)
-                    }
-
-                    let repo = cs.collect();
-                    repos.insert(Repo::from_string(repo)?, permission);
+    Ok(Config::read(config_path)?.user_db(&user))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::config_path;
+    use crate::testdir::TestDir;
+    use std::fs;
+
+    fn repo(s: &str) -> Repo {
+        Repo::from_string(s.to_string()).unwrap()
+    }
+
+    fn db_of(contents: &str, name: &str) -> UserDb {
+        let dir = TestDir::new("userdb");
+        let path = config_path(&dir);
+        fs::write(&path, contents).unwrap();
+        read_db(&path, User::from_string(name.to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn group_grants_apply_to_members() {
+        let cfg = "=devs alice bob\nr web/site\nw web/api\n@alice\nw web/site\n@carol\nr a/b\n";
+
+        let alice = db_of(cfg, "alice");
+        assert!(alice.can_write(&repo("web/site")));
+        assert!(alice.can_write(&repo("web/api")));
+        assert_eq!(alice.groups.len(), 1);
+
+        let bob = db_of(cfg, "bob");
+        assert!(bob.can_read(&repo("web/site")));
+        assert!(!bob.can_write(&repo("web/site")));
+
+        let carol = db_of(cfg, "carol");
+        assert!(!carol.can_read(&repo("web/site")));
+        assert!(carol.groups.is_empty());
+    }
+
+    #[test]
+    fn deny_overrides_user_and_group_grants() {
+        let cfg = "=devs alice bob\nr */*\n- secret/*\n@alice\nw secret/keys\n@bob\n- web/site\n";
+        let alice = db_of(cfg, "alice");
+        assert!(alice.can_read(&repo("web/site")));
+        assert!(!alice.can_read(&repo("secret/keys")));
+        assert!(!alice.can_write(&repo("secret/keys")));
+
+        let bob = db_of(cfg, "bob");
+        assert!(!bob.can_read(&repo("web/site")));
+        assert!(bob.can_read(&repo("web/api")));
+    }
+
+    #[test]
+    fn ref_rules_from_user_and_groups() {
+        let cfg = "=devs alice\nw+ web/* refs/heads/feature/*\n@alice\nr web/site\nw web/site refs/heads/main\n@bob\nr web/site\n";
+        let alice = db_of(cfg, "alice");
+        assert_eq!(alice.refs.len(), 2);
+        // a ref rule is enough to push, even over a read grant
+        assert!(alice.can_write(&repo("web/site")));
+        assert_eq!(alice.ref_rules(&repo("web/site")).count(), 2);
+        assert_eq!(alice.ref_rules(&repo("doc/site")).count(), 0);
+
+        let bob = db_of(cfg, "bob");
+        assert!(!bob.can_write(&repo("web/site")));
+    }
+
+    #[test]
+    fn force_and_delete_grants() {
+        let cfg = "@alice\nw+ web/site\n+ web/api\nd web/doc\nd web/* refs/heads/*\n";
+        let alice = db_of(cfg, "alice");
+        assert_eq!(alice.repos[&repo("web/site")], Permission::Force);
+        assert_eq!(alice.repos[&repo("web/api")], Permission::Force);
+        assert_eq!(alice.repos[&repo("web/doc")], Permission::Delete);
+        assert!(alice.can_read(&repo("web/doc")));
+        assert!(alice.can_write(&repo("web/doc")));
+        assert_eq!(
+            alice.ref_rules(&repo("web/site")).next().unwrap().1,
+            Permission::Delete
+        );
+    }
+
+    #[test]
+    fn create_patterns_and_owners() {
+        let cfg = "=devs alice bob\nC sandbox/*\n- sandbox/secret\n@alice\nowner sandbox/one\n@bob\nr sandbox/one\n";
+        let alice = db_of(cfg, "alice");
+        assert!(alice.can_create(&repo("sandbox/two")));
+        assert!(!alice.can_create(&repo("sandbox/secret")));
+        assert!(!alice.can_create(&repo("web/site")));
+        assert_eq!(alice.repos[&repo("sandbox/one")], Permission::Delete);
+
+        let bob = db_of(cfg, "bob");
+        assert!(bob.can_create(&repo("sandbox/two")));
+        assert!(!bob.can_write(&repo("sandbox/one")));
+    }
+
+    #[test]
+    fn personal_namespaces() {
+        let cfg = "personal /srv/personal\n=devs bob\nr */*\n@alice\n@bob\nr ~alice/scratch\n";
+        let alice = db_of(cfg, "alice");
+        let scratch = repo("~alice/scratch");
+        assert_eq!(
+            alice.personal_dir.as_deref(),
+            Some(Path::new("/srv/personal"))
+        );
+        assert_eq!(alice.permission(&scratch).unwrap().1, Permission::Delete);
+        assert!(alice.can_create(&repo("~alice/new")));
+        assert!(!alice.can_read(&repo("~bob/x")));
+
+        // only the grant naming alice's repository reaches into it
+        let bob = db_of(cfg, "bob");
+        assert!(bob.can_read(&scratch));
+        assert!(!bob.can_write(&scratch));
+        assert!(!bob.can_read(&repo("~alice/other")));
+        assert!(!bob.can_create(&repo("~alice/new")));
+
+        // users unknown to the config get nothing
+        assert!(db_of(cfg, "carol").is_empty());
+    }
+
+    #[test]
+    fn patterns_cover_namespaces() {
+        let cfg = "@alice\nw web/*\nr web/secret\nr */docs\n=all alice\nr */*\n";
+        let alice = db_of(cfg, "alice");
+        assert!(alice.can_write(&repo("web/site")));
+        // the exact grant is more specific than web/*
+        assert!(alice.can_read(&repo("web/secret")));
+        assert!(!alice.can_write(&repo("web/secret")));
+        assert!(alice.can_read(&repo("lib/docs")));
+        assert!(!alice.can_write(&repo("lib/docs")));
+        assert!(alice.can_read(&repo("lib/core")));
+        assert_eq!(
+            alice.permission(&repo("web/site")).unwrap().0.to_string(),
+            "web/*"
+        );
+    }
+
+    #[test]
+    fn same_language_as_gitcontrol() {
+        // indented lines, trailing spaces and comments anywhere
+        let cfg = "# access\n@alice \n  w web/site  \n    # later\nr  web/api\n";
+        let alice = db_of(cfg, "alice");
+        assert!(alice.can_write(&repo("web/site")));
+        assert!(alice.can_read(&repo("web/api")));
+
+        for (cfg, msg) in [
+            (
+                "@alice\nw\n",
+                "line 2: expected <permission> <dir/repo> [<ref>]",
+            ),
+            (
+                "@alice\n\nwweb/site\n",
+                "line 3: expected <permission> <dir/repo> [<ref>]",
+            ),
+            ("@alice\nx web/site\n", "line 2: "),
+        ] {
+            let dir = TestDir::new("userdb");
+            let path = config_path(&dir);
+            fs::write(&path, cfg).unwrap();
+            let result = read_db(&path, User::from_string("alice".to_string()).unwrap());
+            match result {
+                Err(Error::ConfigInvalid(e)) => {
+                    // the file and the line
+                    assert!(e.contains(&format!("gitcontrol.cfg: {msg}")), "{e}")
                 }
+                Err(e) => panic!("{e}"),
+                Ok(_) => panic!("{cfg:?} parsed"),
             }
         }
     }
-
-    Ok(UserDb { repos })
 }